@@ -0,0 +1,185 @@
+//! This example serves an ACM device over smoltcp, like firmware without `std` would do.
+//! Instead of a real network interface, smoltcp's loopback device is used, so the host
+//! side is played by a second TCP socket on the same interface.
+//! The client lists the devices, imports the serial port and reads its device descriptor.
+//!
+//! Run it with `cargo run --example smoltcp_loopback --features smoltcp`.
+
+use smoltcp::{
+   iface::{Config, Interface, SocketSet},
+   phy::{Loopback, Medium},
+   socket::tcp,
+   time::Instant,
+   wire::{EthernetAddress, IpAddress, IpCidr},
+};
+use usb_device::{bus::UsbBusAllocator, prelude::*};
+use usbd_serial::{SerialPort, USB_CLASS_CDC};
+use usbip_device::{
+   protocol::{
+      Direction, OpHeader, OpRequest, OpResponse, OpResponseCommand, TransferFlags, UsbCmd,
+      UsbIpCmdSubmit, UsbIpHeader, UsbIpRequest, UsbIpRequestCmd, UsbIpResponse, USBIP_VERSION,
+   },
+   SmoltcpTransport, UsbIpBusBuilder, UsbIpServer, USBIP_PORT,
+};
+
+/// The steps of the client, each waits for the answer to the previous request
+enum Step {
+   Connect,
+   Connecting,
+   ListDevices,
+   Import,
+   GetDescriptor,
+   Done,
+}
+
+fn tcp_socket() -> tcp::Socket<'static> {
+   tcp::Socket::new(
+      tcp::SocketBuffer::new(vec![0; 4096]),
+      tcp::SocketBuffer::new(vec![0; 4096]),
+   )
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+   pretty_env_logger::init();
+
+   // The network stack, both ends live on 127.0.0.1
+   let mut device = Loopback::new(Medium::Ethernet);
+   let config = Config::new(EthernetAddress([0x02, 0, 0, 0, 0, 1]).into());
+   let mut iface = Interface::new(config, &mut device, Instant::now());
+   iface.update_ip_addrs(|addrs| {
+      addrs.push(IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8)).unwrap();
+   });
+
+   let mut sockets = SocketSet::new(vec![]);
+   let server_handle = sockets.add(tcp_socket());
+   let client_handle = sockets.add(tcp_socket());
+
+   // The device, served on the socket of the server
+   let server = UsbIpServer::unbound();
+   let bus = UsbIpBusBuilder::new().server(&server).build()?;
+   let mut transport = SmoltcpTransport::new(&server, server_handle, USBIP_PORT);
+
+   let bus_allocator = UsbBusAllocator::new(bus);
+   let mut usb_serial = SerialPort::new(&bus_allocator);
+   let mut usb_bus = UsbDeviceBuilder::new(&bus_allocator, UsbVidPid(0x16c0, 0x27dd))
+      .manufacturer("Fake company")
+      .product("Serial port")
+      .serial_number("TEST")
+      .device_class(USB_CLASS_CDC)
+      .build();
+
+   let header = OpHeader {
+      version: USBIP_VERSION,
+      command: 0,
+      status: 0,
+   };
+   let mut step = Step::Connect;
+   let mut devid = 0;
+   let mut rx = vec![];
+
+   loop {
+      iface.poll(Instant::now(), &mut device, &mut sockets);
+      transport.poll(&mut sockets)?;
+      usb_bus.poll(&mut [&mut usb_serial]);
+
+      let client = sockets.get_mut::<tcp::Socket>(client_handle);
+      while client.can_recv() {
+         client.recv(|data| {
+            rx.extend_from_slice(data);
+            (data.len(), ())
+         })?;
+      }
+
+      match step {
+         Step::Connect => {
+            // The server socket is already listening, since the transport was polled
+            client.connect(iface.context(), (IpAddress::v4(127, 0, 0, 1), USBIP_PORT), 49152)?;
+            step = Step::Connecting;
+         }
+         Step::Connecting => {
+            if !client.may_send() {
+               continue;
+            }
+
+            let request = OpRequest::ListDevices(header.clone());
+            client.send_slice(&request.to_vec())?;
+            step = Step::ListDevices;
+         }
+         Step::ListDevices => {
+            let (response, len) = match OpResponse::decode(&rx)? {
+               Some(response) => response,
+               None => continue,
+            };
+            rx.drain(..len);
+
+            let device = match response.cmd {
+               OpResponseCommand::ListDevices(devices) => devices.first().cloned(),
+               OpResponseCommand::ConnectDevice(_) => unreachable!(),
+            };
+
+            // The device is only listed, once it has reported its descriptors
+            let device = match device {
+               Some(device) => device,
+               None => {
+                  let request = OpRequest::ListDevices(header.clone());
+                  client.send_slice(&request.to_vec())?;
+                  continue;
+               }
+            };
+            log::info!("found device {:?}", device);
+            devid = (device.descriptor.busnum << 16) | device.descriptor.devnum;
+
+            let request = OpRequest::ConnectDevice(header.clone(), device.bus_id);
+            client.send_slice(&request.to_vec())?;
+            step = Step::Import;
+         }
+         Step::Import => {
+            let (response, len) = match OpResponse::decode(&rx)? {
+               Some(response) => response,
+               None => continue,
+            };
+            rx.drain(..len);
+            log::info!("imported device {:#x}: {:?}", devid, response.status);
+
+            // GET_DESCRIPTOR(DEVICE)
+            let request = UsbIpRequest {
+               header: UsbIpHeader {
+                  command: UsbCmd::Request,
+                  seqnum: 1,
+                  devid,
+                  direction: Direction::IN,
+                  ep: 0,
+               },
+               cmd: UsbIpRequestCmd::Cmd(UsbIpCmdSubmit {
+                  transfer_flags: TransferFlags::empty(),
+                  transfer_buffer_length: 18,
+                  start_frame: 0,
+                  number_of_packets: 0,
+                  interval: 0,
+                  setup: [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 18, 0x00],
+               }),
+               data: vec![],
+               iso_packets: vec![],
+            };
+            client.send_slice(&request.to_vec())?;
+            step = Step::GetDescriptor;
+         }
+         Step::GetDescriptor => {
+            let (response, len) = match UsbIpResponse::decode(&rx, 18)? {
+               Some(response) => response,
+               None => continue,
+            };
+            rx.drain(..len);
+            log::info!("device descriptor: {:02x?}", response.data);
+
+            client.close();
+            step = Step::Done;
+         }
+         Step::Done => {
+            if !client.is_open() {
+               return Ok(());
+            }
+         }
+      }
+   }
+}
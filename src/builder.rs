@@ -0,0 +1,194 @@
+use crate::{limits::Limits, UsbIpBus, UsbIpError, UsbIpServer, UsbSpeed};
+#[cfg(feature = "std")]
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+#[cfg(all(feature = "std", unix))]
+use std::path::{Path, PathBuf};
+
+/// The TCP port, the USBIP protocol is registered on.
+pub const USBIP_PORT: u16 = 3240;
+
+/// A builder for [`UsbIpBus`].
+///
+/// By default, the bus listens on `127.0.0.1:3240`, which is where the
+/// `usbip` userspace tools expect it, and reports itself as a high speed device.
+/// Its OUT endpoints are double buffered.
+///
+/// Without the `std` feature, there are no sockets, so the bus is exported on a
+/// [`UsbIpServer::unbound`] server, unless another server is set.
+///
+/// # Example
+/// ```no_run
+/// use usbip_device::UsbIpBusBuilder;
+///
+/// // Listen on an ephemeral port, such that multiple devices can coexist
+/// let bus = UsbIpBusBuilder::new().port(0).build().unwrap();
+/// println!("listening on {}", bus.local_addr().unwrap());
+/// ```
+#[derive(Debug, Clone)]
+pub struct UsbIpBusBuilder {
+   #[cfg(feature = "std")]
+   address: IpAddr,
+   #[cfg(feature = "std")]
+   port: u16,
+   #[cfg(all(feature = "std", unix))]
+   unix_socket: Option<PathBuf>,
+   speed: UsbSpeed,
+   pipe_depth: usize,
+   limits: Limits,
+   #[cfg(feature = "std")]
+   export_to: Option<SocketAddr>,
+   server: Option<UsbIpServer>,
+}
+
+/// The number of packets, an OUT endpoint buffers by default.
+pub(crate) const DEFAULT_PIPE_DEPTH: usize = 2;
+
+impl UsbIpBusBuilder {
+   /// Create a new [`UsbIpBusBuilder`] with the default settings.
+   pub fn new() -> Self {
+      Self {
+         #[cfg(feature = "std")]
+         address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+         #[cfg(feature = "std")]
+         port: USBIP_PORT,
+         #[cfg(all(feature = "std", unix))]
+         unix_socket: None,
+         speed: UsbSpeed::default(),
+         pipe_depth: DEFAULT_PIPE_DEPTH,
+         limits: Limits::default(),
+         #[cfg(feature = "std")]
+         export_to: None,
+         server: None,
+      }
+   }
+
+   /// Set the address to listen on.
+   ///
+   /// Both IPv4 and IPv6 addresses are supported.
+   #[cfg(feature = "std")]
+   pub fn address(mut self, address: impl Into<IpAddr>) -> Self {
+      self.address = address.into();
+      self
+   }
+
+   /// Set the port to listen on.
+   ///
+   /// If the port is set to `0`, the operating system assigns an ephemeral port.
+   /// Use [`UsbIpBus::local_addr`] to find out which port was chosen.
+   #[cfg(feature = "std")]
+   pub fn port(mut self, port: u16) -> Self {
+      self.port = port;
+      self
+   }
+
+   /// Listen on the Unix domain socket at `path` instead of a TCP port.
+   ///
+   /// See [`UsbIpServer::bind_unix`]. The address and port settings are ignored.
+   #[cfg(all(feature = "std", unix))]
+   pub fn unix_socket(mut self, path: impl AsRef<Path>) -> Self {
+      self.unix_socket = Some(path.as_ref().to_path_buf());
+      self
+   }
+
+   /// Set the speed of the device.
+   ///
+   /// The endpoints, allocated on the bus, are validated against the rules of this speed,
+   /// e.g. bulk endpoints of a full speed device must not exceed 64 bytes.
+   pub fn speed(mut self, speed: UsbSpeed) -> Self {
+      self.speed = speed;
+      self
+   }
+
+   /// Set the number of packets, each OUT endpoint can buffer by default.
+   ///
+   /// Once this many packets are waiting to be read by the device, further OUT transfers wait,
+   /// just like it would be NAKed by a real device.
+   /// The depth of a single endpoint can be changed by [`UsbIpBus::set_pipe_depth`].
+   /// The depth is at least one packet.
+   pub fn pipe_depth(mut self, depth: usize) -> Self {
+      self.pipe_depth = depth.max(1);
+      self
+   }
+
+   /// Set the largest transfer buffer, a single URB may have. Defaults to 1 MiB.
+   ///
+   /// An IN URB, that asks for more, fails with `-EINVAL`. An OUT URB, that carries more,
+   /// closes the connection, since its data can not be skipped safely.
+   pub fn max_transfer_length(mut self, length: usize) -> Self {
+      self.limits.max_transfer_length = length;
+      self
+   }
+
+   /// Set the number of URBs, that can be pending on an endpoint at the same time. Defaults to 128.
+   ///
+   /// Further URBs fail with `-ENOMEM`.
+   pub fn max_queued_urbs(mut self, count: usize) -> Self {
+      self.limits.max_queued_urbs = count;
+      self
+   }
+
+   /// Set the number of bytes, all the pending URBs of the device may buffer together.
+   /// Defaults to 16 MiB.
+   ///
+   /// URBs, that would exceed the limit, fail with `-ENOMEM`. The responses, that wait to be
+   /// sent, are limited to the same size, a host, that does not read them, is disconnected.
+   pub fn max_buffered_bytes(mut self, bytes: usize) -> Self {
+      self.limits.max_buffered_bytes = bytes;
+      self
+   }
+
+   /// Export the device to a host, that waits for devices at `addr`.
+   ///
+   /// Instead of waiting for `usbip attach`, the bus connects to the host by itself,
+   /// like `usbip connect` does with a host running `usbipd --device`.
+   /// If the host is not reachable or closes the connection, the bus tries again later.
+   ///
+   /// The bus still listens on its own address and port as well, so choose a different port,
+   /// e.g. `0`, when the host runs on the same machine.
+   #[cfg(feature = "std")]
+   pub fn export_to(mut self, addr: impl Into<SocketAddr>) -> Self {
+      self.export_to = Some(addr.into());
+      self
+   }
+
+   /// Export the bus on an existing [`UsbIpServer`] instead of creating a new one.
+   ///
+   /// This allows exporting multiple devices on the same port.
+   /// If a server is set, the address, port and socket settings are ignored.
+   pub fn server(mut self, server: &UsbIpServer) -> Self {
+      self.server = Some(server.clone());
+      self
+   }
+
+   /// Build the [`UsbIpBus`].
+   ///
+   /// # Errors
+   /// If the socket could not be bound, e.g. because the port is already in use.
+   pub fn build(self) -> Result<UsbIpBus, UsbIpError> {
+      let server = match self.server {
+         Some(server) => server,
+         #[cfg(all(feature = "std", unix))]
+         None if self.unix_socket.is_some() => UsbIpServer::bind_unix(self.unix_socket.unwrap())?,
+         #[cfg(feature = "std")]
+         None => UsbIpServer::bind(SocketAddr::new(self.address, self.port))?,
+         #[cfg(not(feature = "std"))]
+         None => UsbIpServer::unbound(),
+      };
+
+      let bus = server.add_bus(self.speed, self.pipe_depth, self.limits);
+      #[cfg(feature = "std")]
+      if let Some(addr) = self.export_to {
+         // The server must not be locked while holding the bus, as polling locks them the other way around
+         let devid = bus.lock().devid;
+         server.add_export(devid, addr);
+      }
+
+      Ok(bus)
+   }
+}
+
+impl Default for UsbIpBusBuilder {
+   fn default() -> Self {
+      Self::new()
+   }
+}
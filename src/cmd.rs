@@ -1,15 +1,26 @@
-use std::{convert::TryInto, fmt::Debug};
+use crate::UsbIpError;
+use core::{convert::TryInto, fmt::Debug};
 
 /// The command type of the Urb
-#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum UsbCmd {
+   /// `USBIP_CMD_SUBMIT`, the host submits a URB
    Request,
+
+   /// `USBIP_CMD_UNLINK`, the host cancels a URB
    UnlinkRequest,
+
+   /// `USBIP_RET_SUBMIT`, the device completes a URB
    Response,
+
+   /// `USBIP_RET_UNLINK`, the device answers the cancellation
    UnlinkResponse,
 }
 
 impl UsbCmd {
+   /// Returns the value of the command field.
    pub fn to_u32(self) -> u32 {
       match self {
          UsbCmd::Request => 1,
@@ -19,6 +30,10 @@ impl UsbCmd {
       }
    }
 
+   /// Parses the value of the command field.
+   ///
+   /// # Returns
+   /// `None`, if the value is no command of the USBIP specification.
    pub fn try_from_u32(num: u32) -> Option<Self> {
       match num {
          1 => Some(UsbCmd::Request),
@@ -30,16 +45,29 @@ impl UsbCmd {
    }
 }
 
-#[derive(Debug, Clone)]
+/// The header, every CMD and RET message starts with (`usbip_header_basic`).
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UsbIpHeader {
+   /// The kind of the message
    pub command: UsbCmd,
+
+   /// The sequence number of the URB, a response carries the one of its request
    pub seqnum: u32,
+
+   /// The device, `(busnum << 16) | devnum`, only set by the host
    pub devid: u32,
+
+   /// The direction of the transfer
    pub direction: Direction,
+
+   /// The number of the endpoint, without the direction bit
    pub ep: u32,
 }
 
 impl UsbIpHeader {
+   /// Encodes the header.
    pub fn to_array(&self) -> [u8; 20] {
       let mut result = [0; 20];
 
@@ -52,34 +80,106 @@ impl UsbIpHeader {
       result
    }
 
-   pub fn from_slice(data: &[u8]) -> Self {
-      Self {
-         command: UsbCmd::try_from_u32(u32::from_be_bytes(data[0..4].try_into().unwrap())).unwrap(),
+   /// Parses the header from the first 20 bytes of `data`.
+   ///
+   /// # Errors
+   /// If the command or the direction is unknown to the USBIP specification.
+   ///
+   /// # Panics
+   /// If `data` is shorter than 20 bytes.
+   pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+      let command = u32::from_be_bytes(data[0..4].try_into().unwrap());
+      let direction = u32::from_be_bytes(data[12..16].try_into().unwrap());
+
+      Ok(Self {
+         command: UsbCmd::try_from_u32(command).ok_or(UsbIpError::InvalidCommand(command))?,
          seqnum: u32::from_be_bytes(data[4..8].try_into().unwrap()),
          devid: u32::from_be_bytes(data[8..12].try_into().unwrap()),
-         direction: Direction::from_bits_truncate(u32::from_be_bytes(
-            data[12..16].try_into().unwrap(),
-         )),
+         direction: Direction::from_bits(direction).ok_or(UsbIpError::InvalidDirection(direction))?,
          ep: u32::from_be_bytes(data[16..20].try_into().unwrap()),
-      }
+      })
    }
 }
 
 bitflags::bitflags! {
+   /// The `transfer_flags` of a URB, as defined by the Linux USB core.
+   #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+   #[cfg_attr(feature = "defmt", derive(defmt::Format))]
    pub struct TransferFlags: u32 {
+      /// A short packet fails an IN URB
       const SHORT_NOT_OK = 0x00000001;
+      /// The isochronous URB starts with the next free frame
       const ISO_ASAP = 0x00000002;
+      /// Only meaningful to the host controller driver
       const NO_TRANSFER_DMA_MAP = 0x00000004;
+      /// An OUT URB, that ends with a full packet, is terminated by a zero length packet
       const ZERO_PACKET = 0x00000040;
+      /// The host does not need an interrupt on completion
       const NO_INTERRUPT = 0x00000080;
+      /// The host frees the buffer on completion
       const FREE_BUFFER = 0x00000100;
+      /// The direction of a control transfer
       const DIR_MASK = 0x00000200;
    }
 }
 
 bitflags::bitflags! {
+   /// The direction of a transfer, as seen from the host.
+   #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+   #[cfg_attr(feature = "defmt", derive(defmt::Format))]
    pub struct Direction: u32 {
+      /// From the host to the device
       const OUT = 0x0000000;
+      /// From the device to the host
       const IN = 0x0000001;
    }
 }
+
+/// The description of a single packet of an isochronous URB (`usbip_iso_packet_descriptor`).
+///
+/// An array of these follows the data of every isochronous CMD_SUBMIT and RET_SUBMIT.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsbIpIsoPacketDescriptor {
+   /// The offset of the packet in the transfer buffer
+   pub offset: u32,
+
+   /// The number of bytes, the host expects or sends
+   pub length: u32,
+
+   /// The number of bytes, that were transferred
+   pub actual_length: u32,
+
+   /// The negative errno of the packet, e.g. [`UrbStatus::to_errno`]
+   ///
+   /// [`UrbStatus::to_errno`]: crate::protocol::UrbStatus::to_errno
+   pub status: i32,
+}
+
+impl UsbIpIsoPacketDescriptor {
+   /// Encodes the descriptor.
+   pub fn to_array(self) -> [u8; 16] {
+      let mut result = [0; 16];
+
+      result[0..4].copy_from_slice(&self.offset.to_be_bytes());
+      result[4..8].copy_from_slice(&self.length.to_be_bytes());
+      result[8..12].copy_from_slice(&self.actual_length.to_be_bytes());
+      result[12..16].copy_from_slice(&self.status.to_be_bytes());
+
+      result
+   }
+
+   /// Parses the descriptor from the first 16 bytes of `data`.
+   ///
+   /// # Panics
+   /// If `data` is shorter than 16 bytes.
+   pub fn from_slice(data: &[u8]) -> Self {
+      Self {
+         offset: u32::from_be_bytes(data[0..4].try_into().unwrap()),
+         length: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+         actual_length: u32::from_be_bytes(data[8..12].try_into().unwrap()),
+         status: i32::from_be_bytes(data[12..16].try_into().unwrap()),
+      }
+   }
+}
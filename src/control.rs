@@ -0,0 +1,231 @@
+//! The host side of the control transfers on endpoint 0.
+//!
+//! USBIP submits a control transfer as a single URB, which contains the setup packet and,
+//! for OUT transfers, the data. The device on the other hand processes the transfer in stages:
+//! it receives the SETUP packet, sends or receives the data packets and finally
+//! acknowledges the transfer in the status stage.
+//! The control pipe drives the device through these stages, just like a host controller would.
+
+use crate::{
+   cmd::{Direction, UsbIpHeader},
+   request::UsbIpCmdSubmit,
+   response::UsbIpResponse,
+   status::UrbStatus,
+   tweak::Tweak,
+   UsbIpBusInner,
+};
+use alloc::vec::Vec;
+
+/// The stage, the control transfer on endpoint 0 is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlStage {
+   /// The device sends the data of an IN transfer
+   DataIn,
+
+   /// The device receives the data of an OUT transfer and acknowledges it with a ZLP
+   DataOut,
+
+   /// The host acknowledges the data of an IN transfer with a ZLP
+   StatusOut,
+}
+
+/// The control transfer, that is currently in flight on endpoint 0.
+#[derive(Debug, Clone)]
+pub struct ControlTransfer {
+   header: UsbIpHeader,
+   length: usize,
+   stage: ControlStage,
+   data: Vec<u8>,
+
+   /// The effects of the request on the bus, which are applied once the device accepted it
+   tweak: Option<Tweak>,
+}
+
+impl ControlTransfer {
+   /// Returns the stage, the transfer is in.
+   pub fn stage(&self) -> ControlStage {
+      self.stage
+   }
+
+   /// Returns the sequence number of the URB.
+   pub fn seqnum(&self) -> u32 {
+      self.header.seqnum
+   }
+
+   /// Returns the length of the data stage.
+   pub fn length(&self) -> usize {
+      self.length
+   }
+}
+
+impl UsbIpBusInner {
+   /// Starts a control transfer on endpoint 0.
+   ///
+   /// Only one control transfer can be in flight at a time, a new SETUP is rejected
+   /// until the previous transfer completed.
+   pub fn handle_control(&mut self, header: UsbIpHeader, cmd: UsbIpCmdSubmit, data: Vec<u8>) {
+      if let Some(ref control) = self.control {
+         warn!(
+            "rejecting control transfer {}, transfer {} is still in flight",
+            header.seqnum,
+            control.header.seqnum
+         );
+         self.complete(&header, UrbStatus::Busy);
+         return;
+      }
+
+      // The port reset is handled by the hub, the device only sees the USB reset
+      let tweak = Tweak::from_setup(&cmd.setup);
+      if tweak == Some(Tweak::PortReset) {
+         self.reset_port(&header);
+         return;
+      }
+
+      let ep0 = &mut self.endpoint[0];
+      let ep_out = match (ep0.pipe_in.as_mut(), ep0.pipe_out.as_mut()) {
+         (Some(_), Some(ep_out)) => ep_out,
+         _ => {
+            warn!("received control transfer, but endpoint 0 is not allocated");
+            self.complete(&header, UrbStatus::NoEndpoint);
+            return;
+         }
+      };
+
+      // Receiving a SETUP packet clears the halt of a control endpoint
+      // and aborts whatever was left of the previous transfer
+      ep_out.stalled = false;
+      ep_out.data.clear();
+      ep_out.data.push_back(cmd.setup.to_vec());
+
+      let stage = if header.direction == Direction::IN {
+         ControlStage::DataIn
+      } else {
+         for chunk in data.chunks(ep_out.max_packet_size as usize) {
+            ep_out.data.push_back(chunk.to_vec());
+         }
+         ControlStage::DataOut
+      };
+
+      if let Some(ep_in) = ep0.pipe_in.as_mut() {
+         ep_in.stalled = false;
+         ep_in.data.clear();
+      }
+      ep0.setup_flag = true;
+
+      self.control = Some(ControlTransfer {
+         header,
+         length: cmd.transfer_buffer_length.max(0) as usize,
+         stage,
+         data,
+         tweak,
+      });
+   }
+
+   /// Handles a packet, the device has written to endpoint 0.
+   ///
+   /// # Returns
+   /// - `true` if the host accepted the packet
+   /// - `false` if the host does not expect any data right now
+   pub fn control_write(&mut self, packet: &[u8]) -> bool {
+      let max_packet_size = match self.endpoint[0].pipe_in {
+         Some(ref ep_in) => ep_in.max_packet_size as usize,
+         None => return false,
+      };
+
+      let control = match self.control.as_mut() {
+         Some(control) => control,
+         None => return false,
+      };
+
+      match control.stage {
+         ControlStage::DataIn => {
+            self.endpoint[0].in_complete_flag = true;
+
+            let bytes_left = control.length - control.data.len();
+            if packet.len() > bytes_left {
+               warn!(
+                  "babble on endpoint 0: {} bytes do not fit into the remaining {} bytes",
+                  packet.len(),
+                  bytes_left
+               );
+               control.data.extend_from_slice(&packet[..bytes_left]);
+               self.finish_control(UrbStatus::Babble);
+               return true;
+            }
+
+            control.data.extend_from_slice(packet);
+
+            // The data stage ends with a short packet or once the requested length was sent
+            if packet.len() < max_packet_size || control.data.len() == control.length {
+               control.stage = ControlStage::StatusOut;
+               if let Some(ep_out) = self.endpoint[0].pipe_out.as_mut() {
+                  ep_out.data.push_back(vec![]);
+               }
+            }
+
+            true
+         }
+         // The device acknowledges the OUT transfer with a ZLP
+         ControlStage::DataOut if packet.is_empty() => {
+            self.endpoint[0].in_complete_flag = true;
+            self.finish_control(UrbStatus::Ok);
+            true
+         }
+         ControlStage::DataOut | ControlStage::StatusOut => false,
+      }
+   }
+
+   /// Called, after the device has read a packet from endpoint 0.
+   pub fn control_read(&mut self) {
+      // The device has received the ZLP of the status stage
+      if let Some(ControlStage::StatusOut) = self.control.as_ref().map(ControlTransfer::stage) {
+         self.finish_control(UrbStatus::Ok);
+      }
+   }
+
+   /// Aborts the control transfer in flight, if its URB has the sequence number `seqnum`.
+   ///
+   /// # Returns
+   /// - `true` if the transfer was aborted
+   /// - `false` if there was no such transfer
+   pub fn unlink_control(&mut self, seqnum: u32) -> bool {
+      match self.control {
+         Some(ref control) if control.header.seqnum == seqnum => {
+            self.control = None;
+            self.endpoint[0].reset();
+            true
+         }
+         _ => false,
+      }
+   }
+
+   /// Completes the control transfer in flight with `status`.
+   pub fn finish_control(&mut self, status: UrbStatus) {
+      let control = match self.control.take() {
+         Some(control) => control,
+         None => return,
+      };
+
+      // An OUT transfer reports the length of the sent data, but does not echo it back
+      let (actual_length, data) = match control.header.direction {
+         Direction::IN => (control.data.len(), control.data),
+         _ if status == UrbStatus::Ok => (control.data.len(), vec![]),
+         _ => (0, vec![]),
+      };
+
+      // Whatever is left of the transfer is of no use anymore
+      if status != UrbStatus::Ok {
+         if let Some(ep_out) = self.endpoint[0].pipe_out.as_mut() {
+            ep_out.data.clear();
+         }
+      }
+
+      if let (UrbStatus::Ok, Some(tweak)) = (status, control.tweak) {
+         self.apply_tweak(tweak);
+      }
+
+      let response =
+         UsbIpResponse::ret_submit(&control.header, self.devid, status, actual_length, data);
+      self.outgoing.push_back(response);
+   }
+}
@@ -1,6 +1,6 @@
 //! A collection of functions which allow for better debug output.
 
-use std::fmt::{Debug, Formatter, LowerHex, Result as FmtResult};
+use core::fmt::{Debug, Formatter, LowerHex, Result as FmtResult};
 
 /// Just a thin wrapper to allow for printing in hexadecimal
 #[derive(Clone)]
@@ -8,7 +8,7 @@ pub struct DbgBuf<'a>(pub &'a [u8]);
 
 impl Debug for DbgBuf<'_> {
    fn fmt(&self, f: &mut Formatter) -> FmtResult {
-      if self.0.len() == 0 {
+      if self.0.is_empty() {
          return f.write_str("[]");
       }
 
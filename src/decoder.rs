@@ -0,0 +1,73 @@
+//! Decoding of the incoming byte stream into USBIP frames.
+//!
+//! TCP does not preserve message boundaries, so a frame might arrive in
+//! multiple pieces or together with the next frame.
+//! The decoder buffers the incoming bytes until a complete frame is available.
+//! Decoded frames are not removed from the front of the buffer one by one,
+//! which would copy the rest of the buffer for every frame. Instead, the start
+//! of the remaining bytes is tracked and the buffer is compacted on the next push.
+
+use crate::{
+   op::{OpExportReply, OpRequest},
+   request::UsbIpRequest,
+   UsbIpError,
+};
+use alloc::vec::Vec;
+
+#[derive(Debug, Default)]
+pub struct Decoder {
+   buf: Vec<u8>,
+
+   /// The number of bytes at the front of `buf`, that were already decoded
+   start: usize,
+}
+
+impl Decoder {
+   /// Appends received bytes to the buffer.
+   pub fn push(&mut self, data: &[u8]) {
+      if self.start > 0 {
+         self.buf.drain(..self.start);
+         self.start = 0;
+      }
+      self.buf.extend_from_slice(data);
+   }
+
+   /// Returns the number of bytes, that were not decoded yet.
+   pub fn len(&self) -> usize {
+      self.buf.len() - self.start
+   }
+
+   /// Returns `true`, if there is no partial frame left in the buffer.
+   pub fn is_empty(&self) -> bool {
+      self.len() == 0
+   }
+
+   /// Decodes the next op request, if it was received completely.
+   pub fn decode_op(&mut self) -> Result<Option<OpRequest>, UsbIpError> {
+      let decoded = OpRequest::decode(&self.buf[self.start..])?;
+      Ok(self.consume(decoded))
+   }
+
+   /// Decodes the reply to an export request, if it was received completely.
+   pub fn decode_export_reply(&mut self) -> Result<Option<OpExportReply>, UsbIpError> {
+      let decoded = OpExportReply::decode(&self.buf[self.start..])?;
+      Ok(self.consume(decoded))
+   }
+
+   /// Decodes the next command, if it was received completely.
+   pub fn decode_cmd(&mut self, max_transfer_length: usize) -> Result<Option<UsbIpRequest>, UsbIpError> {
+      let decoded = UsbIpRequest::decode(&self.buf[self.start..], max_transfer_length)?;
+      Ok(self.consume(decoded))
+   }
+
+   /// Removes a decoded frame from the buffer.
+   fn consume<T>(&mut self, decoded: Option<(T, usize)>) -> Option<T> {
+      let (frame, len) = decoded?;
+      self.start += len;
+      if self.start == self.buf.len() {
+         self.buf.clear();
+         self.start = 0;
+      }
+      Some(frame)
+   }
+}
@@ -0,0 +1,393 @@
+//! Parsing of the descriptors, the device reports about itself.
+//!
+//! USBIP needs to know some of the descriptor values before the device is imported
+//! by the host (e.g. to answer `OP_REQ_DEVLIST`).
+//! To learn them, the bus enumerates the device internally, while no host is attached.
+
+use crate::op::{OpDeviceDescriptor, OpInterfaceDescriptor};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::convert::TryInto;
+
+const DESCRIPTOR_TYPE_DEVICE: u8 = 1;
+const DESCRIPTOR_TYPE_CONFIGURATION: u8 = 2;
+const DESCRIPTOR_TYPE_INTERFACE: u8 = 4;
+const DESCRIPTOR_TYPE_ENDPOINT: u8 = 5;
+
+const DEVICE_DESCRIPTOR_LEN: u16 = 18;
+const CONFIGURATION_DESCRIPTOR_LEN: u16 = 9;
+
+/// The relevant fields of a device descriptor.
+#[derive(Debug, Clone)]
+pub struct DeviceDescriptor {
+   pub device_class: u8,
+   pub device_subclass: u8,
+   pub device_protocol: u8,
+   pub vendor: u16,
+   pub product: u16,
+   pub bcd_device: u16,
+   pub num_configurations: u8,
+}
+
+impl DeviceDescriptor {
+   fn from_slice(data: &[u8]) -> Option<Self> {
+      if data.len() < DEVICE_DESCRIPTOR_LEN as usize || data[1] != DESCRIPTOR_TYPE_DEVICE {
+         return None;
+      }
+
+      Some(Self {
+         device_class: data[4],
+         device_subclass: data[5],
+         device_protocol: data[6],
+         vendor: u16::from_le_bytes(data[8..10].try_into().unwrap()),
+         product: u16::from_le_bytes(data[10..12].try_into().unwrap()),
+         bcd_device: u16::from_le_bytes(data[12..14].try_into().unwrap()),
+         num_configurations: data[17],
+      })
+   }
+}
+
+/// The relevant fields of an interface descriptor.
+#[derive(Debug, Clone)]
+pub struct InterfaceDescriptor {
+   pub interface_number: u8,
+   pub alternate_setting: u8,
+   pub interface_class: u8,
+   pub interface_subclass: u8,
+   pub interface_protocol: u8,
+
+   /// The addresses of the endpoints, the alternate setting uses
+   pub endpoints: Vec<u8>,
+}
+
+/// The relevant fields of a configuration descriptor, including all the interfaces.
+#[derive(Debug, Clone)]
+pub struct ConfigurationDescriptor {
+   pub configuration_value: u8,
+   pub interfaces: Vec<InterfaceDescriptor>,
+}
+
+impl ConfigurationDescriptor {
+   /// Returns the length of the configuration descriptor including all the descriptors,
+   /// that follow it, as reported in its header.
+   fn total_length(data: &[u8]) -> Option<u16> {
+      if data.len() < CONFIGURATION_DESCRIPTOR_LEN as usize || data[1] != DESCRIPTOR_TYPE_CONFIGURATION {
+         return None;
+      }
+
+      match u16::from_le_bytes(data[2..4].try_into().unwrap()) {
+         len if len < CONFIGURATION_DESCRIPTOR_LEN => None,
+         len => Some(len),
+      }
+   }
+
+   fn from_slice(data: &[u8]) -> Option<Self> {
+      // The total length must be there, otherwise we miss interfaces
+      let total_length = Self::total_length(data)? as usize;
+      if data.len() < total_length {
+         return None;
+      }
+
+      let mut interfaces = vec![];
+
+      // Walk through the descriptors following the configuration descriptor
+      let mut rest = &data[..total_length];
+      while rest.len() >= 2 {
+         let len = rest[0] as usize;
+         if len < 2 || len > rest.len() {
+            warn!("malformed descriptor in configuration");
+            return None;
+         }
+
+         if rest[1] == DESCRIPTOR_TYPE_INTERFACE && len >= 9 {
+            interfaces.push(InterfaceDescriptor {
+               interface_number: rest[2],
+               alternate_setting: rest[3],
+               interface_class: rest[5],
+               interface_subclass: rest[6],
+               interface_protocol: rest[7],
+               endpoints: vec![],
+            });
+         }
+
+         // The endpoints belong to the interface descriptor, they follow
+         if rest[1] == DESCRIPTOR_TYPE_ENDPOINT && len >= 7 {
+            if let Some(interface) = interfaces.last_mut() {
+               interface.endpoints.push(rest[2]);
+            }
+         }
+
+         rest = &rest[len..];
+      }
+
+      Some(Self {
+         configuration_value: data[5],
+         interfaces,
+      })
+   }
+}
+
+/// A descriptor request of the internal enumeration.
+#[derive(Debug, Clone)]
+struct Request {
+   ty: u8,
+   index: u8,
+
+   /// The number of bytes requested
+   len: u16,
+
+   /// The data received so far
+   data: Vec<u8>,
+}
+
+/// Keeps track of the internal enumeration.
+#[derive(Debug, Clone, Default)]
+pub struct Enumeration {
+   pub device: Option<DeviceDescriptor>,
+
+   /// The configurations, in the order of their indices
+   configurations: Vec<ConfigurationDescriptor>,
+
+   /// The descriptor, that is currently requested
+   request: Option<Request>,
+
+   /// The total length of the configuration, whose header was read last
+   total_length: Option<u16>,
+
+   /// Set if the device refused to report its descriptors
+   failed: bool,
+
+   /// The configuration, the host has selected, or 0 if the device is not configured
+   pub configuration_value: u8,
+
+   /// The alternate settings, the host has selected, by interface number
+   alternate_settings: BTreeMap<u8, u8>,
+}
+
+impl Enumeration {
+   /// Returns `true`, if there is an internal transfer in flight.
+   pub fn is_active(&self) -> bool {
+      self.request.is_some()
+   }
+
+   /// Returns `true`, if all descriptors are known or the device refused to report them.
+   pub fn is_done(&self) -> bool {
+      match self.device {
+         Some(ref device) => {
+            self.failed || self.configurations.len() >= device.num_configurations as usize
+         }
+         None => self.failed,
+      }
+   }
+
+   /// Returns the setup packet of the next descriptor request, if there is a descriptor
+   /// left to request.
+   ///
+   /// A configuration is read in two steps, like a host does: first its header,
+   /// which contains the total length, then the complete configuration.
+   pub fn next_request(&mut self) -> Option<[u8; 8]> {
+      if self.is_active() || self.is_done() {
+         return None;
+      }
+
+      let index = self.configurations.len() as u8;
+      let (ty, len) = match (&self.device, self.total_length) {
+         (None, _) => (DESCRIPTOR_TYPE_DEVICE, DEVICE_DESCRIPTOR_LEN),
+         (Some(_), None) => (DESCRIPTOR_TYPE_CONFIGURATION, CONFIGURATION_DESCRIPTOR_LEN),
+         (Some(_), Some(total_length)) => (DESCRIPTOR_TYPE_CONFIGURATION, total_length),
+      };
+
+      debug!("requesting {} bytes of descriptor {}/{} internally", len, ty, index);
+      self.request = Some(Request {
+         ty,
+         index,
+         len,
+         data: vec![],
+      });
+
+      let len = len.to_le_bytes();
+      Some([0x80, 0x06, index, ty, 0x00, 0x00, len[0], len[1]])
+   }
+
+   /// Processes a packet, the device sent as a response to the internal request.
+   ///
+   /// # Returns
+   /// `true`, if the data stage is complete, the device then expects the status stage.
+   pub fn push_data(&mut self, data: &[u8], max_packet_size: u16) -> bool {
+      let request = match self.request {
+         Some(ref mut request) => request,
+         None => return false,
+      };
+
+      request.data.extend_from_slice(data);
+
+      // A short packet terminates the transfer
+      if data.len() >= max_packet_size as usize && request.data.len() < request.len as usize {
+         return false;
+      }
+
+      let request = self.request.take().unwrap();
+      let ok = match (request.ty, self.total_length.take()) {
+         (DESCRIPTOR_TYPE_DEVICE, _) => {
+            self.device = DeviceDescriptor::from_slice(&request.data);
+            self.device.is_some()
+         }
+         // The header, the complete configuration is requested next
+         (_, None) => {
+            self.total_length = ConfigurationDescriptor::total_length(&request.data);
+            self.total_length.is_some()
+         }
+         (_, Some(_)) => match ConfigurationDescriptor::from_slice(&request.data) {
+            Some(configuration) => {
+               self.configurations.push(configuration);
+               true
+            }
+            None => false,
+         },
+      };
+
+      match ok {
+         true => info!("learned descriptor {}/{} from device", request.ty, request.index),
+         false => self.fail(),
+      }
+      true
+   }
+
+   /// Aborts the internal transfer, if one is in flight.
+   ///
+   /// The descriptor will be requested again later.
+   pub fn abort(&mut self) {
+      self.request = None;
+      self.total_length = None;
+   }
+
+   /// Marks the enumeration as failed, such that it is not retried.
+   pub fn fail(&mut self) {
+      if !self.failed {
+         warn!("device did not report its descriptors, using defaults");
+      }
+
+      self.request = None;
+      self.failed = true;
+   }
+
+   /// Records the configuration, the host has selected.
+   ///
+   /// A new configuration starts with the default alternate setting on every interface.
+   pub fn set_configuration(&mut self, configuration_value: u8) {
+      self.configuration_value = configuration_value;
+      self.alternate_settings.clear();
+   }
+
+   /// Records the alternate setting, the host has selected for an interface.
+   pub fn set_interface(&mut self, interface_number: u8, alternate_setting: u8) {
+      self.alternate_settings.insert(interface_number, alternate_setting);
+   }
+
+   /// Returns the alternate setting, that is active on an interface.
+   pub fn alternate_setting(&self, interface_number: u8) -> u8 {
+      self.alternate_settings.get(&interface_number).copied().unwrap_or(0)
+   }
+
+   /// Returns the addresses of the endpoints, an alternate setting of an interface uses.
+   pub fn interface_endpoints(&self, interface_number: u8, alternate_setting: u8) -> Vec<u8> {
+      match self.configuration() {
+         Some(configuration) => configuration
+            .interfaces
+            .iter()
+            .filter(|interface| {
+               interface.interface_number == interface_number
+                  && interface.alternate_setting == alternate_setting
+            })
+            .flat_map(|interface| interface.endpoints.iter().copied())
+            .collect(),
+         None => vec![],
+      }
+   }
+
+   /// Returns the addresses of the endpoints, a configuration uses in its default alternate
+   /// settings, or `None`, if the configuration is not known.
+   pub fn configuration_endpoints(&self, configuration_value: u8) -> Option<Vec<u8>> {
+      // An unconfigured device has no endpoints, besides endpoint 0
+      if configuration_value == 0 {
+         return Some(vec![]);
+      }
+
+      let configuration = self
+         .configurations
+         .iter()
+         .find(|configuration| configuration.configuration_value == configuration_value)?;
+      Some(
+         configuration
+            .interfaces
+            .iter()
+            .filter(|interface| interface.alternate_setting == 0)
+            .flat_map(|interface| interface.endpoints.iter().copied())
+            .collect(),
+      )
+   }
+
+   /// Returns the configuration, the host has selected, or the first one,
+   /// if the device is not configured.
+   fn configuration(&self) -> Option<&ConfigurationDescriptor> {
+      self
+         .configurations
+         .iter()
+         .find(|configuration| configuration.configuration_value == self.configuration_value)
+         .or_else(|| self.configurations.first())
+   }
+
+   /// Builds the device descriptor as it is reported in the op messages.
+   pub fn op_device_descriptor(&self, busnum: u32, devnum: u32, speed: u32) -> OpDeviceDescriptor {
+      let device = self.device.clone().unwrap_or(DeviceDescriptor {
+         device_class: 0,
+         device_subclass: 0,
+         device_protocol: 0,
+         vendor: 0x1111,
+         product: 0x1010,
+         bcd_device: 0,
+         num_configurations: 1,
+      });
+
+      OpDeviceDescriptor {
+         busnum,
+         devnum,
+         speed,
+         vendor: device.vendor,
+         product: device.product,
+         bcd_device: device.bcd_device,
+         device_class: device.device_class,
+         device_subclass: device.device_subclass,
+         device_protocol: device.device_protocol,
+         configuration_value: self.configuration_value,
+         num_configurations: device.num_configurations,
+         num_interfaces: self.op_interface_descriptors().len() as u8,
+      }
+   }
+
+   /// Builds the list of interfaces as it is reported in the op messages.
+   ///
+   /// Only the active alternate setting of each interface is reported.
+   pub fn op_interface_descriptors(&self) -> Vec<OpInterfaceDescriptor> {
+      match self.configuration() {
+         Some(configuration) => configuration
+            .interfaces
+            .iter()
+            .filter(|interface| {
+               interface.alternate_setting == self.alternate_setting(interface.interface_number)
+            })
+            .map(|interface| OpInterfaceDescriptor {
+               interface_class: interface.interface_class,
+               interface_subclass: interface.interface_subclass,
+               interface_protocol: interface.interface_protocol,
+               padding: 0,
+            })
+            .collect(),
+         None => vec![OpInterfaceDescriptor {
+            interface_class: 0,
+            interface_subclass: 0,
+            interface_protocol: 0,
+            padding: 0,
+         }],
+      }
+   }
+}
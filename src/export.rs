@@ -0,0 +1,169 @@
+//! The export mode, in which the device connects to a host instead of waiting for it.
+//!
+//! This is the flow of `usbip connect` together with `usbipd --device` on the host:
+//! the device opens the TCP connection and sends `OP_REQ_EXPORT`. Once the host has
+//! accepted the device with `OP_REP_EXPORT`, URBs are exchanged like after an import.
+//! If the host is not reachable or the connection is lost, the device tries again later,
+//! waiting a little longer after every failed attempt.
+
+use crate::{
+   handler::ConnectionState,
+   listener::Stream,
+   op::{OpExportRequest, USBIP_VERSION},
+   server::UsbIpServerInner,
+};
+use std::{
+   io::Result as IoResult,
+   net::{SocketAddr, TcpStream},
+   sync::mpsc::{self, Receiver, TryRecvError},
+   time::{Duration, Instant},
+};
+
+/// The time to wait after the first failed attempt.
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The longest time to wait between two attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The time, after which a connection attempt is given up.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A device, that exports itself to a host.
+#[derive(Debug)]
+pub struct Export {
+   devid: u32,
+   addr: SocketAddr,
+
+   /// The connection attempt in flight
+   ///
+   /// Connecting blocks, so it happens on a separate thread, while the server keeps polling.
+   connecting: Option<Receiver<IoResult<TcpStream>>>,
+
+   /// The earliest time of the next attempt
+   next_attempt: Instant,
+
+   /// The time to wait after the next failed attempt
+   backoff: Duration,
+}
+
+impl Export {
+   pub fn new(devid: u32, addr: SocketAddr) -> Self {
+      Self {
+         devid,
+         addr,
+         connecting: None,
+         next_attempt: Instant::now(),
+         backoff: MIN_BACKOFF,
+      }
+   }
+
+   /// Starts a new connection attempt and schedules the next one.
+   fn connect(&mut self) {
+      info!("exporting device {:#x} to {}", self.devid, self.addr);
+
+      let (tx, rx) = mpsc::channel();
+      let addr = self.addr;
+      std::thread::spawn(move || {
+         let _ = tx.send(TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT));
+      });
+      self.connecting = Some(rx);
+
+      self.next_attempt = Instant::now() + self.backoff;
+      self.backoff = Duration::min(self.backoff * 2, MAX_BACKOFF);
+   }
+}
+
+impl UsbIpServerInner {
+   /// Connects the exported devices, that are not attached to a host, and sends the export requests.
+   pub fn handle_exports(&mut self) {
+      let mut dropped = vec![];
+
+      for index in 0..self.exports.len() {
+         let devid = self.exports[index].devid;
+
+         let bus = match self.device_by_devid(devid) {
+            Some(bus) => bus,
+            // The bus was dropped, so the export ends
+            None => {
+               dropped.push(devid);
+               continue;
+            }
+         };
+
+         // Wait for the connection attempt in flight
+         if let Some(ref connecting) = self.exports[index].connecting {
+            let stream = match connecting.try_recv() {
+               Ok(stream) => stream,
+               Err(TryRecvError::Empty) => continue,
+               Err(TryRecvError::Disconnected) => Err(std::io::ErrorKind::Other.into()),
+            };
+            self.exports[index].connecting = None;
+
+            let addr = self.exports[index].addr;
+            let stream = match stream {
+               Ok(stream) => stream,
+               Err(err) => {
+                  warn!("failed to connect to {}: {}", addr, display!(err));
+                  continue;
+               }
+            };
+
+            // A host might have imported the device, while the connection was established
+            if !self.is_exportable(devid) {
+               info!("device {:#x} became busy, dropping the connection to {}", devid, addr);
+               continue;
+            }
+
+            let request = OpExportRequest {
+               version: USBIP_VERSION,
+               device: bus.lock().op_device(),
+            };
+
+            // The host answers with OP_REP_EXPORT, the device is attached once it accepted
+            if let Err(err) = self.handler.add_connection(
+               Stream::Tcp(stream),
+               addr,
+               ConnectionState::Exporting,
+               Some(devid),
+               &request.to_vec(),
+            ) {
+               self.report(err);
+            }
+            continue;
+         }
+
+         if Instant::now() >= self.exports[index].next_attempt && self.is_exportable(devid) {
+            self.exports[index].connect();
+         }
+      }
+
+      self.exports.retain(|export| !dropped.contains(&export.devid));
+   }
+
+   /// Returns `true`, if the device with `devid` is ready to be attached to a host.
+   fn is_exportable(&self, devid: u32) -> bool {
+      // The device is exported only once
+      if self.handler.is_imported(devid) {
+         return false;
+      }
+
+      let bus = match self.device_by_devid(devid) {
+         Some(bus) => bus,
+         None => return false,
+      };
+      let bus = bus.lock();
+
+      // The host learns about the device from the export request, so the descriptors must be known
+      bus.plugged
+         && !bus.reset
+         && bus.connection == ConnectionState::Listening
+         && bus.enumeration.is_done()
+   }
+
+   /// Called, when the host accepted the exported device.
+   pub fn export_accepted(&mut self, devid: u32) {
+      if let Some(export) = self.exports.iter_mut().find(|export| export.devid == devid) {
+         export.backoff = MIN_BACKOFF;
+      }
+   }
+}
@@ -1,367 +1,1056 @@
+#[cfg(feature = "std")]
+use crate::listener::{Listener, Stream};
 use crate::{
-   cmd::{Direction, TransferFlags, UsbCmd, UsbIpHeader},
-   op::{OpDeviceDescriptor, OpInterfaceDescriptor, OpRequest, OpResponse, OpResponseCommand},
+   cmd::{Direction, TransferFlags, UsbIpHeader, UsbIpIsoPacketDescriptor},
+   limits::Limits,
+   op::{
+      is_supported_version, OpDevice, OpRequest, OpResponse, OpResponseCommand, OpStatus,
+      USBIP_VERSION,
+   },
    request::{UsbIpCmdSubmit, UsbIpCmdUnlink, UsbIpRequest, UsbIpRequestCmd},
-   response::{UsbIpResponse, UsbIpResponseCmd, UsbIpRetSubmit, UsbIpRetUnlink},
-   UsbIpBusInner,
+   response::UsbIpResponse,
+   server::UsbIpServerInner,
+   session::Session,
+   status::UrbStatus,
+   UsbIpBusInner, UsbIpError,
 };
+use alloc::{collections::VecDeque, vec::Vec};
+use core::net::SocketAddr;
+#[cfg(feature = "std")]
 use std::{
-   io::{ErrorKind, Write},
-   net::{TcpListener, TcpStream},
+   io::{ErrorKind, Read, Result as IoResult, Write},
+   net::TcpListener,
+};
+#[cfg(all(feature = "std", unix))]
+use std::path::{Path, PathBuf};
+use usb_device::{
+   endpoint::{EndpointAddress, EndpointType},
+   UsbDirection,
 };
-use usb_device::{endpoint::EndpointType, UsbError};
 
 #[derive(Debug)]
 pub struct SocketHandler {
-   listener: TcpListener,
-   connection: Option<TcpStream>,
+   /// The listener, `None` if the server is only driven through [`UsbIpSession`]s
+   ///
+   /// [`UsbIpSession`]: crate::UsbIpSession
+   #[cfg(feature = "std")]
+   listener: Option<Listener>,
+   /// The address of the TCP listener, `None` if the handler does not listen on TCP
+   local_addr: Option<SocketAddr>,
+   connections: Vec<Connection>,
+   next_id: u64,
+}
+
+/// The state of a USBIP connection.
+///
+/// This is independent of the state of the USB bus, i.e. a USB reset
+/// does not change the connection state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+   /// No host has imported the device yet
+   Listening,
+
+   /// A host connected and exchanges op messages
+   Negotiating,
+
+   /// The device has asked a host to import it and waits for the reply
+   #[cfg_attr(not(feature = "std"), allow(dead_code))]
+   Exporting,
+
+   /// The device was imported, URBs are exchanged
+   Imported,
+
+   /// The connection is being torn down
+   Closing,
+}
+
+/// An OUT URB, whose packets have not all been read by the device yet.
+#[derive(Debug, Clone)]
+pub struct OutTransfer {
+   pub header: UsbIpHeader,
+
+   /// The packets, that wait for room in the pipe
+   pub packets: VecDeque<Vec<u8>>,
+
+   /// The number of packets in the pipe, the device has not read yet
+   pub queued: usize,
+
+   /// The number of bytes, the device has read
+   pub actual_length: usize,
+}
+
+/// Without `std`, there are no sockets, the bytes are always passed by the user.
+#[cfg(not(feature = "std"))]
+type Stream = core::convert::Infallible;
+
+/// A session together with the transport, it runs over.
+#[derive(Debug)]
+struct Connection {
+   /// Identifies the connection, while others are opened and closed
+   id: u64,
+
+   /// The socket, `None` if the bytes are passed in and out by the user
+   stream: Option<Stream>,
+
+   session: Session,
 }
 
-// TODO: Allow settable device speed
-const DEVICE_SPEED: u32 = 3;
+impl Connection {
+   /// Reads the data, that is available on the socket, into the session.
+   ///
+   /// Once more than `max_frame_length` bytes wait to be decoded, reading stops,
+   /// so a host, that sends faster than the device handles the commands, is slowed down
+   /// by the socket instead of filling up the memory.
+   ///
+   /// # Returns
+   /// - `Ok(true)` if the connection is still open
+   /// - `Ok(false)` if it was closed by the peer
+   #[cfg_attr(not(feature = "std"), allow(unused_variables))]
+   fn receive(&mut self, max_frame_length: usize) -> Result<bool, UsbIpError> {
+      #[cfg(feature = "std")]
+      if let Some(ref mut stream) = self.stream {
+         let mut buf = [0; 4096];
+
+         while self.session.decoder.len() <= max_frame_length {
+            match stream.read(&mut buf) {
+               Ok(0) => return Ok(false),
+               Ok(len) => self.session.push(&buf[..len]),
+               Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(true),
+               Err(err) if err.kind() == ErrorKind::Interrupted => (),
+               Err(err) => return Err(err.into()),
+            }
+         }
+      }
+
+      // Without a socket, the user pushes the data into the session
+      Ok(true)
+   }
+
+   /// Sends as much of the queued data as possible without blocking.
+   fn flush(&mut self) -> Result<(), UsbIpError> {
+      #[cfg(feature = "std")]
+      if let Some(ref mut stream) = self.stream {
+         while !self.session.pending().is_empty() {
+            match stream.write(self.session.pending()) {
+               Ok(0) => return Err(UsbIpError::ConnectionClosed),
+               Ok(len) => self.session.consume(len),
+               Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+               Err(err) if err.kind() == ErrorKind::Interrupted => (),
+               Err(err) => return Err(err.into()),
+            }
+         }
+      }
+
+      // Without a socket, the data waits until the user takes it
+      Ok(())
+   }
+}
 
 impl SocketHandler {
-   /// Create a new handler
-   pub fn new() -> Self {
-      let listener = TcpListener::bind(("127.0.0.1", 3240)).unwrap();
-      listener.set_nonblocking(true).unwrap();
+   /// Create a new handler, listening on `addr`
+   #[cfg(feature = "std")]
+   pub fn new(addr: SocketAddr) -> IoResult<Self> {
+      let listener = TcpListener::bind(addr)?;
+      listener.set_nonblocking(true)?;
+
+      // Query the address, since the port might have been chosen by the OS
+      let local_addr = listener.local_addr()?;
+      info!("listening on {}", local_addr);
+
+      Ok(Self {
+         listener: Some(Listener::Tcp(listener)),
+         local_addr: Some(local_addr),
+         connections: vec![],
+         next_id: 0,
+      })
+   }
+
+   /// Create a new handler, listening on the Unix domain socket at `path`
+   #[cfg(all(feature = "std", unix))]
+   pub fn bind_unix(path: &Path) -> IoResult<Self> {
+      let listener = Listener::bind_unix(path)?;
+      info!("listening on {}", display!(path.display()));
+
+      Ok(Self {
+         listener: Some(listener),
+         ..Self::unbound()
+      })
+   }
+
+   /// Create a new handler, that does not listen at all.
+   pub fn unbound() -> Self {
       Self {
-         listener,
-         connection: None,
+         #[cfg(feature = "std")]
+         listener: None,
+         local_addr: None,
+         connections: vec![],
+         next_id: 0,
       }
    }
 
-   pub fn is_connected(&self) -> bool {
-      self.connection.is_some()
+   pub fn local_addr(&self) -> Option<SocketAddr> {
+      self.local_addr
+   }
+
+   /// Returns the path of the Unix domain socket, the handler is listening on.
+   #[cfg(all(feature = "std", unix))]
+   pub fn path(&self) -> Option<PathBuf> {
+      self.listener.as_ref()?.path().map(Path::to_path_buf)
+   }
+
+   /// Adds a new connection and returns its id.
+   fn push(&mut self, stream: Option<Stream>, session: Session) -> u64 {
+      let id = self.next_id;
+      self.next_id += 1;
+
+      self.connections.push(Connection { id, stream, session });
+      id
+   }
+
+   /// Adds a connection, that was opened by the device, and sends `data` over it.
+   #[cfg(feature = "std")]
+   pub fn add_connection(
+      &mut self,
+      stream: Stream,
+      addr: SocketAddr,
+      state: ConnectionState,
+      devid: Option<u32>,
+      data: &[u8],
+   ) -> Result<(), UsbIpError> {
+      // We must never block inside of poll
+      stream.set_nonblocking(true)?;
+
+      let mut session = Session::new(addr.to_string(), state, devid);
+      session.send(data);
+      self.push(Some(stream), session);
+
+      let result = self.connections.last_mut().unwrap().flush();
+      if result.is_err() {
+         self.connections.pop();
+      }
+      result
+   }
+
+   /// Adds a connection over a stream, the user has opened, e.g. stdin and stdout.
+   ///
+   /// The host on the other end lists and imports the devices, like over an accepted socket.
+   #[cfg(feature = "std")]
+   pub fn add_stream(&mut self, stream: Stream) {
+      let session = Session::new(
+         format!("stream {}", self.next_id),
+         ConnectionState::Negotiating,
+         None,
+      );
+      info!("new {}", session.peer);
+
+      self.push(Some(stream), session);
+   }
+
+   /// Returns `true`, if the device with `devid` is imported over any of the connections.
+   pub fn is_imported(&self, devid: u32) -> bool {
+      self
+         .connections
+         .iter()
+         .any(|connection| connection.session.devid == Some(devid))
+   }
+
+   /// Returns the index of the connection with the id `id`, if it is still open.
+   fn index(&self, id: u64) -> Option<usize> {
+      self.connections.iter().position(|connection| connection.id == id)
    }
 }
 
-impl UsbIpBusInner {
+impl UsbIpServerInner {
    pub fn handle_socket(&mut self) {
-      match self.handler.connection {
-         // If not connected, listen for new connections
-         None => match self.handler.listener.accept() {
-            Ok((connection, addr)) => {
-               log::info!("new connection from: {}", addr);
-               self.handler.connection = Some(connection)
+      // Accept all the new connections
+      #[cfg(feature = "std")]
+      self.accept();
+
+      // Connect the devices, that export themselves
+      #[cfg(feature = "std")]
+      self.handle_exports();
+
+      // Receive the data of every connection
+      for index in 0..self.handler.connections.len() {
+         if let Err(err) = self.handle_connection(index) {
+            self.close(index, err);
+         }
+      }
+
+      // Send out the responses, that were queued since the last poll, all at once
+      self.flush();
+
+      self.remove_closed();
+   }
+
+   /// Accepts all the pending connections on the listener.
+   #[cfg(feature = "std")]
+   fn accept(&mut self) {
+      while let Some(ref listener) = self.handler.listener {
+         match listener.accept() {
+            Ok((stream, peer)) => {
+               info!("new connection from: {}", peer);
+
+               // We must never block inside of poll
+               if let Err(err) = stream.set_nonblocking(true) {
+                  self.report(err.into());
+                  continue;
+               }
+
+               let session = Session::new(peer, ConnectionState::Negotiating, None);
+               self.handler.push(Some(stream), session);
             }
-            Err(err) if err.kind() == ErrorKind::WouldBlock => (),
-            Err(err) => panic!("unexpected error: {}", err),
-         },
+            Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+            Err(err) => {
+               self.report(err.into());
+               break;
+            }
+         }
+      }
+   }
 
-         // If connected, receive the data
-         Some(ref mut stream) => {
-            match self.reset {
-               // If in reset state, answer op msgs
-               true => {
-                  // in case of Op, we directly send a response here
-                  let op = match OpRequest::read(stream) {
-                     Ok(op) => op,
-                     Err(err) if err.kind() == ErrorKind::WouldBlock => return,
-                     Err(err) if err.kind() == ErrorKind::NotConnected => {
-                        self.handler.connection = None;
-                        return;
-                     }
-                     Err(err) => panic!("unexpected error {}", err),
-                  };
-                  self.handle_op(op);
+   /// Reports the error and closes the connection, that caused it.
+   fn close(&mut self, index: usize, err: UsbIpError) {
+      self.report(err);
+      self.handler.connections[index].session.state = ConnectionState::Closing;
+   }
+
+   /// Tears down the connections, that were closed.
+   ///
+   /// The devices are detached right away, but a session without a socket is kept,
+   /// until the user has taken the bytes, that are left.
+   fn remove_closed(&mut self) {
+      for index in 0..self.handler.connections.len() {
+         let connection = &mut self.handler.connections[index];
+         if connection.session.state != ConnectionState::Closing {
+            continue;
+         }
+
+         // Give the peer a last chance to learn why the connection is closed
+         let _ = connection.flush();
+
+         // A device, that was refused by the host, was never attached,
+         // detaching it would only cause a spurious USB reset
+         if core::mem::take(&mut connection.session.attached) {
+            if let Some(bus) = connection.session.devid.and_then(|id| self.device_by_devid(id)) {
+               bus.lock().detach();
+            }
+         }
+      }
+
+      self.handler.connections.retain(|connection| {
+         let closed = connection.session.state == ConnectionState::Closing
+            && (connection.stream.is_some() || connection.session.pending().is_empty());
+         if closed {
+            info!("connection to {} closed", connection.session.peer);
+         }
+         !closed
+      });
+   }
+
+   /// Receives the available data on a connection and handles all complete requests.
+   fn handle_connection(&mut self, index: usize) -> Result<(), UsbIpError> {
+      let max_frame_length = self.limits(index).max_frame_length();
+      let open = self.handler.connections[index].receive(max_frame_length)?;
+
+      self.handle_session(index)?;
+
+      let connection = &mut self.handler.connections[index];
+      if !open {
+         if !connection.session.decoder.is_empty() {
+            warn!("connection to {} closed in the middle of a frame", connection.session.peer);
+         }
+
+         // The host went away without answering the export request completely
+         if connection.session.state == ConnectionState::Exporting {
+            return Err(UsbIpError::ConnectionClosed);
+         }
+         connection.session.state = ConnectionState::Closing;
+      }
+
+      Ok(())
+   }
+
+   /// Handles all the complete requests, a session has received.
+   ///
+   /// This is the transport independent part of a connection,
+   /// the responses are queued in the session, but not sent.
+   fn handle_session(&mut self, index: usize) -> Result<(), UsbIpError> {
+      loop {
+         let session = &mut self.handler.connections[index].session;
+
+         match session.state {
+            // If no device is imported yet, answer op msgs
+            ConnectionState::Negotiating => {
+               // in case of Op, we directly send a response here
+               let op = match session.decoder.decode_op()? {
+                  Some(op) => op,
+                  None => break,
+               };
+               self.handle_op(index, op)?;
+            }
+            // The host answers the export request
+            ConnectionState::Exporting => {
+               let reply = match session.decoder.decode_export_reply()? {
+                  Some(reply) => reply,
+                  None => break,
+               };
+
+               let devid = session.devid.unwrap();
+               debug!("reply version is {}", reply.header.version);
+               if !is_supported_version(reply.header.version) {
+                  return Err(UsbIpError::UnsupportedVersion(reply.header.version));
+               }
+               if reply.header.status != OpStatus::Ok.to_u32() {
+                  warn!("host refused device {:#x} with status {}", devid, reply.header.status);
+                  return Err(UsbIpError::StatusNotOk(reply.header.status));
+               }
+               if reply.return_code != 0 {
+                  warn!("host refused device {:#x} with {}", devid, reply.return_code);
+                  return Err(UsbIpError::StatusNotOk(reply.return_code as u32));
+               }
+
+               info!(
+                  "device {:#x} was imported by {}, protocol version {:#06x}",
+                  devid,
+                  session.peer,
+                  reply.header.version
+               );
+               session.state = ConnectionState::Imported;
+               session.attached = true;
+               session.version = Some(reply.header.version);
+               if let Some(bus) = self.device_by_devid(devid) {
+                  bus.lock().attach(reply.header.version);
                }
-               // If not in reset state, expect commands
-               false => {
-                  let cmd = match UsbIpRequest::read(stream) {
-                     Ok(cmd) => cmd,
-                     Err(err) if err.kind() == ErrorKind::WouldBlock => return,
-                     Err(err) if err.kind() == ErrorKind::NotConnected => {
-                        // If the connection is no longer connected, return to initial state
-                        self.reset = true;
-                        self.handler.connection = None;
-                        return;
-                     }
-                     Err(err) => panic!("unexpected error {}", err),
+               #[cfg(feature = "std")]
+               self.export_accepted(devid);
+            }
+            // If a device is imported, expect commands
+            ConnectionState::Imported => {
+               let devid = session.devid.unwrap();
+
+               let mut limits = Limits::default();
+               if let Some(bus) = self.device_by_devid(devid) {
+                  let (connection, reset) = {
+                     let bus = bus.lock();
+                     limits = bus.limits;
+                     (bus.connection, bus.reset)
                   };
-                  self.handle_usbip_pkg(cmd);
+
+                  // The device has disconnected itself from the host
+                  if connection != ConnectionState::Imported {
+                     info!("device {:#x} went away, closing the connection", devid);
+                     self.flush_connection(index)?;
+                     self.handler.connections[index].session.state = ConnectionState::Closing;
+                     break;
+                  }
+
+                  // The device must process the USB reset before it can handle URBs,
+                  // until then, the commands stay in the buffer
+                  if reset {
+                     break;
+                  }
+               }
+
+               let session = &mut self.handler.connections[index].session;
+               let cmd = match session.decoder.decode_cmd(limits.max_transfer_length)? {
+                  Some(cmd) => cmd,
+                  None => break,
+               };
+
+               if cmd.header.devid != devid {
+                  return Err(UsbIpError::UnknownDevice(cmd.header.devid));
+               }
+
+               match self.device_by_devid(devid) {
+                  Some(bus) => bus.lock().handle_usbip_pkg(cmd)?,
+                  // The bus was dropped, while the device was still imported
+                  None => {
+                     let response = match cmd.cmd {
+                        UsbIpRequestCmd::Cmd(_) => {
+                           UsbIpResponse::ret_submit(&cmd.header, devid, UrbStatus::NoDevice, 0, vec![])
+                        }
+                        UsbIpRequestCmd::Unlink(_) => {
+                           UsbIpResponse::ret_unlink(&cmd.header, devid, UrbStatus::NoDevice)
+                        }
+                     };
+                     let session = &mut self.handler.connections[index].session;
+                     session.send(&response.to_vec());
+                  }
                }
             }
+            ConnectionState::Listening | ConnectionState::Closing => break,
          }
       }
+
+      Ok(())
    }
 
-   pub fn try_send_pending(&mut self, ep_addr: usize) {
-      let ep = match self.get_endpoint(ep_addr) {
-         Ok(ep) => ep,
-         Err(_) => return,
+   /// Sends out the responses, the imported devices have queued.
+   fn flush(&mut self) {
+      for index in 0..self.handler.connections.len() {
+         if let Err(err) = self.flush_connection(index) {
+            self.close(index, err);
+         }
+      }
+   }
+
+   /// Sends out the responses, the device imported over a connection has queued.
+   fn flush_connection(&mut self, index: usize) -> Result<(), UsbIpError> {
+      let session = &mut self.handler.connections[index].session;
+      if session.state == ConnectionState::Closing {
+         return Ok(());
+      }
+
+      if let Some(bus) = session.devid.and_then(|devid| self.device_by_devid(devid)) {
+         let session = &mut self.handler.connections[index].session;
+         let mut bus = bus.lock();
+         while let Some(response) = bus.outgoing.pop_front() {
+            debug!("{:?}", response);
+            session.send(&response.to_vec());
+         }
+      }
+
+      // Together with what was left over last time
+      let connection = &mut self.handler.connections[index];
+      connection.flush()?;
+
+      // A host, that does not read the responses, must not fill up the memory
+      let pending = connection.session.pending().len();
+      if pending > self.limits(index).max_buffered_bytes {
+         return Err(UsbIpError::SendBufferFull(pending));
+      }
+
+      Ok(())
+   }
+
+   /// Returns the limits of the device imported over a connection.
+   ///
+   /// As long as no device is imported, the default limits apply.
+   fn limits(&self, index: usize) -> Limits {
+      self.handler.connections[index]
+         .session
+         .devid
+         .and_then(|devid| self.device_by_devid(devid))
+         .map(|bus| bus.lock().limits)
+         .unwrap_or_default()
+   }
+
+   /// Handles an incomming op packet, queues the corresponding response
+   fn handle_op(&mut self, index: usize, op: OpRequest) -> Result<(), UsbIpError> {
+      let version = op.header().version;
+      debug!("request version is {}", version);
+      match op {
+         OpRequest::ListDevices(_) => info!("received request to list devices"),
+         OpRequest::ConnectDevice(_, ref bus_id) => {
+            info!("received request to connect device {}", bus_id)
+         }
+      }
+
+      // Older versions are answered in their own version, unknown ones are refused
+      if !is_supported_version(version) {
+         warn!("refusing request of unsupported protocol version {:#06x}", version);
+
+         let response = OpResponse {
+            version: USBIP_VERSION,
+            status: OpStatus::Na,
+            cmd: match op {
+               OpRequest::ListDevices(_) => OpResponseCommand::ListDevices(vec![]),
+               OpRequest::ConnectDevice(_, _) => OpResponseCommand::ConnectDevice(None),
+            },
+         };
+         self.handler.connections[index].session.send(&response.to_vec());
+         return Err(UsbIpError::UnsupportedVersion(version));
+      }
+      self.handler.connections[index].session.version = Some(version);
+
+      let response = match op {
+         OpRequest::ListDevices(header) => OpResponse {
+            version: header.version,
+            status: OpStatus::Ok,
+            // Unplugged devices and the ones, whose descriptors are not known yet, are not listed
+            cmd: OpResponseCommand::ListDevices(
+               self
+                  .devices()
+                  .iter()
+                  .map(|bus| bus.lock())
+                  .filter(|bus| bus.plugged && bus.enumeration.is_done())
+                  .map(|bus| bus.op_device())
+                  .collect(),
+            ),
+         },
+         OpRequest::ConnectDevice(header, bus_id) => match self
+            .device_by_bus_id(&bus_id)
+            .filter(|bus| bus.lock().plugged)
+         {
+            Some(bus) if !bus.lock().enumeration.is_done() => {
+               info!("refusing import, device {} is still being enumerated", bus_id);
+               OpResponse {
+                  version: header.version,
+                  status: OpStatus::DevBusy,
+                  cmd: OpResponseCommand::ConnectDevice(None),
+               }
+            }
+            Some(bus) => {
+               let mut bus = bus.lock();
+
+               if self.handler.is_imported(bus.devid) {
+                  warn!("requested device {} is already imported", bus_id);
+                  OpResponse {
+                     version: header.version,
+                     status: OpStatus::DevBusy,
+                     cmd: OpResponseCommand::ConnectDevice(None),
+                  }
+               } else {
+                  info!("device {} is imported", bus_id);
+                  bus.attach(version);
+                  let session = &mut self.handler.connections[index].session;
+                  session.state = ConnectionState::Imported;
+                  session.devid = Some(bus.devid);
+                  session.attached = true;
+
+                  OpResponse {
+                     version: header.version,
+                     status: OpStatus::Ok,
+                     cmd: OpResponseCommand::ConnectDevice(Some(bus.op_device())),
+                  }
+               }
+            }
+            None => {
+               warn!("requested device {} does not exist", bus_id);
+               OpResponse {
+                  version: header.version,
+                  status: OpStatus::NoDev,
+                  cmd: OpResponseCommand::ConnectDevice(None),
+               }
+            }
+         },
+      };
+
+      self.handler.connections[index].session.send(&response.to_vec());
+
+      // Like usbipd, we close the connection after a failed import
+      if response.status != OpStatus::Ok {
+         self.handler.connections[index].session.state = ConnectionState::Closing;
+      }
+
+      Ok(())
+   }
+
+   /// Opens a session without a socket and returns its id.
+   pub fn open_session(&mut self) -> u64 {
+      let id = self.handler.next_id;
+      let session = Session::new(format!("session {}", id), ConnectionState::Negotiating, None);
+      info!("new {}", session.peer);
+
+      self.handler.push(None, session)
+   }
+
+   /// Passes the bytes, the user has received, to a session.
+   pub fn receive_session(&mut self, id: u64, data: &[u8]) -> Result<(), UsbIpError> {
+      let index = match self.handler.index(id) {
+         Some(index) => index,
+         None => return Err(UsbIpError::ConnectionClosed),
       };
+      self.handler.connections[index].session.push(data);
 
-      if !ep.is_rts() {
-         return;
+      let result = self.handle_session(index);
+      if let Err(ref err) = result {
+         self.close(index, err.clone());
       }
+      self.remove_closed();
 
-      let (header, _cmd, _) = match ep.pending_ins.pop_front() {
-         Some(urb) => urb,
+      result
+   }
+
+   /// Takes the bytes, a session has queued for the user to send.
+   pub fn transmit_session(&mut self, id: u64) -> Vec<u8> {
+      let index = match self.handler.index(id) {
+         Some(index) => index,
+         None => return vec![],
+      };
+      if let Err(err) = self.flush_connection(index) {
+         self.close(index, err);
+      }
+
+      let data = self.handler.connections[index].session.take();
+      self.remove_closed();
+      data
+   }
+
+   /// Takes the bytes, a session has queued, for a transport, that still buffers `buffered` bytes itself.
+   ///
+   /// A peer, that does not read the bytes, is disconnected, once the transport buffers more
+   /// than the device allows, just like a TCP connection.
+   #[cfg(feature = "smoltcp")]
+   pub fn transmit_session_within(&mut self, id: u64, buffered: usize) -> Result<Vec<u8>, UsbIpError> {
+      let index = match self.handler.index(id) {
+         Some(index) => index,
+         None => return Ok(vec![]),
+      };
+      if buffered <= self.limits(index).max_buffered_bytes {
+         return Ok(self.transmit_session(id));
+      }
+
+      let err = UsbIpError::SendBufferFull(buffered);
+      self.close(index, err.clone());
+      self.handler.connections[index].session.take();
+      self.remove_closed();
+      Err(err)
+   }
+
+   /// Returns the version of the protocol, the peer of a session speaks, once it is known.
+   pub fn session_version(&self, id: u64) -> Option<u16> {
+      self.handler
+         .index(id)
+         .and_then(|index| self.handler.connections[index].session.version)
+   }
+
+   /// Returns `true`, if the session was closed or is being closed.
+   pub fn is_session_closed(&self, id: u64) -> bool {
+      match self.handler.index(id) {
+         Some(index) => self.handler.connections[index].session.state == ConnectionState::Closing,
+         None => true,
+      }
+   }
+
+   /// Closes a session, the bytes it has still queued are dropped.
+   pub fn close_session(&mut self, id: u64) {
+      if let Some(index) = self.handler.index(id) {
+         let session = &mut self.handler.connections[index].session;
+         session.state = ConnectionState::Closing;
+         session.take();
+      }
+      self.remove_closed();
+   }
+}
+
+impl UsbIpBusInner {
+   /// Returns the description of this device, as it is reported in the op messages.
+   pub fn op_device(&self) -> OpDevice {
+      OpDevice {
+         path: format!("/sys/devices/pci0000:00/0000:00:01.2/usb1/{}", self.bus_id),
+         bus_id: self.bus_id.clone(),
+         descriptor: self.enumeration.op_device_descriptor(
+            self.devid >> 16,
+            self.devid & 0xffff,
+            self.speed.to_u32(),
+         ),
+         interfaces: self.enumeration.op_interface_descriptors(),
+      }
+   }
+
+   /// Moves the packets, the device has written, into the pending IN URBs
+   /// and completes every URB, that is either full or was terminated by a short packet.
+   ///
+   /// Packets, that arrive while no URB is pending, stay queued in the pipe
+   /// until the host asks for them.
+   pub fn try_send_pending(&mut self, ep_addr: usize) {
+      let ep = match self.endpoint.get_mut(ep_addr) {
+         Some(ep) => ep,
          None => return,
       };
-      //let bytes_requested = cmd.transfer_buffer_length;
 
-      let ep_in = match ep.get_in() {
-         Ok(ep_in) => ep_in,
-         Err(UsbError::InvalidEndpoint) => return,
-         Err(e) => panic!("unexpected error {:?} while processing in packet", e),
+      let ep_in = match ep.pipe_in {
+         Some(ref mut ep_in) => ep_in,
+         None => return,
       };
 
-      // Read data from the packet buffer into the output buffer
-      // We must be careful to not send more bytes than requested
-      // FIXME: Fix this up so it supports transfer_buffers smaller than waiting data
-      let mut out_buf = vec![];
-      while let Some(data) = ep_in.data.pop_front() {
-         //let bytes_left = bytes_requested as usize - out_buf.len();
-         //let bytes_to_read = usize::min(data.len(), bytes_left);
+      while let Some((_, cmd, buf)) = ep.pending_ins.front_mut() {
+         let packet = match ep_in.data.pop_front() {
+            Some(packet) => packet,
+            None => return,
+         };
+
+         // The host has taken the packet, so the device can write the next one
+         ep.in_complete_flag = true;
+
+         let requested = cmd.transfer_buffer_length.max(0) as usize;
+         let bytes_left = requested - buf.len();
 
-         //out_buf.extend_from_slice(&data[..bytes_to_read]);
-         out_buf.extend_from_slice(&data);
+         let status = if packet.len() > bytes_left {
+            // The device sent more than fits into the transfer buffer
+            warn!(
+               "babble on endpoint {}: {} bytes do not fit into the remaining {} bytes",
+               ep_addr,
+               packet.len(),
+               bytes_left
+            );
+            buf.extend_from_slice(&packet[..bytes_left]);
+            UrbStatus::Babble
+         } else {
+            buf.extend_from_slice(&packet);
+
+            if buf.len() < requested && packet.len() == ep_in.max_packet_size as usize {
+               // A full packet does not end the transfer, wait for more data
+               continue;
+            }
 
-         // if bytes_to_read != data.len() {
-         //    assert_eq!(out_buf.len(), bytes_requested as usize);
-         //    ep_in.data.push_front(data[bytes_to_read..].to_vec());
-         //    break;
-         // }
+            if buf.len() < requested && cmd.transfer_flags.contains(TransferFlags::SHORT_NOT_OK) {
+               UrbStatus::ShortPacket
+            } else {
+               UrbStatus::Ok
+            }
+         };
+
+         let (header, _, buf) = ep.pending_ins.pop_front().unwrap();
+         self
+            .outgoing
+            .push_back(UsbIpResponse::ret_submit(&header, self.devid, status, buf.len(), buf));
       }
+   }
 
-      // After sending, the in_complete can be set
-      ep.in_complete_flag = true;
+   /// Moves the packets of the first pending OUT URB into the pipe, as long as there is room.
+   ///
+   /// The packets of the next URB follow, once the device has read all the packets of this one.
+   pub fn try_receive_pending(&mut self, ep_addr: usize) {
+      let ep = match self.endpoint.get_mut(ep_addr) {
+         Some(ep) => ep,
+         None => return,
+      };
 
-      // TODO: Error if exact read was requested and out_buf.len() smaller than bytes_requested
+      let (ep_out, out) = match (ep.pipe_out.as_mut(), ep.pending_outs.front_mut()) {
+         (Some(ep_out), Some(out)) => (ep_out, out),
+         _ => return,
+      };
 
-      let response = UsbIpResponse {
-         header: UsbIpHeader {
-            command: UsbCmd::Response,
-            seqnum: header.seqnum,
-            devid: 2,
-            direction: Direction::IN,
-            ep: ep_addr as u32,
-         },
-         cmd: UsbIpResponseCmd::Cmd(UsbIpRetSubmit {
-            status: 0,
-            actual_length: out_buf.len() as i32,
-            start_frame: 0,
-            number_of_packets: 0,
-            error_count: 0,
-         }),
-         data: out_buf,
+      while ep_out.data.len() < ep_out.depth {
+         match out.packets.pop_front() {
+            Some(packet) => {
+               ep_out.data.push_back(packet);
+               out.queued += 1;
+            }
+            None => break,
+         }
+      }
+   }
+
+   /// Called, after the device has read a packet of `len` bytes from an OUT pipe.
+   ///
+   /// Completes the OUT URB, once all of its packets were read.
+   pub fn packet_received(&mut self, ep_addr: usize, len: usize) {
+      let ep = match self.endpoint.get_mut(ep_addr) {
+         Some(ep) => ep,
+         None => return,
       };
-      log::debug!("{:?}", response);
 
-      self
-         .handler
-         .connection
-         .as_mut()
-         .unwrap()
-         .write_all(&response.to_vec().unwrap())
-         .unwrap();
+      let out = match ep.pending_outs.front_mut() {
+         Some(out) if out.queued > 0 => out,
+         _ => return,
+      };
+
+      out.queued -= 1;
+      out.actual_length += len;
+
+      if out.queued == 0 && out.packets.is_empty() {
+         let out = ep.pending_outs.pop_front().unwrap();
+         self.outgoing.push_back(UsbIpResponse::ret_submit(
+            &out.header,
+            self.devid,
+            UrbStatus::Ok,
+            out.actual_length,
+            vec![],
+         ));
+      }
+
+      self.try_receive_pending(ep_addr);
    }
 
-   /// Handles an incomming op packet, sends out the corresponding response
-   fn handle_op(&mut self, op: OpRequest) {
-      match op {
-         OpRequest::ListDevices(header) => {
-            let list_response = OpResponse {
-               version: header.version,
-               path: "/sys/devices/pci0000:00/0000:00:01.2/usb1/1-1".to_string(),
-               bus_id: "1-1".to_string(),
-               descriptor: OpDeviceDescriptor {
-                  busnum: 1,
-                  devnum: 2,
-                  speed: DEVICE_SPEED,
-
-                  // These values should be settable via configuration
-                  vendor: 0x1111,
-                  product: 0x1010,
-                  bcd_device: 0,
-                  device_class: 0,
-                  device_subclass: 0,
-                  device_protocol: 0,
-                  configuration_value: 0,
-
-                  // These are fixed for this implementation
-                  num_configurations: 1,
-                  num_interfaces: 1,
-               },
-               cmd: OpResponseCommand::ListDevices(OpInterfaceDescriptor {
-                  // TODO: Make these setable
-                  interface_class: 0,
-                  interface_subclass: 0,
-                  interface_protocol: 0,
-                  padding: 0,
-               }),
-            };
+   /// Completes all the URBs, that are pending on a pipe of an endpoint, with `status`.
+   pub fn fail_pending(&mut self, ep_addr: EndpointAddress, status: UrbStatus) {
+      let ep = match self.endpoint.get_mut(ep_addr.index()) {
+         Some(ep) => ep,
+         None => return,
+      };
 
-            self
-               .handler
-               .connection
-               .as_mut()
-               .unwrap()
-               .write_all(&list_response.to_vec().unwrap())
-               .unwrap();
+      match ep_addr.direction() {
+         UsbDirection::In => {
+            for (header, _, buf) in ep.pending_ins.drain(..) {
+               let response = UsbIpResponse::ret_submit(&header, self.devid, status, buf.len(), buf);
+               self.outgoing.push_back(response);
+            }
          }
-         OpRequest::ConnectDevice(header) => {
-            let list_response = OpResponse {
-               version: header.version,
-               path: "/sys/devices/pci0000:00/0000:00:01.2/usb1/1-1".to_string(),
-               bus_id: "1-1".to_string(),
-               descriptor: OpDeviceDescriptor {
-                  busnum: 1,
-                  devnum: 2,
-                  speed: DEVICE_SPEED,
-
-                  // These values should be settable via configuration
-                  vendor: 0x1111,
-                  product: 0x1010,
-                  bcd_device: 0,
-                  device_class: 0,
-                  device_subclass: 0,
-                  device_protocol: 0,
-                  configuration_value: 0,
-
-                  // These are fixed for this implementation
-                  num_configurations: 1,
-                  num_interfaces: 1,
-               },
-               cmd: OpResponseCommand::ConnectDevice,
-            };
+         UsbDirection::Out => {
+            // The packets, the device has not read yet, are discarded
+            if let Some(ref mut ep_out) = ep.pipe_out {
+               ep_out.data.clear();
+            }
 
-            // Set the inner value to not reset, because we have connected the device
-            log::info!("device is leaving reset state");
-            self.reset = false;
-
-            self
-               .handler
-               .connection
-               .as_mut()
-               .unwrap()
-               .write_all(&list_response.to_vec().unwrap())
-               .unwrap();
+            for out in ep.pending_outs.drain(..) {
+               let response =
+                  UsbIpResponse::ret_submit(&out.header, self.devid, status, out.actual_length, vec![]);
+               self.outgoing.push_back(response);
+            }
          }
       }
    }
 
-   fn handle_usbip_pkg(&mut self, request: UsbIpRequest) {
-      log::debug!("{:?}", request);
+   pub fn handle_usbip_pkg(&mut self, request: UsbIpRequest) -> Result<(), UsbIpError> {
+      debug!("{:?}", request);
+
+      // The host has to resume the bus, before it can transfer anything
+      self.host_resume();
 
       match request.cmd {
          UsbIpRequestCmd::Unlink(unlink) => self.handle_unlink(request.header, unlink),
-         UsbIpRequestCmd::Cmd(cmd) => self.handle_cmd(request.header, cmd, request.data),
+         UsbIpRequestCmd::Cmd(cmd) => {
+            self.handle_cmd(request.header, cmd, request.data, request.iso_packets)?
+         }
       }
+
+      Ok(())
    }
 
    /// Handle a [`UsbIpCmdSubmit`] package
-   fn handle_cmd(&mut self, header: UsbIpHeader, cmd: UsbIpCmdSubmit, data: Vec<u8>) {
+   fn handle_cmd(
+      &mut self,
+      header: UsbIpHeader,
+      cmd: UsbIpCmdSubmit,
+      data: Vec<u8>,
+      iso_packets: Vec<UsbIpIsoPacketDescriptor>,
+   ) -> Result<(), UsbIpError> {
+      if let Err(status) = self.check_submit(&header, &cmd) {
+         self.complete(&header, status);
+         return Ok(());
+      }
+
+      let is_setup = cmd.setup != [0, 0, 0, 0, 0, 0, 0, 0];
+
+      // Endpoint 0 only carries control transfers, which are driven by the control pipe
+      if header.ep == 0 {
+         match is_setup {
+            true => self.handle_control(header, cmd, data),
+            false => {
+               warn!("received urb for endpoint 0 without a setup packet");
+               self.complete(&header, UrbStatus::Stall);
+            }
+         }
+         return Ok(());
+      }
+
       // Get the endpoint
-      let ep = match self.get_endpoint(header.ep as usize) {
-         Ok(ep) => ep,
-         Err(err) => {
-            log::warn!("reveiced message for unimplemented endpoint {:?}", err);
-            return;
+      let ep = match self.endpoint.get_mut(header.ep as usize) {
+         Some(ep) => ep,
+         None => {
+            warn!("received urb for endpoint {}, which does not exist", header.ep);
+            self.complete(&header, UrbStatus::NoEndpoint);
+            return Ok(());
+         }
+      };
+
+      // The URB can only be answered by the pipes, the device has allocated
+      let pipe = match header.direction {
+         Direction::OUT => ep.pipe_out.as_ref(),
+         Direction::IN => ep.pipe_in.as_ref(),
+         _ => return Err(UsbIpError::InvalidDirection(header.direction.bits())),
+      };
+      let ty = match pipe {
+         Some(pipe) if !is_setup || ep.pipe_out.is_some() => pipe.ty,
+         _ => {
+            warn!("received urb for endpoint {}, which is not allocated", header.ep);
+            self.complete(&header, UrbStatus::NoEndpoint);
+            return Ok(());
          }
       };
 
+      // Isochronous transfers are scheduled by frame
+      if ty == EndpointType::Isochronous {
+         self.handle_iso(header, cmd, data, iso_packets);
+         return Ok(());
+      }
+
       // check wether we have a setup packet
       // NOTE: This assumes the control endpoints have no URBs pending
-      if cmd.setup != [0, 0, 0, 0, 0, 0, 0, 0] {
-         ep.get_out().unwrap().data.push_back(cmd.setup.to_vec());
+      if is_setup {
+         // Receiving a SETUP packet clears the halt of a control endpoint
+         for pipe in ep.pipe_in.iter_mut().chain(ep.pipe_out.iter_mut()) {
+            pipe.stalled = false;
+         }
+
+         if let Some(ep_out) = ep.pipe_out.as_mut() {
+            ep_out.data.push_back(cmd.setup.to_vec());
+         }
          ep.setup_flag = true;
       }
 
       match header.direction {
          Direction::OUT => {
-            let ep_out = ep.get_out().unwrap();
+            let ep_out = match ep.pipe_out.as_mut() {
+               Some(ep_out) => ep_out,
+               None => return Ok(()),
+            };
 
-            // pass the data into the correct buffers
-            for chunk in data.chunks(ep_out.max_packet_size as usize) {
-               ep_out.data.push_back(chunk.to_vec());
+            // The device does not accept any data on a halted endpoint
+            if ep_out.stalled {
+               self.complete(&header, UrbStatus::Stall);
+               return Ok(());
             }
 
-            if cmd.transfer_flags.contains(TransferFlags::ZERO_PACKET)
-               && ep_out.ty == EndpointType::Bulk
+            // split the data into packets, the device reads them one by one
+            let max_packet_size = ep_out.max_packet_size as usize;
+            let mut packets: VecDeque<_> = data.chunks(max_packet_size).map(<[u8]>::to_vec).collect();
+
+            // `is_multiple_of` would raise the minimum supported Rust version to 1.87
+            #[allow(clippy::manual_is_multiple_of)]
+            let on_packet_boundary = data.len() % max_packet_size == 0;
+
+            // An empty transfer consists of a single ZLP and a bulk transfer,
+            // that ends on a packet boundary, can be terminated by one on request
+            if packets.is_empty()
+               || (cmd.transfer_flags.contains(TransferFlags::ZERO_PACKET)
+                  && ep_out.ty == EndpointType::Bulk
+                  && on_packet_boundary)
             {
-               ep_out.data.push_back(vec![]);
+               packets.push_back(vec![]);
             }
 
-            self.ack_cmd_out(header.ep, header.seqnum);
+            let ep_addr = header.ep as usize;
+            ep.pending_outs.push_back(OutTransfer {
+               header,
+               packets,
+               queued: 0,
+               actual_length: 0,
+            });
+            self.try_receive_pending(ep_addr);
          }
-         Direction::IN => {
+         _ => {
+            // The device does not send any data on a halted endpoint
+            if matches!(ep.pipe_in, Some(ref ep_in) if ep_in.stalled) {
+               self.complete(&header, UrbStatus::Stall);
+               return Ok(());
+            }
+
             let ep_addr = header.ep;
             ep.pending_ins.push_back((header, cmd, data));
             self.try_send_pending(ep_addr as usize);
          }
-         _ => panic!(),
       }
-   }
 
-   /// Send an acknowledgement after recieving a cmd out package.
-   fn ack_cmd_out(&mut self, ep: u32, seqnum: u32) {
-      let response = UsbIpResponse {
-         header: UsbIpHeader {
-            command: UsbCmd::Response,
-            seqnum,
-            devid: 2,
-            direction: Direction::OUT,
-            ep,
-         },
-         cmd: UsbIpResponseCmd::Cmd(UsbIpRetSubmit {
-            status: 0,
-            actual_length: 0,
-            start_frame: 0,
-            number_of_packets: 0,
-            error_count: 0,
-         }),
-         data: vec![],
-      };
-      log::debug!("{:?}", response);
+      Ok(())
+   }
 
-      self
-         .handler
-         .connection
-         .as_mut()
-         .unwrap()
-         .write_all(&response.to_vec().unwrap())
-         .unwrap();
+   /// Completes a URB without transferring any data.
+   pub fn complete(&mut self, header: &UsbIpHeader, status: UrbStatus) {
+      let response = UsbIpResponse::ret_submit(header, self.devid, status, 0, vec![]);
+      self.outgoing.push_back(response);
    }
 
    /// Handle a received unlink package
    fn handle_unlink(&mut self, header: UsbIpHeader, unlink: UsbIpCmdUnlink) {
-      match self.unlink(unlink.seqnum) {
-         true => (),
+      // Like the Linux stub, we report a successful unlink with -ECONNRESET
+      // and an URB, that has already completed, with 0
+      let status = match self.unlink(unlink.seqnum) {
+         true => UrbStatus::Unlinked,
          false => {
-            log::warn!(
+            warn!(
                "received request to remove urb {} that does not exists",
                unlink.seqnum
             );
+            UrbStatus::Ok
          }
-      }
-
-      self.ack_unlink(header.ep, header.seqnum);
-   }
-
-   /// Send an acknowledgement after recieving an unlink package.
-   fn ack_unlink(&mut self, ep: u32, seqnum: u32) {
-      let response = UsbIpResponse {
-         header: UsbIpHeader {
-            command: UsbCmd::UnlinkResponse,
-            seqnum,
-            devid: 2,
-            direction: Direction::OUT,
-            ep,
-         },
-         cmd: UsbIpResponseCmd::Unlink(UsbIpRetUnlink { status: 0 }),
-         data: vec![],
       };
-      log::debug!("{:?}", response);
 
-      self
-         .handler
-         .connection
-         .as_mut()
-         .unwrap()
-         .write_all(&response.to_vec().unwrap())
-         .unwrap();
+      let response = UsbIpResponse::ret_unlink(&header, self.devid, status);
+      self.outgoing.push_back(response);
    }
 }
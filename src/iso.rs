@@ -0,0 +1,242 @@
+//! The scheduling of isochronous transfers.
+//!
+//! Isochronous endpoints transfer exactly one packet per (micro)frame of their interval,
+//! they neither retry nor handshake. The bus emulates a frame on every poll of the device,
+//! so the device sees its isochronous endpoints being serviced at a steady rate.
+
+use crate::{
+   cmd::{Direction, TransferFlags, UsbIpHeader, UsbIpIsoPacketDescriptor},
+   request::UsbIpCmdSubmit,
+   response::UsbIpResponse,
+   status::UrbStatus,
+   Pipe, UsbIpBusInner,
+};
+use alloc::{collections::VecDeque, vec::Vec};
+
+/// An isochronous URB, that waits for its frames to come.
+#[derive(Debug, Clone)]
+pub struct IsoTransfer {
+   header: UsbIpHeader,
+   start_frame: u32,
+   interval: u32,
+   packets: Vec<UsbIpIsoPacketDescriptor>,
+   /// The data to send for OUT transfers or the received packets for IN transfers
+   data: Vec<u8>,
+   /// The index of the next packet to transfer
+   next: usize,
+}
+
+impl IsoTransfer {
+   /// Returns the frame, the next packet is scheduled for.
+   fn next_frame(&self) -> u32 {
+      self
+         .start_frame
+         .wrapping_add((self.next as u32).wrapping_mul(self.interval))
+   }
+
+   /// Returns the sequence number of the URB.
+   pub fn seqnum(&self) -> u32 {
+      self.header.seqnum
+   }
+
+   /// Returns the number of bytes, the URB transfers at most.
+   pub fn length(&self) -> usize {
+      let requested: usize = self.packets.iter().map(|packet| packet.length as usize).sum();
+      usize::max(requested, self.data.len())
+   }
+
+   fn is_done(&self) -> bool {
+      self.next == self.packets.len()
+   }
+
+   /// Transfers the next packet between the URB and the pipe.
+   fn transfer(&mut self, pipe: &mut VecDeque<Vec<u8>>) {
+      let packet = &mut self.packets[self.next];
+      packet.actual_length = 0;
+      packet.status = UrbStatus::Ok.to_errno();
+
+      match self.header.direction {
+         Direction::IN => {
+            // The device sends whatever it has prepared, nothing is a valid answer as well
+            if let Some(data) = pipe.pop_front() {
+               let len = usize::min(data.len(), packet.length as usize);
+               if len < data.len() {
+                  packet.status = UrbStatus::Babble.to_errno();
+               }
+               packet.actual_length = len as u32;
+               self.data.extend_from_slice(&data[..len]);
+            }
+         }
+         _ => {
+            let start = usize::min(packet.offset as usize, self.data.len());
+            let end = usize::min(start + packet.length as usize, self.data.len());
+            pipe.push_back(self.data[start..end].to_vec());
+            packet.actual_length = (end - start) as u32;
+         }
+      }
+
+      self.next += 1;
+   }
+
+   /// Creates the response, that completes the URB with `status`.
+   fn into_response(self, status: UrbStatus, devid: u32) -> UsbIpResponse {
+      let data = match self.header.direction {
+         Direction::IN => self.data,
+         _ => vec![],
+      };
+
+      UsbIpResponse::ret_submit_iso(
+         &self.header,
+         devid,
+         status,
+         self.start_frame,
+         self.packets,
+         data,
+      )
+   }
+}
+
+/// Checks, whether `frame` lies before `now`, taking the wrap around of the frame counter into account.
+fn is_before(frame: u32, now: u32) -> bool {
+   (now.wrapping_sub(frame) as i32) > 0
+}
+
+impl Pipe {
+   /// Transfers the packet, that is scheduled for `frame`, and completes the finished URBs.
+   ///
+   /// # Returns
+   /// `true` if the host has taken a packet from the pipe
+   fn service_iso(&mut self, frame: u32, devid: u32, outgoing: &mut VecDeque<UsbIpResponse>) -> bool {
+      let mut taken = false;
+
+      while let Some(iso) = self.pending_isos.front_mut() {
+         // The packets, whose frames have passed, are lost
+         while !iso.is_done() && is_before(iso.next_frame(), frame) {
+            let packet = &mut iso.packets[iso.next];
+            packet.actual_length = 0;
+            packet.status = UrbStatus::Missed.to_errno();
+            iso.next += 1;
+         }
+
+         if !iso.is_done() && iso.next_frame() == frame {
+            taken = !self.data.is_empty();
+            iso.transfer(&mut self.data);
+         }
+
+         if !iso.is_done() {
+            break;
+         }
+
+         let iso = self.pending_isos.pop_front().unwrap();
+         outgoing.push_back(iso.into_response(UrbStatus::Ok, devid));
+      }
+
+      taken
+   }
+
+   /// Completes all the isochronous URBs of the pipe right away with `status`.
+   ///
+   /// The packets, that were not transferred yet, are reported with `status` as well.
+   pub fn abort_isos(
+      &mut self,
+      status: UrbStatus,
+      devid: u32,
+      outgoing: &mut VecDeque<UsbIpResponse>,
+   ) {
+      for mut iso in self.pending_isos.drain(..) {
+         for packet in &mut iso.packets[iso.next..] {
+            packet.actual_length = 0;
+            packet.status = status.to_errno();
+         }
+         iso.next = iso.packets.len();
+         outgoing.push_back(iso.into_response(status, devid));
+      }
+      self.next_iso_frame = None;
+   }
+
+   /// Removes the isochronous URB with the sequence number `seqnum`.
+   ///
+   /// # Returns
+   /// - `true` if the URB was removed
+   /// - `false` if it was not found
+   pub fn unlink_iso(&mut self, seqnum: u32) -> bool {
+      let old_len = self.pending_isos.len();
+      self.pending_isos.retain(|iso| iso.header.seqnum != seqnum);
+      old_len != self.pending_isos.len()
+   }
+}
+
+impl UsbIpBusInner {
+   /// Schedules an isochronous URB on its endpoint.
+   ///
+   /// With `ISO_ASAP`, the URB starts right after the URBs already scheduled on the endpoint,
+   /// or in the next frame. Otherwise it starts at the frame requested by the host, which is
+   /// taken as a frame of the bus. The packets, whose frames have already passed, are missed.
+   pub fn handle_iso(
+      &mut self,
+      header: UsbIpHeader,
+      cmd: UsbIpCmdSubmit,
+      data: Vec<u8>,
+      packets: Vec<UsbIpIsoPacketDescriptor>,
+   ) {
+      let next_frame = self.frame.wrapping_add(1);
+      let ep = &mut self.endpoint[header.ep as usize];
+
+      let pipe = match header.direction {
+         Direction::IN => ep.pipe_in.as_mut(),
+         _ => ep.pipe_out.as_mut(),
+      };
+      let pipe = match pipe {
+         Some(pipe) => pipe,
+         None => return,
+      };
+
+      // The interval of the endpoint is an exponent, but the host may choose its own
+      let interval = match cmd.interval {
+         interval if interval > 0 => interval as u32,
+         _ => 1 << (pipe.interval.clamp(1, 16) - 1),
+      };
+
+      let start_frame = if cmd.transfer_flags.contains(TransferFlags::ISO_ASAP) {
+         match pipe.next_iso_frame {
+            Some(frame) if !is_before(frame, next_frame) => frame,
+            _ => next_frame,
+         }
+      } else {
+         cmd.start_frame as u32
+      };
+      let length = (packets.len() as u32).wrapping_mul(interval);
+      pipe.next_iso_frame = Some(start_frame.wrapping_add(length));
+
+      let data = match header.direction {
+         Direction::IN => vec![],
+         _ => data,
+      };
+
+      pipe.pending_isos.push_back(IsoTransfer {
+         header,
+         start_frame,
+         interval,
+         packets,
+         data,
+         next: 0,
+      });
+   }
+
+   /// Advances the bus by one frame and transfers the isochronous packets scheduled for it.
+   pub fn next_frame(&mut self) {
+      self.frame = self.frame.wrapping_add(1);
+
+      for ep in self.endpoint.iter_mut() {
+         if let Some(ref mut pipe) = ep.pipe_in {
+            // The device can prepare the next packet, once the host has taken the last one
+            if pipe.service_iso(self.frame, self.devid, &mut self.outgoing) {
+               ep.in_complete_flag = true;
+            }
+         }
+         if let Some(ref mut pipe) = ep.pipe_out {
+            pipe.service_iso(self.frame, self.devid, &mut self.outgoing);
+         }
+      }
+   }
+}
@@ -1,15 +1,58 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[macro_use]
+extern crate alloc;
+
+#[macro_use]
+mod logging;
+
+pub(crate) mod builder;
 pub(crate) mod cmd;
+pub(crate) mod control;
 pub(crate) mod debug;
+pub(crate) mod decoder;
+pub(crate) mod descriptor;
+#[cfg(feature = "std")]
+pub(crate) mod export;
 pub(crate) mod handler;
+pub(crate) mod iso;
+pub(crate) mod limits;
+#[cfg(feature = "std")]
+pub(crate) mod listener;
 pub(crate) mod op;
+pub mod protocol;
 pub(crate) mod request;
 pub(crate) mod response;
-
-use crate::{cmd::UsbIpHeader, handler::SocketHandler, request::UsbIpCmdSubmit};
-use std::{
-    collections::VecDeque,
-    sync::{Arc, Mutex, MutexGuard},
+pub(crate) mod server;
+pub(crate) mod session;
+#[cfg(feature = "smoltcp")]
+pub(crate) mod smoltcp_transport;
+pub(crate) mod speed;
+pub(crate) mod status;
+#[cfg(feature = "std")]
+pub(crate) mod stdio;
+pub(crate) mod sync;
+pub(crate) mod tweak;
+
+#[cfg(all(test, feature = "std"))]
+mod tests;
+
+use crate::{
+    cmd::UsbIpHeader,
+    control::ControlTransfer,
+    descriptor::Enumeration,
+    handler::{ConnectionState, OutTransfer},
+    iso::IsoTransfer,
+    limits::Limits,
+    request::UsbIpCmdSubmit,
+    response::UsbIpResponse,
+    status::UrbStatus,
+    sync::{Mutex, MutexGuard},
 };
+use alloc::{collections::VecDeque, string::String, sync::Arc, vec::Vec};
+use core::net::SocketAddr;
+#[cfg(feature = "std")]
+use std::io::{Error as IoError, ErrorKind};
 use usb_device::{
     Result as UsbResult, UsbDirection, UsbError,
     {
@@ -18,8 +61,19 @@ use usb_device::{
     },
 };
 
+pub use crate::{
+    builder::{UsbIpBusBuilder, USBIP_PORT},
+    server::UsbIpServer,
+    session::UsbIpSession,
+    speed::UsbSpeed,
+};
+
+#[cfg(feature = "smoltcp")]
+pub use crate::smoltcp_transport::SmoltcpTransport;
+
 #[derive(Debug, Clone)]
 /// The error type, used by this crate.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum UsbIpError {
     /// The connection closed unexpectedly.
     ConnectionClosed,
@@ -28,25 +82,65 @@ pub enum UsbIpError {
     PkgTooShort(usize),
 
     /// A received packet contained a command, that is unknown to the USBIP specification.
-    InvalidCommand(u16),
+    InvalidCommand(u32),
+
+    /// A received command had a direction, that is neither IN nor OUT.
+    InvalidDirection(u32),
+
+    /// A received command addressed a device, that is not imported over this connection.
+    UnknownDevice(u32),
 
     /// A received packet had a status field set to an unknown status value.
     StatusNotOk(u32),
+
+    /// A received import request contained a bus id, that is not valid UTF-8.
+    InvalidBusId,
+
+    /// The peer speaks a version of the USBIP protocol, that is not supported.
+    UnsupportedVersion(u16),
+
+    /// A received command had a length, that is negative or exceeds the limits of the bus.
+    InvalidLength(i32),
+
+    /// The host does not read the responses, more than the given number of bytes wait to be sent.
+    SendBufferFull(usize),
+
+    /// An I/O error occured on the underlying socket.
+    #[cfg(feature = "std")]
+    Io(#[cfg_attr(feature = "defmt", defmt(Debug2Format))] ErrorKind),
 }
 
-impl std::fmt::Display for UsbIpError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for UsbIpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Self::ConnectionClosed => write!(f, "connection no longer exsists"),
             Self::PkgTooShort(len) => write!(f, "packet of length {} is to short to parse", len),
             Self::InvalidCommand(cmd) => write!(f, "unknown command: {}", cmd),
+            Self::InvalidDirection(dir) => write!(f, "unknown direction: {}", dir),
+            Self::UnknownDevice(devid) => write!(f, "device {:#x} is not imported", devid),
             Self::StatusNotOk(status) => write!(f, "received invalid status: {}", status),
+            Self::InvalidBusId => write!(f, "bus id is not valid utf-8"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version: {:#06x}", version)
+            }
+            Self::InvalidLength(len) => write!(f, "invalid length: {}", len),
+            Self::SendBufferFull(len) => write!(f, "{} bytes wait to be sent to the host", len),
+            #[cfg(feature = "std")]
+            Self::Io(kind) => write!(f, "i/o error: {}", kind),
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for UsbIpError {}
 
+#[cfg(feature = "std")]
+impl From<IoError> for UsbIpError {
+    fn from(err: IoError) -> Self {
+        Self::Io(err.kind())
+    }
+}
+
 const NUM_ENDPOINTS: usize = 8;
 
 #[derive(Debug, Clone)]
@@ -55,6 +149,10 @@ pub(crate) struct Pipe {
     pub ty: EndpointType,
     pub max_packet_size: u16,
     pub interval: u8,
+    pub depth: usize,
+    pub stalled: bool,
+    pub pending_isos: VecDeque<IsoTransfer>,
+    pub next_iso_frame: Option<u32>,
 }
 
 impl Pipe {
@@ -77,30 +175,31 @@ impl Pipe {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 struct Endpoint {
     pub(crate) pipe_in: Option<Pipe>,
     pub(crate) pipe_out: Option<Pipe>,
     pub(crate) pending_ins: VecDeque<(UsbIpHeader, UsbIpCmdSubmit, Vec<u8>)>,
-    pub(crate) stalled: bool,
+    pub(crate) pending_outs: VecDeque<OutTransfer>,
     pub(crate) setup_flag: bool,
     pub(crate) in_complete_flag: bool,
 }
 
-impl Default for Endpoint {
-    fn default() -> Self {
-        Self {
-            pipe_in: None,
-            pipe_out: None,
-            pending_ins: VecDeque::new(),
-            stalled: true,
-            setup_flag: false,
-            in_complete_flag: false,
+impl Endpoint {
+    /// Clears all the queued data, but keeps the pipes allocated.
+    ///
+    /// Like on real hardware, a reset also clears the halt condition.
+    /// The pending URBs must be completed by the bus beforehand.
+    fn reset(&mut self) {
+        for pipe in self.pipe_in.iter_mut().chain(self.pipe_out.iter_mut()) {
+            pipe.data.clear();
+            pipe.stalled = false;
+            pipe.next_iso_frame = None;
         }
+        self.setup_flag = false;
+        self.in_complete_flag = false;
     }
-}
 
-impl Endpoint {
     /// Returns the input pipe of this endpoint
     fn get_in(&mut self) -> UsbResult<&mut Pipe> {
         self.pipe_in.as_mut().ok_or(UsbError::InvalidEndpoint)
@@ -111,14 +210,6 @@ impl Endpoint {
         self.pipe_out.as_mut().ok_or(UsbError::InvalidEndpoint)
     }
 
-    /// Checks, whether the input pipe is ready to send data back to the host.
-    fn is_rts(&self) -> bool {
-        match self.pipe_in {
-            None => false,
-            Some(ref pipe) => pipe.is_rts(),
-        }
-    }
-
     /// Processes an unlink and removes the pending packet on this endpoint.
     ///
     /// # Returns
@@ -134,38 +225,271 @@ impl Endpoint {
             .filter(|(header, _, _)| header.seqnum != seqnum)
             .collect();
 
+        let mut unlinked = old_len != self.pending_ins.len();
+        for pipe in self.pipe_in.iter_mut().chain(self.pipe_out.iter_mut()) {
+            unlinked |= pipe.unlink_iso(seqnum);
+        }
+
+        if let Some(index) = self
+            .pending_outs
+            .iter()
+            .position(|out| out.header.seqnum == seqnum)
+        {
+            // The packets, the device has not read yet, are taken back
+            let out = self.pending_outs.remove(index).unwrap();
+            if let Some(ref mut pipe) = self.pipe_out {
+                pipe.data
+                    .truncate(pipe.data.len().saturating_sub(out.queued));
+            }
+            unlinked = true;
+        }
+
         // If the length is the same as before, we have not changed anything
         // and return false
-        old_len != self.pending_ins.len()
+        unlinked
     }
 }
 
 #[derive(Debug)]
 pub(crate) struct UsbIpBusInner {
-    pub handler: SocketHandler,
+    pub devid: u32,
+    pub bus_id: String,
+    pub outgoing: VecDeque<UsbIpResponse>,
     pub endpoint: [Endpoint; NUM_ENDPOINTS],
+    pub control: Option<ControlTransfer>,
+    pub enumeration: Enumeration,
+    pub speed: UsbSpeed,
+    pub pipe_depth: usize,
+    pub limits: Limits,
+    pub frame: u32,
     pub device_address: u8,
+    pub connection: ConnectionState,
     pub reset: bool,
     pub suspended: bool,
+    pub host_suspended: bool,
+    pub remote_wakeup_enabled: bool,
+    pub protocol_version: Option<u16>,
+    pub plugged: bool,
+    pub unplug_after: Option<usize>,
 }
 
 impl UsbIpBusInner {
     /// Creates a new UsbIpBusInner
-    fn new() -> Self {
+    fn new(devid: u32, bus_id: String, speed: UsbSpeed, pipe_depth: usize, limits: Limits) -> Self {
         Self {
-            handler: SocketHandler::new(),
+            devid,
+            bus_id,
+            outgoing: VecDeque::new(),
             endpoint: <[Endpoint; NUM_ENDPOINTS]>::default(),
+            control: None,
+            enumeration: Enumeration::default(),
+            speed,
+            pipe_depth,
+            limits,
+            frame: 0,
             device_address: 0,
-            reset: true,
+            connection: ConnectionState::Listening,
+            reset: false,
             suspended: false,
+            host_suspended: false,
+            remote_wakeup_enabled: false,
+            protocol_version: None,
+            plugged: true,
+            unplug_after: None,
         }
     }
 
-    /// Resets the handler to the state, in which it acts like it is new
+    /// Performs a USB reset.
+    ///
+    /// This clears all the queued data, but keeps the endpoint allocations,
+    /// just like the reset of a real USB peripheral.
     fn reset(&mut self) {
-        self.endpoint = <[Endpoint; NUM_ENDPOINTS]>::default();
-        self.reset = true;
+        // Like on a real host controller, the transfers that were in flight fail,
+        // the responses that are already queued are still sent to the host
+        self.fail_all(UrbStatus::Unlinked);
+        for ep in self.endpoint.iter_mut() {
+            ep.reset();
+        }
+        // The queued stages of an internal transfer are gone, so it must start over
+        self.enumeration.abort();
+        self.device_address = 0;
+        self.enumeration.set_configuration(0);
+        self.reset = false;
         self.suspended = false;
+        self.host_suspended = false;
+        self.remote_wakeup_enabled = false;
+
+        // Once the device has processed the reset, it is ready to be imported again
+        if self.connection == ConnectionState::Closing {
+            self.connection = ConnectionState::Listening;
+        }
+    }
+
+    /// Called, when the device was imported by a host, that speaks `version` of the protocol.
+    pub fn attach(&mut self, version: u16) {
+        // The host takes over, so an internal transfer would get in the way
+        self.enumeration.abort();
+        self.protocol_version = Some(version);
+
+        // The device sees a freshly reset bus, when being attached to the host
+        self.connection = ConnectionState::Imported;
+        self.reset = true;
+    }
+
+    /// Called, when the connection to the host was closed.
+    pub fn detach(&mut self) {
+        // There is no one left to answer pending URBs
+        self.drop_pending();
+        self.outgoing.clear();
+
+        self.protocol_version = None;
+        self.connection = ConnectionState::Closing;
+        self.reset = true;
+    }
+
+    /// Drops all the URBs, that are still pending, without answering them.
+    fn drop_pending(&mut self) {
+        for ep in self.endpoint.iter_mut() {
+            ep.pending_ins.clear();
+            ep.pending_outs.clear();
+            for pipe in ep.pipe_in.iter_mut().chain(ep.pipe_out.iter_mut()) {
+                pipe.pending_isos.clear();
+            }
+        }
+        self.control = None;
+    }
+
+    /// Simulates a disconnect, after which the device comes back as a new device.
+    ///
+    /// The import is closed, so the host sees the device go away, and the descriptors
+    /// are learned again, since the device might have changed them.
+    fn force_reset(&mut self) {
+        info!("device forces a reset");
+
+        // The URBs, that already completed, are still sent to the host,
+        // the pending ones go away together with the connection
+        if self.connection == ConnectionState::Imported {
+            self.drop_pending();
+            self.connection = ConnectionState::Closing;
+        }
+        self.enumeration = Enumeration::default();
+        self.reset = true;
+    }
+
+    /// Completes all the URBs, that are still pending, with `status`.
+    fn fail_all(&mut self, status: UrbStatus) {
+        self.finish_control(status);
+
+        for index in 0..NUM_ENDPOINTS {
+            for &direction in [UsbDirection::In, UsbDirection::Out].iter() {
+                self.fail_pending(EndpointAddress::from_parts(index, direction), status);
+            }
+
+            let ep = &mut self.endpoint[index];
+            for pipe in ep.pipe_in.iter_mut().chain(ep.pipe_out.iter_mut()) {
+                pipe.abort_isos(status, self.devid, &mut self.outgoing);
+            }
+        }
+    }
+
+    /// Pulls the device from the bus.
+    ///
+    /// The pending URBs fail, the import is closed and the device is no longer exported,
+    /// until it is plugged in again.
+    fn unplug(&mut self) {
+        if !self.plugged {
+            return;
+        }
+        info!("device is unplugged");
+
+        // The URBs fail just like on a real host controller, the connection is closed after they were sent
+        if self.connection == ConnectionState::Imported {
+            self.fail_all(UrbStatus::Shutdown);
+            self.connection = ConnectionState::Closing;
+        }
+
+        self.plugged = false;
+        self.unplug_after = None;
+        self.reset = true;
+    }
+
+    /// Plugs the device back into the bus, where it is exported again.
+    fn plug(&mut self) {
+        if self.plugged {
+            return;
+        }
+        info!("device is plugged in");
+
+        // The device sees a reset, when it is connected to the bus
+        self.plugged = true;
+        self.reset = true;
+    }
+
+    /// Counts a packet, that was transferred on one of the endpoints, towards an armed unplug.
+    fn count_packet(&mut self) {
+        match self.unplug_after {
+            Some(0) | Some(1) => self.unplug(),
+            Some(packets) => self.unplug_after = Some(packets - 1),
+            None => (),
+        }
+    }
+
+    /// Suspends the bus, like a host does after the bus was idle for 3 ms.
+    fn host_suspend(&mut self) {
+        info!("host suspends the bus");
+        self.host_suspended = true;
+    }
+
+    /// Resumes the suspended bus on behalf of the host.
+    pub fn host_resume(&mut self) {
+        if self.host_suspended {
+            info!("host resumes the bus");
+        }
+        self.host_suspended = false;
+    }
+
+    /// Signals a remote wakeup to the host, which resumes the bus in response.
+    fn remote_wakeup(&mut self) -> UsbResult<()> {
+        if !self.host_suspended {
+            warn!("remote wakeup while the bus is not suspended");
+            return Err(UsbError::InvalidState);
+        }
+
+        if !self.remote_wakeup_enabled {
+            warn!("remote wakeup was not enabled by the host");
+            return Err(UsbError::InvalidState);
+        }
+
+        info!("device wakes up the host");
+        self.host_resume();
+        Ok(())
+    }
+
+    /// Drives the internal enumeration, which is used to learn the descriptors of the device
+    /// while no host is attached.
+    ///
+    /// # Returns
+    /// - `true` if an internal transfer is in flight
+    /// - `false` if there is nothing to enumerate
+    fn enumerate(&mut self) -> bool {
+        // We can only enumerate once the control endpoint was allocated
+        let ep0 = &mut self.endpoint[0];
+        if ep0.pipe_in.is_none() || ep0.pipe_out.is_none() {
+            return false;
+        }
+
+        // The status stage of the last request must be read first
+        let pipe = ep0.get_out().unwrap();
+        if !pipe.data.is_empty() {
+            return true;
+        }
+
+        if let Some(setup) = self.enumeration.next_request() {
+            pipe.data.push_back(setup.to_vec());
+            ep0.setup_flag = true;
+        }
+
+        self.enumeration.is_active()
     }
 
     /// Returns the first enpoint, that is not already initialized or `None`,
@@ -197,7 +521,7 @@ impl UsbIpBusInner {
         //let ep_addr = ep.index();
 
         if ep >= NUM_ENDPOINTS {
-            log::error!("attempt to access out-of-bounds endpoint {:?}", ep);
+            error!("attempt to access out-of-bounds endpoint {:?}", ep);
             return Err(UsbError::InvalidEndpoint);
         }
 
@@ -210,8 +534,14 @@ impl UsbIpBusInner {
     /// - `true` if pending urb was removed
     /// - `false` if it was not found
     fn unlink(&mut self, seqnum: u32) -> bool {
+        if self.unlink_control(seqnum) {
+            return true;
+        }
+
         for i in 0..NUM_ENDPOINTS {
             if self.endpoint[i].unlink(seqnum) {
+                // The next OUT URB can take the place of the unlinked one
+                self.try_receive_pending(i);
                 return true;
             }
         }
@@ -223,26 +553,146 @@ impl UsbIpBusInner {
 #[derive(Debug, Clone)]
 /// An implementation of [`UsbBus`](https://docs.rs/usb-device/0.2.7/usb_device/bus/trait.UsbBus.html),
 /// based on the Linux USBIP protocol.
-pub struct UsbIpBus(Arc<Mutex<UsbIpBusInner>>);
+pub struct UsbIpBus {
+    inner: Arc<Mutex<UsbIpBusInner>>,
+    server: UsbIpServer,
+}
 
 impl UsbIpBus {
-    /// Create a new [`UsbIpBus`].
+    /// Create a new [`UsbIpBus`], listening on `127.0.0.1:3240`.
     ///
     /// # Note
-    /// There can only ever be one bus [`UsbIpBus`] device on the system, since
-    /// it is blocking port 3240.
+    /// There can only ever be one bus [`UsbIpBus`] created this way on the system,
+    /// since it is blocking port 3240.
+    /// Use [`UsbIpBusBuilder`] to listen on a different address or port.
     ///
     /// # Panics
     /// If port 3240 is already in use.
+    #[cfg(feature = "std")]
     pub fn new() -> Self {
-        Self(Arc::new(Mutex::new(UsbIpBusInner::new())))
+        UsbIpBusBuilder::new()
+            .build()
+            .expect("failed to bind the usbip socket")
+    }
+
+    pub(crate) fn from_parts(inner: Arc<Mutex<UsbIpBusInner>>, server: UsbIpServer) -> Self {
+        Self { inner, server }
+    }
+
+    /// Returns the address, this bus is listening on.
+    ///
+    /// This is useful to find out the actual port, if the bus was built
+    /// with port `0`. Returns `None`, if the server of the bus does not listen on TCP.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.local_addr()
+    }
+
+    /// Returns the bus id, under which this bus is exported, e.g. `1-1`.
+    pub fn bus_id(&self) -> String {
+        self.lock().bus_id.clone()
+    }
+
+    /// Returns the server, this bus is exported on.
+    pub fn server(&self) -> &UsbIpServer {
+        &self.server
     }
 
-    fn lock(&self) -> MutexGuard<UsbIpBusInner> {
-        self.0.lock().unwrap()
+    /// Suspends the bus, as a host would after 3 ms without traffic.
+    ///
+    /// The device sees [`PollResult::Suspend`] and no more frames until the bus is resumed,
+    /// either by [`UsbIpBus::host_resume`], by the host submitting a URB or by a remote wakeup.
+    ///
+    /// As USBIP does not carry the suspend state, this is meant to exercise the suspend
+    /// and resume paths of the device in tests.
+    pub fn host_suspend(&self) {
+        self.lock().host_suspend();
+    }
+
+    /// Resumes the bus, after it was suspended by [`UsbIpBus::host_suspend`].
+    ///
+    /// The device sees [`PollResult::Resume`] on its next poll.
+    pub fn host_resume(&self) {
+        self.lock().host_resume();
+    }
+
+    /// Unplugs the device, as if its cable was pulled.
+    ///
+    /// All the pending URBs fail with `-ESHUTDOWN`, the connection to the host is closed,
+    /// so the host sees the device disappear, and the device is reset.
+    /// Until it is plugged in again by [`UsbIpBus::plug`], the device is not exported.
+    pub fn unplug(&self) {
+        self.lock().unplug();
+    }
+
+    /// Unplugs the device, once `packets` more packets were transferred on its endpoints.
+    ///
+    /// This allows pulling the device in the middle of a transfer, to test how the host
+    /// handles a surprise removal. Endpoint 0 does not count.
+    pub fn unplug_after(&self, packets: usize) {
+        let mut inner = self.lock();
+
+        if packets == 0 {
+            inner.unplug();
+        } else if inner.plugged {
+            inner.unplug_after = Some(packets);
+        }
+    }
+
+    /// Plugs the device back in, after it was unplugged by [`UsbIpBus::unplug`].
+    ///
+    /// The device is reset and is exported again under its old bus id.
+    pub fn plug(&self) {
+        self.lock().plug();
+    }
+
+    /// Returns the version of the USBIP protocol, the host, that imported the device, speaks.
+    ///
+    /// Returns `None`, while the device is not imported.
+    pub fn protocol_version(&self) -> Option<u16> {
+        self.lock().protocol_version
+    }
+
+    /// Returns `false`, while the device is unplugged.
+    pub fn is_plugged(&self) -> bool {
+        self.lock().plugged
+    }
+
+    /// Sets the number of packets, the OUT endpoint `ep_addr` can buffer.
+    ///
+    /// This overrides the depth, that was set by [`UsbIpBusBuilder::pipe_depth`],
+    /// for a single endpoint. The depth is at least one packet.
+    ///
+    /// # Errors
+    /// [`UsbError::InvalidEndpoint`] if `ep_addr` is not an OUT endpoint, that was allocated.
+    pub fn set_pipe_depth(&self, ep_addr: EndpointAddress, depth: usize) -> UsbResult<()> {
+        if ep_addr.direction() != UsbDirection::Out {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        let mut inner = self.lock();
+        let ep = inner
+            .endpoint
+            .get_mut(ep_addr.index())
+            .ok_or(UsbError::InvalidEndpoint)?;
+        ep.get_out()?.depth = depth.max(1);
+        Ok(())
+    }
+
+    /// Wakes up the suspended host.
+    ///
+    /// # Errors
+    /// [`UsbError::InvalidState`] if the bus is not suspended or the host has not enabled
+    /// the remote wakeup feature of the device.
+    pub fn remote_wakeup(&self) -> UsbResult<()> {
+        self.lock().remote_wakeup()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, UsbIpBusInner> {
+        self.inner.lock()
     }
 }
 
+#[cfg(feature = "std")]
 impl Default for UsbIpBus {
     fn default() -> Self {
         Self::new()
@@ -260,6 +710,11 @@ impl UsbBus for UsbIpBus {
     ) -> UsbResult<EndpointAddress> {
         let mut inner = self.lock();
 
+        // Check the endpoint against the rules a host would apply
+        inner
+            .speed
+            .validate_endpoint(ep_type, max_packet_size, interval)?;
+
         // Get the endpoint to initialize
         let endpoint_index = match ep_addr {
             Some(addr) => {
@@ -274,7 +729,8 @@ impl UsbBus for UsbIpBus {
                 .ok_or(UsbError::EndpointMemoryOverflow)?,
         };
 
-        let endpoint = &mut inner.endpoint[endpoint_index as usize];
+        let depth = inner.pipe_depth;
+        let endpoint = &mut inner.endpoint[endpoint_index];
 
         // check endpoint allocation here
         let maybe_pipe = match ep_dir {
@@ -296,84 +752,95 @@ impl UsbBus for UsbIpBus {
             ty: ep_type,
             max_packet_size,
             interval,
+            depth,
+            stalled: false,
+            pending_isos: VecDeque::new(),
+            next_iso_frame: None,
         };
         match ep_dir {
             UsbDirection::In => endpoint.pipe_in = Some(pipe),
             UsbDirection::Out => endpoint.pipe_out = Some(pipe),
         }
 
-        log::debug!(
-            "initialized new endpoint {:?} as address {:?}",
-            endpoint,
-            endpoint_index
+        let ep_addr = EndpointAddress::from_parts(endpoint_index, ep_dir);
+        debug!(
+            "initialized new {:?} endpoint as address {:?} with packets of {} bytes",
+            ep_type,
+            ep_addr,
+            max_packet_size
         );
 
-        Ok(EndpointAddress::from_parts(endpoint_index as usize, ep_dir))
+        Ok(ep_addr)
     }
 
     fn enable(&mut self) {
-        log::info!("usb device is being enabled");
+        info!("usb device is being enabled");
     }
 
     fn reset(&self) {
         let mut inner = self.lock();
 
-        // Skip if we are already in reset state
-        if inner.reset {
-            return;
-        }
-
         inner.reset();
-        log::debug!("usb device is being reset");
+        debug!("usb device is being reset");
     }
 
     fn set_device_address(&self, addr: u8) {
         let mut inner = self.lock();
 
-        log::info!("setting device address to {}", addr);
+        info!("setting device address to {}", addr);
         inner.device_address = addr;
     }
 
     fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> UsbResult<usize> {
-        log::trace!("write request at endpoint {}", ep_addr.index());
+        trace!("write request at endpoint {}", ep_addr.index());
         let mut inner = self.lock();
 
-        // We can not write anything, as long as there is no connection
-        if !inner.handler.is_connected() {
-            return Err(UsbError::WouldBlock);
+        // Answers to the internal enumeration are not sent to the host
+        if ep_addr.index() == 0 && inner.enumeration.is_active() {
+            let ep = inner.get_endpoint(0)?;
+            ep.in_complete_flag = true;
+            let max_packet_size = ep.get_in()?.max_packet_size;
+
+            // Complete the transfer with the status stage, like a host would
+            if inner.enumeration.push_data(buf, max_packet_size) {
+                inner.endpoint[0].get_out()?.data.push_back(vec![]);
+            }
+            return Ok(buf.len());
         }
 
-        // Get the endpoint
-        let ep = inner.get_endpoint(ep_addr.index())?;
+        // We can not write anything, as long as the device is not imported
+        if inner.connection != ConnectionState::Imported || inner.reset {
+            return Err(UsbError::WouldBlock);
+        }
 
-        // NOTE: This is a hack to allow driving the setup packets in a transactional way
-        // while the rest of the packets use packet logic
+        // The control pipe passes the packet on to the control transfer in flight
         if ep_addr.index() == 0 {
-            ep.in_complete_flag = true;
+            return match inner.control_write(buf) {
+                true => Ok(buf.len()),
+                false => Err(UsbError::WouldBlock),
+            };
         }
 
+        // Get the endpoint
+        let ep = inner.get_endpoint(ep_addr.index())?;
         let pipe = ep.get_in()?;
 
         // If there is data waiting in the output buffer, we need to wait
         if pipe.is_rts() {
-            if ep_addr.index() == 0 {
-                ep.in_complete_flag = false;
-            }
             return Err(UsbError::WouldBlock);
         }
 
         pipe.data.push_back(buf.to_vec());
 
         // we attempt to service in packets, if we have them available
-        if pipe.is_rts() {
-            inner.try_send_pending(ep_addr.index());
-        }
+        inner.try_send_pending(ep_addr.index());
+        inner.count_packet();
 
         Ok(buf.len())
     }
 
     fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> UsbResult<usize> {
-        log::trace!("read request at endpoint {}", ep_addr.index());
+        trace!("read request at endpoint {}", ep_addr.index());
         let mut inner = self.lock();
         let ep = inner.get_endpoint(ep_addr.index())?;
         let pipe = ep.get_out()?;
@@ -381,12 +848,19 @@ impl UsbBus for UsbIpBus {
         // Try to get data
         let data = match pipe.data.pop_front() {
             None => {
-                log::trace!("no data available at endpoint");
+                trace!("no data available at endpoint");
                 return Err(UsbError::WouldBlock);
             }
             Some(data) => data,
         };
 
+        if ep_addr.index() == 0 {
+            inner.control_read();
+        } else {
+            inner.packet_received(ep_addr.index(), data.len());
+            inner.count_packet();
+        }
+
         if buf.len() < data.len() {
             buf.copy_from_slice(&data[..buf.len()]);
         } else {
@@ -398,19 +872,40 @@ impl UsbBus for UsbIpBus {
     fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
         let mut inner = self.lock();
 
+        // The device refused to answer the internal enumeration
+        if ep_addr.index() == 0 && stalled && inner.enumeration.is_active() {
+            inner.enumeration.fail();
+        }
+
         let endpoint = match inner.get_endpoint(ep_addr.index()) {
             Ok(endpoint) => endpoint,
             _ => return,
         };
 
-        if endpoint.stalled != stalled {
-            log::debug!(
+        let pipe = match ep_addr.direction() {
+            UsbDirection::In => endpoint.get_in(),
+            UsbDirection::Out => endpoint.get_out(),
+        };
+        let pipe = match pipe {
+            Ok(pipe) => pipe,
+            _ => return,
+        };
+
+        if pipe.stalled != stalled {
+            debug!(
                 "setting endpoint {:?} to stalled state {}",
                 ep_addr,
                 stalled
             );
         }
-        endpoint.stalled = stalled;
+        pipe.stalled = stalled;
+
+        // The host sees the STALL handshake on all the URBs, that are waiting for data
+        if stalled && ep_addr.index() == 0 {
+            inner.finish_control(UrbStatus::Stall);
+        } else if stalled {
+            inner.fail_pending(ep_addr, UrbStatus::Stall);
+        }
     }
 
     fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
@@ -421,15 +916,19 @@ impl UsbBus for UsbIpBus {
             _ => return false,
         };
 
-        endpoint.stalled
+        let pipe = match ep_addr.direction() {
+            UsbDirection::In => endpoint.get_in(),
+            UsbDirection::Out => endpoint.get_out(),
+        };
+        pipe.map(|pipe| pipe.stalled).unwrap_or(false)
     }
 
     fn suspend(&self) {
         let mut inner = self.lock();
 
-        log::info!("suspending device");
+        info!("suspending device");
         if inner.suspended {
-            log::warn!("supending already suspended device");
+            warn!("supending already suspended device");
         }
 
         inner.suspended = true;
@@ -438,35 +937,64 @@ impl UsbBus for UsbIpBus {
     fn resume(&self) {
         let mut inner = self.lock();
 
-        log::info!("resuming device");
+        info!("resuming device");
         if !inner.suspended {
-            log::warn!("resuming already active device");
+            warn!("resuming already active device");
         }
 
         inner.suspended = false;
     }
 
-    fn poll(&self) -> PollResult {
+    fn force_reset(&self) -> UsbResult<()> {
         let mut inner = self.lock();
-        log::trace!("usb device is being polled");
 
-        inner.handle_socket();
+        inner.force_reset();
+        Ok(())
+    }
+
+    fn poll(&self) -> PollResult {
+        trace!("usb device is being polled");
+
+        // NOTE: The server locks the buses, therefore we must not hold the lock here
+        self.server.poll();
+        let mut inner = self.lock();
 
         if inner.reset {
-            log::trace!("device is in reset state");
+            trace!("device is in reset state");
             return PollResult::Reset;
         }
 
-        if inner.suspended {
-            log::trace!("device is suspended");
+        // An unplugged device does not see any traffic
+        if !inner.plugged {
+            trace!("device is unplugged");
+            return PollResult::None;
+        }
+
+        // While there is no host attached, we use the time to learn the descriptors
+        if inner.connection != ConnectionState::Imported && !inner.enumerate() {
+            trace!("device is not imported");
+            return PollResult::None;
+        }
+
+        // While the bus is suspended, there are no frames
+        if inner.host_suspended {
+            trace!("bus is suspended");
             return PollResult::Suspend;
         }
 
+        if inner.suspended {
+            debug!("device is being resumed");
+            return PollResult::Resume;
+        }
+
+        // Every poll of an active device is a new frame on the bus
+        inner.next_frame();
+
         let mut ep_in: u16 = 0;
         let mut ep_out: u16 = 0;
         let mut ep_setup: u16 = 0;
 
-        for i in (0..NUM_ENDPOINTS).into_iter().rev() {
+        for i in (0..NUM_ENDPOINTS).rev() {
             ep_in <<= 1;
             ep_out <<= 1;
             ep_setup <<= 1;
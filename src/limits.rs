@@ -0,0 +1,147 @@
+//! The limits, that protect the device against broken or malicious hosts.
+//!
+//! Every length in a USBIP frame is chosen by the host. Without limits, a single frame
+//! could make the device allocate gigabytes or queue URBs until the memory runs out.
+//! A URB, that exceeds a limit, is completed with an error status. Only if the framing of
+//! the stream itself can not be trusted anymore, the connection is closed.
+
+use crate::{cmd::UsbIpHeader, request::UsbIpCmdSubmit, status::UrbStatus, UsbIpBusInner};
+use alloc::vec::Vec;
+
+/// The number of isochronous packets, a single URB may carry, like in the Linux stub driver.
+pub const MAX_ISO_PACKETS: usize = 1024;
+
+/// The length of the header of a command, including the fields of `CMD_SUBMIT`.
+const USBIP_HEADER_LENGTH: usize = 48;
+
+/// The length of the descriptor of an isochronous packet.
+const ISO_PACKET_DESCRIPTOR_LENGTH: usize = 16;
+
+/// The limits of a bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+   /// The largest transfer buffer of a single URB
+   pub max_transfer_length: usize,
+
+   /// The number of URBs, that can be pending on an endpoint at the same time
+   pub max_queued_urbs: usize,
+
+   /// The number of bytes, all the pending URBs of the bus may buffer together.
+   ///
+   /// The responses, that wait to be sent to the host, are limited to the same number of bytes.
+   pub max_buffered_bytes: usize,
+}
+
+impl Default for Limits {
+   fn default() -> Self {
+      Self {
+         max_transfer_length: 1024 * 1024,
+         max_queued_urbs: 128,
+         max_buffered_bytes: 16 * 1024 * 1024,
+      }
+   }
+}
+
+impl Limits {
+   /// Returns the length of the largest command, a host may send.
+   ///
+   /// The bytes received from the host are only buffered up to this length,
+   /// until then, reading from the socket is paused.
+   pub fn max_frame_length(&self) -> usize {
+      USBIP_HEADER_LENGTH + self.max_transfer_length + MAX_ISO_PACKETS * ISO_PACKET_DESCRIPTOR_LENGTH
+   }
+}
+
+impl UsbIpBusInner {
+   /// Checks a submitted URB against the limits of the bus.
+   ///
+   /// # Returns
+   /// The status, the URB has to be completed with, if it can not be accepted.
+   pub fn check_submit(&self, header: &UsbIpHeader, cmd: &UsbIpCmdSubmit) -> Result<(), UrbStatus> {
+      let length = cmd.transfer_buffer_length;
+      if length < 0 || length as usize > self.limits.max_transfer_length {
+         warn!("urb {} has an invalid transfer length of {}", header.seqnum, length);
+         return Err(UrbStatus::Invalid);
+      }
+
+      // The sequence number identifies the URB, e.g. when it is unlinked
+      if self.is_pending(header.seqnum) {
+         warn!("urb {} is already pending", header.seqnum);
+         return Err(UrbStatus::Invalid);
+      }
+
+      if let Some(ep) = self.endpoint.get(header.ep as usize) {
+         let queued = ep.pending_ins.len()
+            + ep.pending_outs.len()
+            + ep
+               .pipe_in
+               .iter()
+               .chain(ep.pipe_out.iter())
+               .map(|pipe| pipe.pending_isos.len())
+               .sum::<usize>();
+
+         if queued >= self.limits.max_queued_urbs {
+            warn!("too many urbs queued on endpoint {}", header.ep);
+            return Err(UrbStatus::NoMemory);
+         }
+      }
+
+      if self.buffered_bytes() + length as usize > self.limits.max_buffered_bytes {
+         warn!("too many bytes buffered to accept urb {}", header.seqnum);
+         return Err(UrbStatus::NoMemory);
+      }
+
+      Ok(())
+   }
+
+   /// Returns the number of bytes, the pending URBs buffer or may buffer once they complete.
+   fn buffered_bytes(&self) -> usize {
+      let control = self.control.as_ref().map(|control| control.length()).unwrap_or(0);
+
+      control
+         + self
+            .endpoint
+            .iter()
+            .map(|ep| {
+               let ins: usize = ep
+                  .pending_ins
+                  .iter()
+                  .map(|(_, cmd, _)| cmd.transfer_buffer_length.max(0) as usize)
+                  .sum();
+               let outs: usize = ep
+                  .pending_outs
+                  .iter()
+                  .flat_map(|out| out.packets.iter())
+                  .map(Vec::len)
+                  .sum();
+               let isos: usize = ep
+                  .pipe_in
+                  .iter()
+                  .chain(ep.pipe_out.iter())
+                  .flat_map(|pipe| pipe.pending_isos.iter())
+                  .map(|iso| iso.length())
+                  .sum();
+
+               ins + outs + isos
+            })
+            .sum::<usize>()
+   }
+
+   /// Returns `true`, if an URB with the sequence number `seqnum` is pending.
+   fn is_pending(&self, seqnum: u32) -> bool {
+      if matches!(self.control, Some(ref control) if control.seqnum() == seqnum) {
+         return true;
+      }
+
+      self.endpoint.iter().any(|ep| {
+         ep.pending_ins.iter().any(|(header, _, _)| header.seqnum == seqnum)
+            || ep.pending_outs.iter().any(|out| out.header.seqnum == seqnum)
+            || ep
+               .pipe_in
+               .iter()
+               .chain(ep.pipe_out.iter())
+               .flat_map(|pipe| pipe.pending_isos.iter())
+               .any(|iso| iso.seqnum() == seqnum)
+      })
+   }
+}
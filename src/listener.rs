@@ -0,0 +1,163 @@
+//! The sockets, the server accepts hosts on.
+//!
+//! Besides the TCP listener, that the `usbip` tools connect to, the server can listen on a
+//! Unix domain socket. This avoids picking free ports, when many devices run on the same
+//! machine, and local test clients or proxies connect by path instead.
+//! Both kinds of sockets are driven the same way, so the rest of the server does not
+//! need to know, which one a connection came in on.
+
+use crate::stdio::StdioStream;
+use std::{
+   io::{Read, Result as IoResult, Write},
+   net::{TcpListener, TcpStream},
+};
+#[cfg(unix)]
+use std::{
+   io::{Error as IoError, ErrorKind},
+   os::unix::{
+      fs::FileTypeExt,
+      net::{UnixListener, UnixStream},
+   },
+   path::{Path, PathBuf},
+};
+
+/// A socket, the server listens on.
+#[derive(Debug)]
+pub enum Listener {
+   Tcp(TcpListener),
+
+   /// The path is kept, to remove the socket file again
+   #[cfg(unix)]
+   Unix(UnixListener, PathBuf),
+}
+
+/// A connection, that was accepted on a [`Listener`], opened by the device or passed in by the user.
+#[derive(Debug)]
+pub enum Stream {
+   Tcp(TcpStream),
+   #[cfg(unix)]
+   Unix(UnixStream),
+   Stdio(StdioStream),
+}
+
+impl Listener {
+   /// Listens for connections on the Unix domain socket at `path`.
+   ///
+   /// A socket file, that was left behind by a process, which did not exit cleanly,
+   /// is removed first. If another server still listens on `path`, this fails
+   /// with [`ErrorKind::AddrInUse`].
+   #[cfg(unix)]
+   pub fn bind_unix(path: &Path) -> IoResult<Self> {
+      remove_stale_socket(path)?;
+
+      let listener = UnixListener::bind(path)?;
+      listener.set_nonblocking(true)?;
+      Ok(Listener::Unix(listener, path.to_path_buf()))
+   }
+
+   /// Accepts a new connection, returns the stream and the peer, as it appears in the log.
+   pub fn accept(&self) -> IoResult<(Stream, String)> {
+      match self {
+         Listener::Tcp(listener) => {
+            let (stream, addr) = listener.accept()?;
+            Ok((Stream::Tcp(stream), addr.to_string()))
+         }
+         // The clients of a Unix socket are usually unnamed, so the path identifies them
+         #[cfg(unix)]
+         Listener::Unix(listener, path) => {
+            let (stream, _) = listener.accept()?;
+            Ok((Stream::Unix(stream), path.display().to_string()))
+         }
+      }
+   }
+
+   /// Returns the path of the Unix domain socket, if this is one.
+   #[cfg(unix)]
+   pub fn path(&self) -> Option<&Path> {
+      match self {
+         Listener::Tcp(_) => None,
+         Listener::Unix(_, path) => Some(path),
+      }
+   }
+}
+
+impl Drop for Listener {
+   fn drop(&mut self) {
+      // Unlike a TCP port, the socket file outlives the listener
+      #[cfg(unix)]
+      if let Listener::Unix(_, path) = self {
+         let _ = std::fs::remove_file(path);
+      }
+   }
+}
+
+/// Removes the socket file at `path`, unless a server still listens on it.
+///
+/// Anything at `path`, that is not a socket, is left alone, so binding fails instead.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> IoResult<()> {
+   let metadata = match std::fs::symlink_metadata(path) {
+      Ok(metadata) => metadata,
+      Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+      Err(err) => return Err(err),
+   };
+   if !metadata.file_type().is_socket() {
+      return Ok(());
+   }
+
+   // Only a socket, that nobody accepts connections on, is stale
+   match UnixStream::connect(path) {
+      Ok(_) => Err(IoError::new(
+         ErrorKind::AddrInUse,
+         format!("{} is in use by another server", path.display()),
+      )),
+      Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+         info!("removing stale socket {}", display!(path.display()));
+         std::fs::remove_file(path)
+      }
+      Err(err) => Err(err),
+   }
+}
+
+impl Stream {
+   pub fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
+      match self {
+         Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+         #[cfg(unix)]
+         Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+         // Never blocks, the streams are served by their own threads
+         Stream::Stdio(_) => Ok(()),
+      }
+   }
+}
+
+impl Read for Stream {
+   fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+      match self {
+         Stream::Tcp(stream) => stream.read(buf),
+         #[cfg(unix)]
+         Stream::Unix(stream) => stream.read(buf),
+         Stream::Stdio(stream) => stream.read(buf),
+      }
+   }
+}
+
+impl Write for Stream {
+   fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+      match self {
+         Stream::Tcp(stream) => stream.write(buf),
+         #[cfg(unix)]
+         Stream::Unix(stream) => stream.write(buf),
+         Stream::Stdio(stream) => stream.write(buf),
+      }
+   }
+
+   fn flush(&mut self) -> IoResult<()> {
+      match self {
+         Stream::Tcp(stream) => stream.flush(),
+         #[cfg(unix)]
+         Stream::Unix(stream) => stream.flush(),
+         Stream::Stdio(stream) => stream.flush(),
+      }
+   }
+}
@@ -0,0 +1,51 @@
+//! The macros, the crate logs with.
+//!
+//! By default, the messages go to the `log` crate. With the `defmt` feature, they are
+//! handed to `defmt` instead, so firmware can use the same logging backend as the rest
+//! of its stack. The arguments are passed on as they are and formatted on the host.
+
+macro_rules! log_at {
+   ($level:ident, $($arg:tt)*) => {{
+      #[cfg(feature = "defmt")]
+      defmt::$level!($($arg)*);
+      #[cfg(not(feature = "defmt"))]
+      log::$level!($($arg)*);
+   }};
+}
+
+macro_rules! trace {
+   ($($arg:tt)*) => { log_at!(trace, $($arg)*) };
+}
+
+macro_rules! debug {
+   ($($arg:tt)*) => { log_at!(debug, $($arg)*) };
+}
+
+macro_rules! info {
+   ($($arg:tt)*) => { log_at!(info, $($arg)*) };
+}
+
+macro_rules! warn {
+   ($($arg:tt)*) => { log_at!(warn, $($arg)*) };
+}
+
+macro_rules! error {
+   ($($arg:tt)*) => { log_at!(error, $($arg)*) };
+}
+
+/// Wraps a value, that only implements `Display`, such that `defmt` formats it on the device.
+///
+/// This is meant for the types of `std`, everything else is passed on as it is.
+#[cfg(all(feature = "std", feature = "defmt"))]
+macro_rules! display {
+   ($value:expr) => {
+      defmt::Display2Format(&$value)
+   };
+}
+
+#[cfg(all(feature = "std", not(feature = "defmt")))]
+macro_rules! display {
+   ($value:expr) => {
+      $value
+   };
+}
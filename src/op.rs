@@ -1,20 +1,99 @@
 use crate::UsbIpError;
-use std::{
-   convert::TryInto,
-   io::{Error, ErrorKind, Read},
-   net::TcpStream,
+use alloc::{
+   string::{String, ToString},
+   vec::Vec,
 };
+use core::convert::TryInto;
+
+/// The version of the USBIP protocol, this crate speaks
+pub const USBIP_VERSION: u16 = 0x0111;
+
+/// The versions of the USBIP protocol, this crate understands.
+///
+/// Older userspace tools (e.g. `usbip-utils` 0.1.x) still announce version 1.0.6,
+/// the frames themselves did not change since.
+pub const SUPPORTED_VERSIONS: [u16; 2] = [USBIP_VERSION, 0x0106];
+
+const OP_REQ_IMPORT: u16 = 0x8003;
+const OP_REP_IMPORT: u16 = 0x0003;
+const OP_REQ_DEVLIST: u16 = 0x8005;
+const OP_REP_DEVLIST: u16 = 0x0005;
+const OP_REQ_EXPORT: u16 = 0x8006;
+const OP_REP_EXPORT: u16 = 0x0006;
+
+/// The status of an op reply.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpStatus {
+   /// The request was completed successfully
+   Ok,
+
+   /// The request failed
+   Na,
+
+   /// The requested device is already in use, or not ready to be imported yet
+   DevBusy,
+
+   /// The requested device is in an error state
+   DevErr,
+
+   /// The requested device does not exist
+   NoDev,
+
+   /// An unexpected error occured
+   Error,
+}
 
+impl OpStatus {
+   /// Returns the value of the status field.
+   pub fn to_u32(self) -> u32 {
+      match self {
+         OpStatus::Ok => 0x00,
+         OpStatus::Na => 0x01,
+         OpStatus::DevBusy => 0x02,
+         OpStatus::DevErr => 0x03,
+         OpStatus::NoDev => 0x04,
+         OpStatus::Error => 0x05,
+      }
+   }
+
+   /// Parses the value of the status field.
+   ///
+   /// # Returns
+   /// `None`, if the value is no status of the USBIP specification.
+   pub fn try_from_u32(num: u32) -> Option<Self> {
+      match num {
+         0x00 => Some(OpStatus::Ok),
+         0x01 => Some(OpStatus::Na),
+         0x02 => Some(OpStatus::DevBusy),
+         0x03 => Some(OpStatus::DevErr),
+         0x04 => Some(OpStatus::NoDev),
+         0x05 => Some(OpStatus::Error),
+         _ => None,
+      }
+   }
+}
+
+/// The header, every op message starts with.
 #[repr(C)]
-#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OpHeader {
+   /// The version of the protocol, the sender speaks
    pub version: u16,
+
+   /// The code of the message, e.g. `0x8005` for `OP_REQ_DEVLIST`
    pub command: u16,
+
+   /// The status of a reply, always 0 in a request
    pub status: u32,
 }
 
 impl OpHeader {
-   fn to_array(&self) -> [u8; 8] {
+   /// Encodes the header.
+   pub fn to_array(&self) -> [u8; 8] {
       let mut result = [0; 8];
 
       result[0..2].copy_from_slice(&self.version.to_be_bytes());
@@ -24,7 +103,11 @@ impl OpHeader {
       result
    }
 
-   fn from_slice(data: &[u8]) -> Self {
+   /// Parses the header from the first 8 bytes of `data`.
+   ///
+   /// # Panics
+   /// If `data` is shorter than 8 bytes.
+   pub fn from_slice(data: &[u8]) -> Self {
       Self {
          version: u16::from_be_bytes(data[0..2].try_into().unwrap()),
          command: u16::from_be_bytes(data[2..4].try_into().unwrap()),
@@ -33,168 +116,472 @@ impl OpHeader {
    }
 }
 
+/// Returns `true`, if `version` is one of the [`SUPPORTED_VERSIONS`].
+pub fn is_supported_version(version: u16) -> bool {
+   SUPPORTED_VERSIONS.contains(&version)
+}
+
+/// An op request, the host sends before a device is imported.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum OpRequest {
+   /// `OP_REQ_DEVLIST`, lists the exported devices
    ListDevices(OpHeader),
-   ConnectDevice(OpHeader),
+
+   /// `OP_REQ_IMPORT`, imports the device with the bus id
+   ConnectDevice(OpHeader, String),
 }
 
 impl OpRequest {
-   pub fn read(reader: &mut TcpStream) -> Result<Self, Error> {
-      // Receive the header
-      reader.set_nonblocking(true)?;
-      let mut header_buf = [0; 8];
-      match reader.read(&mut header_buf) {
-         Ok(bytes_read) if bytes_read == 8 => (),
-         Ok(0) => {
-            return Err(Error::new(
-               ErrorKind::NotConnected,
-               Box::new(UsbIpError::ConnectionClosed),
-            ))
-         }
-         Ok(bytes_read) => {
-            return Err(Error::new(
-               ErrorKind::InvalidInput,
-               Box::new(UsbIpError::PkgTooShort(bytes_read)),
-            ))
-         }
-         Err(err) => return Err(err),
+   /// Returns the header of the request.
+   pub fn header(&self) -> &OpHeader {
+      match self {
+         Self::ListDevices(header) => header,
+         Self::ConnectDevice(header, _) => header,
       }
-      reader.set_nonblocking(false)?;
+   }
 
+   /// Decodes an op request from the beginning of `data`.
+   ///
+   /// # Returns
+   /// - `Ok(Some((request, len)))` if a request of `len` bytes was decoded
+   /// - `Ok(None)` if `data` does not yet contain the complete request
+   pub fn decode(data: &[u8]) -> Result<Option<(Self, usize)>, UsbIpError> {
       // Parse the header
-      let header = OpHeader::from_slice(&header_buf);
+      if data.len() < 8 {
+         return Ok(None);
+      }
+      let header = OpHeader::from_slice(&data[0..8]);
 
       // Check status
       if header.status != 0 {
-         return Err(Error::new(
-            ErrorKind::InvalidInput,
-            Box::new(UsbIpError::StatusNotOk(header.status)),
-         ));
+         return Err(UsbIpError::StatusNotOk(header.status));
       }
 
-      log::debug!("request version is {}", header.version);
-
       // Dispatch on command
       match header.command {
-         0x8005 => {
-            log::info!("received request to list devices");
-            Ok(Self::ListDevices(header))
+         OP_REQ_DEVLIST => Ok(Some((Self::ListDevices(header), 8))),
+         OP_REQ_IMPORT => {
+            if data.len() < 40 {
+               return Ok(None);
+            }
+            let bus_id = deserialize_str(&data[8..40])?;
+            Ok(Some((Self::ConnectDevice(header, bus_id), 40)))
          }
-         0x8003 => {
-            let mut bus_id_buf = [0; 32];
-            reader.read_exact(&mut bus_id_buf)?;
-
-            let bus_id = match std::str::from_utf8(&bus_id_buf) {
-               Ok(data) => data.trim_matches(char::from(0)).to_string(),
-               Err(err) => {
-                  return Err(Error::new(ErrorKind::InvalidInput, err));
-               }
-            };
+         _ => Err(UsbIpError::InvalidCommand(header.command as u32)),
+      }
+   }
 
-            log::info!("received request to connect device {}", bus_id);
-            Ok(Self::ConnectDevice(header))
-         }
-         _ => Err(Error::new(
-            ErrorKind::InvalidInput,
-            Box::new(UsbIpError::InvalidCommand(header.command)),
-         )),
+   /// Encodes the request, as the host sends it.
+   ///
+   /// The command in the header is derived from the kind of the request.
+   pub fn to_vec(&self) -> Vec<u8> {
+      let header = self.header();
+      let command = match self {
+         Self::ListDevices(_) => OP_REQ_DEVLIST,
+         Self::ConnectDevice(_, _) => OP_REQ_IMPORT,
+      };
+
+      let mut result = OpHeader {
+         version: header.version,
+         command,
+         status: header.status,
+      }
+      .to_array()
+      .to_vec();
+
+      if let Self::ConnectDevice(_, bus_id) = self {
+         serialize_str(&mut result, bus_id, 32);
       }
+
+      result
    }
 }
 
-#[derive(Debug, Clone)]
+/// The request, a device sends to export itself to a host, that waits for devices.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpExportRequest {
+   /// The version of the protocol, the device speaks
+   pub version: u16,
+
+   /// The device, that is exported, without its interfaces
+   pub device: OpDevice,
+}
+
+impl OpExportRequest {
+   /// Decodes an export request from the beginning of `data`.
+   ///
+   /// The request does not carry the interfaces, so those of the decoded device are empty.
+   ///
+   /// # Returns
+   /// - `Ok(Some((request, len)))` if a request of `len` bytes was decoded
+   /// - `Ok(None)` if `data` does not yet contain the complete request
+   pub fn decode(data: &[u8]) -> Result<Option<(Self, usize)>, UsbIpError> {
+      if data.len() < 8 {
+         return Ok(None);
+      }
+      let header = OpHeader::from_slice(&data[0..8]);
+
+      if header.command != OP_REQ_EXPORT {
+         return Err(UsbIpError::InvalidCommand(header.command as u32));
+      }
+      if header.status != 0 {
+         return Err(UsbIpError::StatusNotOk(header.status));
+      }
+
+      if data.len() < 8 + OpDevice::LEN {
+         return Ok(None);
+      }
+      let device = OpDevice::deserialize(&data[8..8 + OpDevice::LEN])?;
+
+      Ok(Some((
+         Self {
+            version: header.version,
+            device,
+         },
+         8 + OpDevice::LEN,
+      )))
+   }
+
+   /// Encodes the request, as the device sends it.
+   ///
+   /// The path and the bus id of the device are cut off, if they do not fit into their fields.
+   pub fn to_vec(&self) -> Vec<u8> {
+      let header = OpHeader {
+         version: self.version,
+         command: OP_REQ_EXPORT,
+         status: OpStatus::Ok.to_u32(),
+      };
+
+      let mut result = header.to_array().to_vec();
+      self.device.serialize(&mut result);
+
+      result
+   }
+}
+
+/// The reply of the host to an [`OpExportRequest`].
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpExportReply {
+   /// The header, its status is [`OpStatus::Ok`], if the host has imported the device.
+   ///
+   /// usbipd refuses a device with [`OpStatus::Na`], other hosts only set the return code.
+   pub header: OpHeader,
+
+   /// 0, if the host has imported the device
+   pub return_code: i32,
+}
+
+impl OpExportReply {
+   /// Decodes an export reply from the beginning of `data`.
+   ///
+   /// The reply is decoded whatever its status is, such that the return code
+   /// of a refusal can be read as well.
+   ///
+   /// # Returns
+   /// - `Ok(Some((reply, len)))` if a reply of `len` bytes was decoded
+   /// - `Ok(None)` if `data` does not yet contain the complete reply
+   pub fn decode(data: &[u8]) -> Result<Option<(Self, usize)>, UsbIpError> {
+      // The reply always carries the return code, even if the host refused the device
+      if data.len() < 12 {
+         return Ok(None);
+      }
+      let header = OpHeader::from_slice(&data[0..8]);
+
+      if header.command != OP_REP_EXPORT {
+         return Err(UsbIpError::InvalidCommand(header.command as u32));
+      }
+
+      let return_code = i32::from_be_bytes(data[8..12].try_into().unwrap());
+      Ok(Some((Self { header, return_code }, 12)))
+   }
+
+   /// Encodes the reply, as the host sends it.
+   pub fn to_vec(&self) -> Vec<u8> {
+      let header = OpHeader {
+         version: self.header.version,
+         command: OP_REP_EXPORT,
+         status: self.header.status,
+      };
+
+      let mut result = header.to_array().to_vec();
+      result.extend_from_slice(&self.return_code.to_be_bytes());
+
+      result
+   }
+}
+
+/// The reply of the device to an [`OpRequest`].
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OpResponse {
+   /// The version of the protocol, the reply is sent in
    pub version: u16,
-   pub path: String,
-   pub bus_id: String,
-   pub descriptor: OpDeviceDescriptor,
+
+   /// Whether the request succeeded
+   pub status: OpStatus,
+
+   /// The kind of the reply, together with its content
    pub cmd: OpResponseCommand,
 }
 
-#[derive(Debug, Clone)]
+/// The content of an [`OpResponse`].
+///
+/// If the request failed, only the header is sent,
+/// i.e. the list is empty and the imported device is `None`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum OpResponseCommand {
-   ListDevices(OpInterfaceDescriptor),
-   ConnectDevice,
+   /// `OP_REP_DEVLIST`, the exported devices
+   ListDevices(Vec<OpDevice>),
+
+   /// `OP_REP_IMPORT`, the imported device
+   ConnectDevice(Option<OpDevice>),
+}
+
+/// An exported device, as it is reported in the op messages
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpDevice {
+   /// The path of the device in sysfs, at most 256 bytes
+   pub path: String,
+
+   /// The bus id, the host imports the device by, at most 32 bytes
+   pub bus_id: String,
+
+   /// The numbers and the descriptor of the device
+   pub descriptor: OpDeviceDescriptor,
+
+   /// The interfaces, only sent in a device list
+   pub interfaces: Vec<OpInterfaceDescriptor>,
 }
 
 impl OpResponse {
-   pub fn to_vec(&self) -> Option<Vec<u8>> {
+   /// Decodes an op response from the beginning of `data`.
+   ///
+   /// # Returns
+   /// - `Ok(Some((response, len)))` if a response of `len` bytes was decoded
+   /// - `Ok(None)` if `data` does not yet contain the complete response
+   pub fn decode(data: &[u8]) -> Result<Option<(Self, usize)>, UsbIpError> {
+      if data.len() < 8 {
+         return Ok(None);
+      }
+      let header = OpHeader::from_slice(&data[0..8]);
+      let status = OpStatus::try_from_u32(header.status).ok_or(UsbIpError::StatusNotOk(header.status))?;
+
+      let response = |cmd| Self {
+         version: header.version,
+         status,
+         cmd,
+      };
+
+      match header.command {
+         // If the request failed, only the header is sent
+         OP_REP_DEVLIST if status != OpStatus::Ok => {
+            Ok(Some((response(OpResponseCommand::ListDevices(vec![])), 8)))
+         }
+         OP_REP_IMPORT if status != OpStatus::Ok => {
+            Ok(Some((response(OpResponseCommand::ConnectDevice(None)), 8)))
+         }
+         OP_REP_DEVLIST => {
+            if data.len() < 12 {
+               return Ok(None);
+            }
+            let num_devices = u32::from_be_bytes(data[8..12].try_into().unwrap());
+
+            let mut devices = vec![];
+            let mut offset = 12;
+            for _ in 0..num_devices {
+               if data.len() < offset + OpDevice::LEN {
+                  return Ok(None);
+               }
+               let mut device = OpDevice::deserialize(&data[offset..offset + OpDevice::LEN])?;
+               offset += OpDevice::LEN;
+
+               // In a list, the interface descriptors follow the device
+               let interfaces_len = device.descriptor.num_interfaces as usize * 4;
+               if data.len() < offset + interfaces_len {
+                  return Ok(None);
+               }
+               device.interfaces = data[offset..offset + interfaces_len]
+                  .chunks(4)
+                  .map(OpInterfaceDescriptor::from_slice)
+                  .collect();
+               offset += interfaces_len;
+
+               devices.push(device);
+            }
+
+            Ok(Some((response(OpResponseCommand::ListDevices(devices)), offset)))
+         }
+         OP_REP_IMPORT => {
+            if data.len() < 8 + OpDevice::LEN {
+               return Ok(None);
+            }
+            let device = OpDevice::deserialize(&data[8..8 + OpDevice::LEN])?;
+
+            Ok(Some((
+               response(OpResponseCommand::ConnectDevice(Some(device))),
+               8 + OpDevice::LEN,
+            )))
+         }
+         _ => Err(UsbIpError::InvalidCommand(header.command as u32)),
+      }
+   }
+
+   /// Encodes the response, as the device sends it.
+   ///
+   /// The command in the header is derived from the kind of the response.
+   ///
+   /// The paths and the bus ids of the devices are cut off, if they do not fit into their fields.
+   pub fn to_vec(&self) -> Vec<u8> {
       let mut result = vec![];
 
       // Build and serialize the header
       let reply: u16 = match self.cmd {
-         OpResponseCommand::ListDevices(_) => 0x0005,
-         OpResponseCommand::ConnectDevice => 0x0003,
+         OpResponseCommand::ListDevices(_) => OP_REP_DEVLIST,
+         OpResponseCommand::ConnectDevice(_) => OP_REP_IMPORT,
       };
 
       let header = OpHeader {
          version: self.version,
          command: reply,
-         status: 0,
+         status: self.status.to_u32(),
       };
 
       result.extend_from_slice(&header.to_array());
 
+      // If the request failed, only the header is sent
+      if self.status != OpStatus::Ok {
+         return result;
+      }
+
       match self.cmd {
-         // This implementation can only ever export one device,
-         // therefore number of exported devices is fixed
-         OpResponseCommand::ListDevices(_) => result.extend_from_slice(&[0, 0, 0, 1]),
-         OpResponseCommand::ConnectDevice => (),
-      };
+         OpResponseCommand::ListDevices(ref devices) => {
+            result.extend_from_slice(&(devices.len() as u32).to_be_bytes());
 
-      // Serialize path
-      let str_len = self.path.as_bytes().len();
-      if str_len > 256 {
-         log::warn!("path is longer than 256 bytes");
-         return None;
-      }
+            for device in devices {
+               device.serialize(&mut result);
 
-      let mut path_buf = [0; 256];
-      path_buf[..str_len].copy_from_slice(self.path.as_bytes());
-      result.extend_from_slice(&path_buf);
+               // In a list, the interface descriptors follow the device
+               for interface in device.interfaces.iter() {
+                  result.extend_from_slice(&interface.to_array());
+               }
+            }
+         }
+         OpResponseCommand::ConnectDevice(Some(ref device)) => device.serialize(&mut result),
+         OpResponseCommand::ConnectDevice(None) => (),
+      };
 
-      // Serialize bus_id
-      let str_len = self.bus_id.as_bytes().len();
-      if str_len > 32 {
-         log::warn!("bus_id is longr than 32 bytes");
-         return None;
-      }
+      result
+   }
+}
+
+impl OpDevice {
+   /// The length of a serialized device, without its interfaces.
+   const LEN: usize = 256 + 32 + 24;
 
-      let mut bus_id_buf = [0; 32];
-      bus_id_buf[..str_len].copy_from_slice(self.bus_id.as_bytes());
-      result.extend_from_slice(&bus_id_buf);
+   fn serialize(&self, result: &mut Vec<u8>) {
+      serialize_str(result, &self.path, 256);
+      serialize_str(result, &self.bus_id, 32);
 
       // Serialize the Op Desciptor
       result.extend_from_slice(&self.descriptor.to_array());
+   }
+
+   /// Parses a device from the first [`OpDevice::LEN`] bytes of `data`.
+   ///
+   /// The interfaces are not part of it, they are left empty.
+   fn deserialize(data: &[u8]) -> Result<Self, UsbIpError> {
+      Ok(Self {
+         path: deserialize_str(&data[0..256])?,
+         bus_id: deserialize_str(&data[256..288])?,
+         descriptor: OpDeviceDescriptor::from_slice(&data[288..312]),
+         interfaces: vec![],
+      })
+   }
+}
 
-      // If exists, serialize the interface descriptor
-      if let OpResponseCommand::ListDevices(ref interface) = self.cmd {
-         result.extend_from_slice(&interface.to_array());
+/// Appends `string` as a zero padded field of `len` bytes.
+///
+/// A string, that is too long, is cut off at the last character, that fits.
+fn serialize_str(result: &mut Vec<u8>, string: &str, len: usize) {
+   let mut end = string.len();
+   if end > len {
+      warn!("{} is longer than {} bytes", string, len);
+      end = len;
+      while !string.is_char_boundary(end) {
+         end -= 1;
       }
+   }
+
+   let start = result.len();
+   result.resize(start + len, 0);
+   result[start..start + end].copy_from_slice(&string.as_bytes()[..end]);
+}
 
-      Some(result)
+/// Parses a zero padded string field.
+fn deserialize_str(data: &[u8]) -> Result<String, UsbIpError> {
+   match core::str::from_utf8(data) {
+      Ok(data) => Ok(data.trim_matches(char::from(0)).to_string()),
+      Err(_) => Err(UsbIpError::InvalidBusId),
    }
 }
 
+/// The part of an [`OpDevice`], that is taken from its device descriptor.
 #[repr(C)]
-#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OpDeviceDescriptor {
+   /// The number of the bus, the upper half of the devid
    pub busnum: u32,
+
+   /// The number of the device, the lower half of the devid
    pub devnum: u32,
+
+   /// The speed as `enum usb_device_speed` of Linux
    pub speed: u32,
+
+   /// `idVendor`
    pub vendor: u16,
+
+   /// `idProduct`
    pub product: u16,
+
+   /// `bcdDevice`
    pub bcd_device: u16,
+
+   /// `bDeviceClass`
    pub device_class: u8,
+
+   /// `bDeviceSubClass`
    pub device_subclass: u8,
+
+   /// `bDeviceProtocol`
    pub device_protocol: u8,
+
+   /// The value of the selected configuration
    pub configuration_value: u8,
+
+   /// `bNumConfigurations`
    pub num_configurations: u8,
+
+   /// The number of interfaces of the selected configuration
    pub num_interfaces: u8,
 }
 
 impl OpDeviceDescriptor {
-   fn to_array(&self) -> [u8; 24] {
+   /// Encodes the descriptor.
+   pub fn to_array(&self) -> [u8; 24] {
       let mut result = [0; 24];
 
       result[0..4].copy_from_slice(&self.busnum.to_be_bytes());
@@ -216,19 +603,51 @@ impl OpDeviceDescriptor {
 
       result
    }
+
+   /// Parses the descriptor from the first 24 bytes of `data`.
+   ///
+   /// # Panics
+   /// If `data` is shorter than 24 bytes.
+   pub fn from_slice(data: &[u8]) -> Self {
+      Self {
+         busnum: u32::from_be_bytes(data[0..4].try_into().unwrap()),
+         devnum: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+         speed: u32::from_be_bytes(data[8..12].try_into().unwrap()),
+         vendor: u16::from_be_bytes(data[12..14].try_into().unwrap()),
+         product: u16::from_be_bytes(data[14..16].try_into().unwrap()),
+         bcd_device: u16::from_be_bytes(data[16..18].try_into().unwrap()),
+         device_class: data[18],
+         device_subclass: data[19],
+         device_protocol: data[20],
+         configuration_value: data[21],
+         num_configurations: data[22],
+         num_interfaces: data[23],
+      }
+   }
 }
 
+/// An interface of an [`OpDevice`], as it is reported in a device list.
 #[repr(C)]
-#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OpInterfaceDescriptor {
+   /// `bInterfaceClass`
    pub interface_class: u8,
+
+   /// `bInterfaceSubClass`
    pub interface_subclass: u8,
+
+   /// `bInterfaceProtocol`
    pub interface_protocol: u8,
+
+   /// Always 0
    pub padding: u8,
 }
 
 impl OpInterfaceDescriptor {
-   fn to_array(&self) -> [u8; 4] {
+   /// Encodes the descriptor.
+   pub fn to_array(&self) -> [u8; 4] {
       [
          self.interface_class,
          self.interface_subclass,
@@ -236,4 +655,17 @@ impl OpInterfaceDescriptor {
          self.padding,
       ]
    }
+
+   /// Parses the descriptor from the first 4 bytes of `data`.
+   ///
+   /// # Panics
+   /// If `data` is shorter than 4 bytes.
+   pub fn from_slice(data: &[u8]) -> Self {
+      Self {
+         interface_class: data[0],
+         interface_subclass: data[1],
+         interface_protocol: data[2],
+         padding: data[3],
+      }
+   }
 }
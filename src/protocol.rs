@@ -0,0 +1,42 @@
+//! The messages of the USBIP protocol, as they are sent over the wire.
+//!
+//! These are the same definitions, the device uses to talk to the host. Every message can be
+//! encoded as well as decoded, so they also serve the other end of the connection,
+//! e.g. test clients, proxies or tools, that analyze a captured stream.
+//!
+//! The op messages are exchanged, before a device is imported: [`OpRequest`] and
+//! [`OpResponse`] list and import the devices, [`OpExportRequest`] and [`OpExportReply`]
+//! push a device to a host. Afterwards, the URBs are submitted as [`UsbIpRequest`]s and
+//! completed as [`UsbIpResponse`]s.
+//!
+//! With the `serde` feature, all the messages implement `Serialize` and `Deserialize`.
+//!
+//! # Example
+//! ```
+//! use usbip_device::protocol::{OpHeader, OpRequest, USBIP_VERSION};
+//!
+//! let request = OpRequest::ConnectDevice(
+//!    OpHeader {
+//!       version: USBIP_VERSION,
+//!       command: 0x8003,
+//!       status: 0,
+//!    },
+//!    "1-1".to_string(),
+//! );
+//!
+//! let data = request.to_vec();
+//! assert_eq!(OpRequest::decode(&data).unwrap(), Some((request, 40)));
+//! ```
+
+pub use crate::{
+   cmd::{Direction, TransferFlags, UsbCmd, UsbIpHeader, UsbIpIsoPacketDescriptor},
+   limits::MAX_ISO_PACKETS,
+   op::{
+      is_supported_version, OpDevice, OpDeviceDescriptor, OpExportReply, OpExportRequest, OpHeader,
+      OpInterfaceDescriptor, OpRequest, OpResponse, OpResponseCommand, OpStatus, SUPPORTED_VERSIONS,
+      USBIP_VERSION,
+   },
+   request::{UsbIpCmdSubmit, UsbIpCmdUnlink, UsbIpRequest, UsbIpRequestCmd},
+   response::{UsbIpResponse, UsbIpResponseCmd, UsbIpRetSubmit, UsbIpRetUnlink},
+   status::UrbStatus,
+};
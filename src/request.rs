@@ -1,20 +1,31 @@
 use crate::{
-   cmd::{Direction, TransferFlags, UsbCmd, UsbIpHeader},
+   cmd::{Direction, TransferFlags, UsbCmd, UsbIpHeader, UsbIpIsoPacketDescriptor},
    debug::{DbgBuf, DbgEmpty},
+   limits::MAX_ISO_PACKETS,
    UsbIpError,
 };
-use std::{
+use alloc::vec::Vec;
+use core::{
    convert::TryInto,
    fmt::{Debug, Formatter, Result as FmtResult},
-   io::{Error, ErrorKind, Read},
-   net::TcpStream,
 };
 
-#[derive(Clone)]
+/// A CMD message, the host sends once it has imported a device.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, PartialEq, Eq)]
 pub struct UsbIpRequest {
+   /// The header, the device answers with the same sequence number
    pub header: UsbIpHeader,
+
+   /// The part, that depends on the command
    pub cmd: UsbIpRequestCmd,
+
+   /// The data of an OUT URB
    pub data: Vec<u8>,
+
+   /// The packets of an isochronous URB
+   pub iso_packets: Vec<UsbIpIsoPacketDescriptor>,
 }
 
 impl Debug for UsbIpRequest {
@@ -23,94 +34,146 @@ impl Debug for UsbIpRequest {
          .field("header", &self.header)
          .field("cmd", &self.cmd)
          .field("data", &DbgBuf(&self.data))
+         .field("iso_packets", &self.iso_packets)
          .finish()
    }
 }
 
-#[derive(Debug, Clone)]
+/// The part of a [`UsbIpRequest`], that depends on its command.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum UsbIpRequestCmd {
+   /// `USBIP_CMD_SUBMIT`
    Cmd(UsbIpCmdSubmit),
+
+   /// `USBIP_CMD_UNLINK`
    Unlink(UsbIpCmdUnlink),
 }
 
 impl UsbIpRequest {
-   pub fn read(reader: &mut TcpStream) -> Result<Self, Error> {
-      // Read an parse header
-      reader.set_nonblocking(true)?;
-      let mut buf = [0; 48];
-      match reader.read(&mut buf) {
-         Ok(bytes_read) if bytes_read == 48 => (),
-         Ok(0) => {
-            return Err(Error::new(
-               ErrorKind::NotConnected,
-               Box::new(UsbIpError::ConnectionClosed),
-            ))
-         }
-         Ok(bytes_read) => {
-            return Err(Error::new(
-               ErrorKind::InvalidInput,
-               Box::new(UsbIpError::PkgTooShort(bytes_read)),
-            ))
-         }
-         Err(err) => return Err(err),
+   /// Decodes a request from the beginning of `data`.
+   ///
+   /// The data of an OUT URB must not be longer than `max_transfer_length`.
+   ///
+   /// # Returns
+   /// - `Ok(Some((request, len)))` if a request of `len` bytes was decoded
+   /// - `Ok(None)` if `data` does not yet contain the complete request
+   pub fn decode(data: &[u8], max_transfer_length: usize) -> Result<Option<(Self, usize)>, UsbIpError> {
+      // Parse the header
+      if data.len() < 48 {
+         return Ok(None);
       }
-      reader.set_nonblocking(false)?;
 
-      let header = UsbIpHeader::from_slice(&buf[0..20]);
+      let header = UsbIpHeader::from_slice(&data[0..20])?;
       match header.command {
          UsbCmd::Request => {
-            let cmd = UsbIpCmdSubmit::from_slice(&buf[20..48]);
+            let cmd = UsbIpCmdSubmit::from_slice(&data[20..48]);
 
             // Receive the URB if this is a OUT packet
-            let data = if header.direction == Direction::OUT && cmd.transfer_buffer_length != 0 {
-               // NOTE: Reading 0 bytes would still block the reader
-               let mut data = vec![0; cmd.transfer_buffer_length as usize];
-               reader.read_exact(&mut data)?;
-
-               data
+            // Since the length determines the framing, we can not go on with an invalid one
+            let data_len = if header.direction == Direction::OUT {
+               match cmd.transfer_buffer_length {
+                  len if len < 0 || len as usize > max_transfer_length => {
+                     return Err(UsbIpError::InvalidLength(len))
+                  }
+                  len => len as usize,
+               }
             } else {
-               vec![]
+               0
             };
 
-            //Ok(Self::Cmd(header, cmd, data))
-            Ok(Self {
-               header,
-               cmd: UsbIpRequestCmd::Cmd(cmd),
-               data,
-            })
+            // Isochronous URBs are followed by the descriptors of their packets
+            let number_of_packets = cmd.number_of_packets.max(0) as usize;
+            if number_of_packets > MAX_ISO_PACKETS {
+               return Err(UsbIpError::InvalidLength(cmd.number_of_packets));
+            }
+            let iso_offset = 48 + data_len;
+            let iso_len = number_of_packets * 16;
+
+            if data.len() < iso_offset + iso_len {
+               return Ok(None);
+            }
+
+            let iso_packets = data[iso_offset..iso_offset + iso_len]
+               .chunks(16)
+               .map(UsbIpIsoPacketDescriptor::from_slice)
+               .collect();
+
+            Ok(Some((
+               Self {
+                  header,
+                  cmd: UsbIpRequestCmd::Cmd(cmd),
+                  data: data[48..iso_offset].to_vec(),
+                  iso_packets,
+               },
+               iso_offset + iso_len,
+            )))
          }
          UsbCmd::UnlinkRequest => {
-            let unlink = UsbIpCmdUnlink::from_slice(&buf[20..24]);
+            let unlink = UsbIpCmdUnlink::from_slice(&data[20..24]);
 
             // NOTE: We do not expect to see urb data behind an unlink
 
-            Ok(Self {
-               header,
-               cmd: UsbIpRequestCmd::Unlink(unlink),
-               data: vec![],
-            })
+            Ok(Some((
+               Self {
+                  header,
+                  cmd: UsbIpRequestCmd::Unlink(unlink),
+                  data: vec![],
+                  iso_packets: vec![],
+               },
+               48,
+            )))
          }
-         _ => Err(Error::new(
-            ErrorKind::InvalidInput,
-            Box::new(UsbIpError::InvalidCommand(header.command as u16)),
-         )),
+         _ => Err(UsbIpError::InvalidCommand(header.command.to_u32())),
+      }
+   }
+
+   /// Encodes the request, as the host sends it.
+   pub fn to_vec(&self) -> Vec<u8> {
+      let mut result = vec![];
+
+      result.extend_from_slice(&self.header.to_array());
+      match self.cmd {
+         UsbIpRequestCmd::Cmd(ref cmd) => result.extend_from_slice(&cmd.to_array()),
+         UsbIpRequestCmd::Unlink(ref unlink) => result.extend_from_slice(&unlink.to_array()),
+      }
+
+      // Only OUT URBs carry data, isochronous ones end with the descriptors of their packets
+      result.extend_from_slice(&self.data);
+      for packet in self.iso_packets.iter() {
+         result.extend_from_slice(&packet.to_array());
       }
+
+      result
    }
 }
 
-#[derive(Clone)]
+/// The fields of `USBIP_CMD_SUBMIT`, that follow the header.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, PartialEq, Eq)]
 pub struct UsbIpCmdSubmit {
+   /// The flags of the URB
    pub transfer_flags: TransferFlags,
+
+   /// The number of bytes, the host expects or sends
    pub transfer_buffer_length: i32,
+
+   /// The frame of the first packet of an isochronous URB
    pub start_frame: i32,
+
+   /// The number of packets of an isochronous URB, 0 or -1 for the other URBs
    pub number_of_packets: i32,
+
+   /// The interval of an interrupt or isochronous endpoint
    pub interval: i32,
+
+   /// The setup packet of a control transfer
    pub setup: [u8; 8],
 }
 
 impl Debug for UsbIpCmdSubmit {
-   /// As `start_frame`, `number_of_packets` and `interval` are unused as of now,
-   /// they are not being printed
    fn fmt(&self, f: &mut Formatter) -> FmtResult {
       // Only output setup bytes, if they are relevant
       let setup_dbg = DbgBuf(&self.setup);
@@ -123,12 +186,28 @@ impl Debug for UsbIpCmdSubmit {
       f.debug_struct("UsbIpCmdSubmit")
          .field("transfer_flags", &self.transfer_flags)
          .field("transfer_buffer_length", &self.transfer_buffer_length)
+         .field("start_frame", &self.start_frame)
+         .field("number_of_packets", &self.number_of_packets)
+         .field("interval", &self.interval)
          .field("setup", &setup)
          .finish()
    }
 }
 
 impl UsbIpCmdSubmit {
+   fn to_array(&self) -> [u8; 28] {
+      let mut result = [0; 28];
+
+      result[0..4].copy_from_slice(&self.transfer_flags.bits().to_be_bytes());
+      result[4..8].copy_from_slice(&self.transfer_buffer_length.to_be_bytes());
+      result[8..12].copy_from_slice(&self.start_frame.to_be_bytes());
+      result[12..16].copy_from_slice(&self.number_of_packets.to_be_bytes());
+      result[16..20].copy_from_slice(&self.interval.to_be_bytes());
+      result[20..28].copy_from_slice(&self.setup);
+
+      result
+   }
+
    fn from_slice(data: &[u8]) -> Self {
       Self {
          transfer_flags: TransferFlags::from_bits_truncate(u32::from_be_bytes(
@@ -143,12 +222,22 @@ impl UsbIpCmdSubmit {
    }
 }
 
-#[derive(Debug, Clone)]
+/// The fields of `USBIP_CMD_UNLINK`, that follow the header.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UsbIpCmdUnlink {
+   /// The sequence number of the URB, that is cancelled
    pub seqnum: u32,
 }
 
 impl UsbIpCmdUnlink {
+   fn to_array(&self) -> [u8; 28] {
+      let mut result = [0; 28];
+      result[0..4].copy_from_slice(&self.seqnum.to_be_bytes());
+      result
+   }
+
    fn from_slice(data: &[u8]) -> Self {
       Self {
          seqnum: u32::from_be_bytes(data[0..4].try_into().unwrap()),
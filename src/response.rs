@@ -1,11 +1,34 @@
-use crate::{cmd::UsbIpHeader, debug::DbgBuf};
-use std::fmt::{Debug, Formatter, Result as FmtResult};
+use crate::{
+   cmd::{Direction, UsbCmd, UsbIpHeader, UsbIpIsoPacketDescriptor},
+   debug::DbgBuf,
+   limits::MAX_ISO_PACKETS,
+   status::UrbStatus,
+   UsbIpError,
+};
+use alloc::vec::Vec;
+use core::{
+   convert::TryInto,
+   fmt::{Debug, Formatter, Result as FmtResult},
+};
 
-#[derive(Clone)]
+/// A RET message, the device answers a [`UsbIpRequest`] with.
+///
+/// [`UsbIpRequest`]: crate::protocol::UsbIpRequest
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, PartialEq, Eq)]
 pub struct UsbIpResponse {
+   /// The header, its sequence number is the one of the request
    pub header: UsbIpHeader,
+
+   /// The part, that depends on the command
    pub cmd: UsbIpResponseCmd,
+
+   /// The data of an IN URB
    pub data: Vec<u8>,
+
+   /// The packets of an isochronous URB
+   pub iso_packets: Vec<UsbIpIsoPacketDescriptor>,
 }
 
 impl Debug for UsbIpResponse {
@@ -14,18 +37,182 @@ impl Debug for UsbIpResponse {
          .field("header", &self.header)
          .field("cmd", &self.cmd)
          .field("data", &DbgBuf(&self.data))
+         .field("iso_packets", &self.iso_packets)
          .finish()
    }
 }
 
-#[derive(Debug, Clone)]
+/// The part of a [`UsbIpResponse`], that depends on its command.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum UsbIpResponseCmd {
+   /// `USBIP_RET_SUBMIT`
    Cmd(UsbIpRetSubmit),
+
+   /// `USBIP_RET_UNLINK`
    Unlink(UsbIpRetUnlink),
 }
 
 impl UsbIpResponse {
-   pub fn to_vec(&self) -> Option<Vec<u8>> {
+   /// Creates the RET_SUBMIT, that completes the URB submitted with `header`.
+   pub fn ret_submit(
+      header: &UsbIpHeader,
+      devid: u32,
+      status: UrbStatus,
+      actual_length: usize,
+      data: Vec<u8>,
+   ) -> Self {
+      Self {
+         header: UsbIpHeader {
+            command: UsbCmd::Response,
+            seqnum: header.seqnum,
+            devid,
+            direction: header.direction,
+            ep: header.ep,
+         },
+         cmd: UsbIpResponseCmd::Cmd(UsbIpRetSubmit {
+            status,
+            actual_length: actual_length as i32,
+            start_frame: 0,
+            number_of_packets: 0,
+            error_count: 0,
+         }),
+         data,
+         iso_packets: vec![],
+      }
+   }
+
+   /// Creates the RET_SUBMIT, that completes the isochronous URB submitted with `header`.
+   ///
+   /// For IN transfers, `data` contains the received packets back to back,
+   /// the host spreads them to their offsets using `iso_packets`.
+   pub fn ret_submit_iso(
+      header: &UsbIpHeader,
+      devid: u32,
+      status: UrbStatus,
+      start_frame: u32,
+      iso_packets: Vec<UsbIpIsoPacketDescriptor>,
+      data: Vec<u8>,
+   ) -> Self {
+      let actual_length = iso_packets
+         .iter()
+         .map(|packet| packet.actual_length as i32)
+         .sum();
+      let error_count = iso_packets
+         .iter()
+         .filter(|packet| packet.status != 0)
+         .count();
+
+      Self {
+         header: UsbIpHeader {
+            command: UsbCmd::Response,
+            seqnum: header.seqnum,
+            devid,
+            direction: header.direction,
+            ep: header.ep,
+         },
+         cmd: UsbIpResponseCmd::Cmd(UsbIpRetSubmit {
+            status,
+            actual_length,
+            start_frame: start_frame as i32,
+            number_of_packets: iso_packets.len() as i32,
+            error_count: error_count as i32,
+         }),
+         data,
+         iso_packets,
+      }
+   }
+
+   /// Creates the RET_UNLINK, that answers the CMD_UNLINK sent with `header`.
+   pub fn ret_unlink(header: &UsbIpHeader, devid: u32, status: UrbStatus) -> Self {
+      Self {
+         header: UsbIpHeader {
+            command: UsbCmd::UnlinkResponse,
+            seqnum: header.seqnum,
+            devid,
+            direction: header.direction,
+            ep: header.ep,
+         },
+         cmd: UsbIpResponseCmd::Unlink(UsbIpRetUnlink {
+            status,
+         }),
+         data: vec![],
+         iso_packets: vec![],
+      }
+   }
+
+   /// Decodes a response from the beginning of `data`.
+   ///
+   /// The data of an IN URB must not be longer than `max_transfer_length`.
+   ///
+   /// # Returns
+   /// - `Ok(Some((response, len)))` if a response of `len` bytes was decoded
+   /// - `Ok(None)` if `data` does not yet contain the complete response
+   pub fn decode(data: &[u8], max_transfer_length: usize) -> Result<Option<(Self, usize)>, UsbIpError> {
+      if data.len() < 48 {
+         return Ok(None);
+      }
+
+      let header = UsbIpHeader::from_slice(&data[0..20])?;
+      match header.command {
+         UsbCmd::Response => {
+            let cmd = UsbIpRetSubmit::from_slice(&data[20..48]);
+
+            // Only IN URBs carry data back to the host
+            // Since the length determines the framing, we can not go on with an invalid one
+            let data_len = if header.direction == Direction::IN {
+               match cmd.actual_length {
+                  len if len < 0 || len as usize > max_transfer_length => {
+                     return Err(UsbIpError::InvalidLength(len))
+                  }
+                  len => len as usize,
+               }
+            } else {
+               0
+            };
+
+            let number_of_packets = cmd.number_of_packets.max(0) as usize;
+            if number_of_packets > MAX_ISO_PACKETS {
+               return Err(UsbIpError::InvalidLength(cmd.number_of_packets));
+            }
+            let iso_offset = 48 + data_len;
+            let iso_len = number_of_packets * 16;
+
+            if data.len() < iso_offset + iso_len {
+               return Ok(None);
+            }
+
+            let iso_packets = data[iso_offset..iso_offset + iso_len]
+               .chunks(16)
+               .map(UsbIpIsoPacketDescriptor::from_slice)
+               .collect();
+
+            Ok(Some((
+               Self {
+                  header,
+                  cmd: UsbIpResponseCmd::Cmd(cmd),
+                  data: data[48..iso_offset].to_vec(),
+                  iso_packets,
+               },
+               iso_offset + iso_len,
+            )))
+         }
+         UsbCmd::UnlinkResponse => Ok(Some((
+            Self {
+               header,
+               cmd: UsbIpResponseCmd::Unlink(UsbIpRetUnlink::from_slice(&data[20..48])),
+               data: vec![],
+               iso_packets: vec![],
+            },
+            48,
+         ))),
+         _ => Err(UsbIpError::InvalidCommand(header.command.to_u32())),
+      }
+   }
+
+   /// Encodes the response, as the device sends it.
+   pub fn to_vec(&self) -> Vec<u8> {
       let mut result = vec![];
 
       // Parse the header
@@ -44,27 +231,50 @@ impl UsbIpResponse {
       // parse the data
       result.extend_from_slice(&self.data[..]);
 
-      Some(result)
+      // isochronous transfers end with the descriptors of their packets
+      for packet in self.iso_packets.iter() {
+         result.extend_from_slice(&packet.to_array());
+      }
+
+      result
    }
 }
 
-#[derive(Clone)]
+/// The fields of `USBIP_RET_SUBMIT`, that follow the header.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, PartialEq, Eq)]
 pub struct UsbIpRetSubmit {
-   pub status: i32,
+   /// The status, the URB completed with
+   pub status: UrbStatus,
+
+   /// The number of bytes, that were transferred
    pub actual_length: i32,
+
+   /// The frame of the first packet of an isochronous URB
    pub start_frame: i32,
+
+   /// The number of packets of an isochronous URB, 0 for the other URBs
    pub number_of_packets: i32,
+
+   /// The number of packets of an isochronous URB, that failed
    pub error_count: i32,
 }
 
 impl Debug for UsbIpRetSubmit {
-   /// As `start_frame`, `number_of_packets` and `error_count` are unused as of now,
-   /// they are not being printed
+   /// The isochronous fields are only printed for isochronous transfers
    fn fmt(&self, f: &mut Formatter) -> FmtResult {
-      f.debug_struct("UsbIpRetSubmit")
-         .field("status", &self.status)
-         .field("actual_length", &self.actual_length)
-         .finish()
+      let mut dbg = f.debug_struct("UsbIpRetSubmit");
+      dbg.field("status", &self.status)
+         .field("actual_length", &self.actual_length);
+
+      if self.number_of_packets > 0 {
+         dbg.field("start_frame", &self.start_frame)
+            .field("number_of_packets", &self.number_of_packets)
+            .field("error_count", &self.error_count);
+      }
+
+      dbg.finish()
    }
 }
 
@@ -72,7 +282,7 @@ impl UsbIpRetSubmit {
    fn to_array(&self) -> [u8; 28] {
       let mut result = [0; 28];
 
-      result[0..4].copy_from_slice(&self.status.to_be_bytes());
+      result[0..4].copy_from_slice(&self.status.to_errno().to_be_bytes());
       result[4..8].copy_from_slice(&self.actual_length.to_be_bytes());
       result[8..12].copy_from_slice(&self.start_frame.to_be_bytes());
       result[12..16].copy_from_slice(&self.number_of_packets.to_be_bytes());
@@ -80,17 +290,37 @@ impl UsbIpRetSubmit {
 
       result
    }
+
+   fn from_slice(data: &[u8]) -> Self {
+      Self {
+         status: UrbStatus::from_errno(i32::from_be_bytes(data[0..4].try_into().unwrap())),
+         actual_length: i32::from_be_bytes(data[4..8].try_into().unwrap()),
+         start_frame: i32::from_be_bytes(data[8..12].try_into().unwrap()),
+         number_of_packets: i32::from_be_bytes(data[12..16].try_into().unwrap()),
+         error_count: i32::from_be_bytes(data[16..20].try_into().unwrap()),
+      }
+   }
 }
 
-#[derive(Debug, Clone)]
+/// The fields of `USBIP_RET_UNLINK`, that follow the header.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UsbIpRetUnlink {
-   pub status: u32,
+   /// [`UrbStatus::Unlinked`], if the URB was cancelled, [`UrbStatus::Ok`], if it had already
+   /// completed
+   pub status: UrbStatus,
 }
 
 impl UsbIpRetUnlink {
    fn to_array(&self) -> [u8; 28] {
       let mut result = [0; 28];
-      result[0..4].copy_from_slice(&self.status.to_be_bytes());
+      result[0..4].copy_from_slice(&self.status.to_errno().to_be_bytes());
       result
    }
+   fn from_slice(data: &[u8]) -> Self {
+      Self {
+         status: UrbStatus::from_errno(i32::from_be_bytes(data[0..4].try_into().unwrap())),
+      }
+   }
 }
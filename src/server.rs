@@ -0,0 +1,340 @@
+#[cfg(feature = "std")]
+use crate::{
+   export::Export,
+   listener::Stream,
+   stdio::StdioStream,
+   USBIP_PORT,
+};
+use crate::{
+   handler::SocketHandler,
+   limits::Limits,
+   session::UsbIpSession,
+   sync::{Mutex, MutexGuard},
+   UsbIpBus, UsbIpBusInner, UsbIpError, UsbSpeed,
+};
+use alloc::{
+   boxed::Box,
+   collections::VecDeque,
+   string::String,
+   sync::{Arc, Weak},
+   vec::Vec,
+};
+use core::{fmt, net::SocketAddr};
+#[cfg(feature = "std")]
+use std::{
+   io::{Read, Write},
+   net::Ipv4Addr,
+};
+#[cfg(all(feature = "std", unix))]
+use std::path::{Path, PathBuf};
+
+/// The number of the simulated USB bus, all devices are attached to.
+const BUSNUM: u32 = 1;
+
+/// The number of errors, that are kept for [`UsbIpServer::take_errors`].
+const MAX_ERRORS: usize = 64;
+
+/// A USBIP server, which can export multiple [`UsbIpBus`] devices.
+///
+/// Every bus, that is registered at the server, gets its own bus id (`1-1`, `1-2`, ...),
+/// under which it can be imported by the host.
+///
+/// The server is driven by polling any of the registered buses.
+///
+/// # Example
+/// ```no_run
+/// use usbip_device::{UsbIpBusBuilder, UsbIpServer};
+///
+/// let server = UsbIpServer::bind(([127, 0, 0, 1], 3240)).unwrap();
+///
+/// // Exported as `1-1` and `1-2`
+/// let bus1 = UsbIpBusBuilder::new().server(&server).build().unwrap();
+/// let bus2 = UsbIpBusBuilder::new().server(&server).build().unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct UsbIpServer(Arc<Mutex<UsbIpServerInner>>);
+
+#[derive(Debug)]
+pub(crate) struct UsbIpServerInner {
+   pub handler: SocketHandler,
+   #[cfg(feature = "std")]
+   pub exports: Vec<Export>,
+   devices: Vec<ExportedDevice>,
+   next_devnum: u32,
+   errors: VecDeque<UsbIpError>,
+   unreported: Vec<UsbIpError>,
+   error_handler: Option<Arc<Mutex<ErrorHandler>>>,
+}
+
+/// The callback, that is invoked for every error, the server recovers from.
+struct ErrorHandler(Box<dyn FnMut(&UsbIpError) + Send>);
+
+impl fmt::Debug for ErrorHandler {
+   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      f.write_str("ErrorHandler")
+   }
+}
+
+#[derive(Debug)]
+struct ExportedDevice {
+   devid: u32,
+   bus_id: String,
+   bus: Weak<Mutex<UsbIpBusInner>>,
+}
+
+impl UsbIpServer {
+   /// Create a new [`UsbIpServer`], listening on `127.0.0.1:3240`.
+   ///
+   /// # Errors
+   /// If port 3240 is already in use.
+   #[cfg(feature = "std")]
+   pub fn new() -> Result<Self, UsbIpError> {
+      Self::bind((Ipv4Addr::LOCALHOST, USBIP_PORT))
+   }
+
+   /// Create a new [`UsbIpServer`], listening on `addr`.
+   ///
+   /// # Errors
+   /// If the socket could not be bound, e.g. because the port is already in use.
+   #[cfg(feature = "std")]
+   pub fn bind(addr: impl Into<SocketAddr>) -> Result<Self, UsbIpError> {
+      let handler = SocketHandler::new(addr.into())?;
+      Ok(Self::from_handler(handler))
+   }
+
+   /// Create a new [`UsbIpServer`], listening on the Unix domain socket at `path`.
+   ///
+   /// This avoids choosing a free port, when many devices run on the same machine.
+   /// A socket file, that was left behind by a crashed process, is replaced,
+   /// and the file is removed again, once the server is dropped.
+   ///
+   /// # Errors
+   /// If the socket could not be bound, e.g. because another server still listens on `path`.
+   ///
+   /// # Example
+   /// ```no_run
+   /// use usbip_device::{UsbIpBusBuilder, UsbIpServer};
+   ///
+   /// let server = UsbIpServer::bind_unix("/tmp/usbip-device.sock").unwrap();
+   /// let bus = UsbIpBusBuilder::new().server(&server).build().unwrap();
+   /// ```
+   #[cfg(all(feature = "std", unix))]
+   pub fn bind_unix(path: impl AsRef<Path>) -> Result<Self, UsbIpError> {
+      let handler = SocketHandler::bind_unix(path.as_ref())?;
+      Ok(Self::from_handler(handler))
+   }
+
+   /// Create a new [`UsbIpServer`], that does not listen for connections.
+   ///
+   /// The devices of this server can only be reached through the sessions,
+   /// that are opened with [`UsbIpServer::open_session`].
+   pub fn unbound() -> Self {
+      Self::from_handler(SocketHandler::unbound())
+   }
+
+   fn from_handler(handler: SocketHandler) -> Self {
+      Self(Arc::new(Mutex::new(UsbIpServerInner {
+         handler,
+         #[cfg(feature = "std")]
+         exports: vec![],
+         devices: vec![],
+         next_devnum: 1,
+         errors: VecDeque::new(),
+         unreported: vec![],
+         error_handler: None,
+      })))
+   }
+
+   /// Returns the address, this server is listening on.
+   ///
+   /// Returns `None`, if the server does not listen on TCP, i.e. it was created by
+   /// [`UsbIpServer::unbound`] or [`UsbIpServer::bind_unix`].
+   pub fn local_addr(&self) -> Option<SocketAddr> {
+      self.lock().handler.local_addr()
+   }
+
+   /// Returns the path of the Unix domain socket, this server is listening on.
+   ///
+   /// Returns `None`, unless the server was created by [`UsbIpServer::bind_unix`].
+   #[cfg(all(feature = "std", unix))]
+   pub fn path(&self) -> Option<PathBuf> {
+      self.lock().handler.path()
+   }
+
+   /// Opens a new session, whose bytes are passed in and out by the user.
+   ///
+   /// The session starts out like a freshly accepted TCP connection,
+   /// i.e. the host can list and import the devices of this server.
+   pub fn open_session(&self) -> UsbIpSession {
+      let id = self.lock().open_session();
+      UsbIpSession::new(self.clone(), id)
+   }
+
+   /// Serves a host, that is connected through `reader` and `writer`.
+   ///
+   /// The host can list and import the devices of this server, like over a TCP connection,
+   /// which is still accepted next to it. Once either of the streams is closed, the
+   /// imported device is detached. Reading and writing block on separate threads,
+   /// so any pair of streams can be used, e.g. the pipes of a subprocess.
+   ///
+   /// A blocking read can not be interrupted, so the thread, that reads `reader`, outlives
+   /// the session, until its read returns: it ends on the next data, an error or the end of
+   /// the stream, and keeps `reader` until then.
+   #[cfg(feature = "std")]
+   pub fn serve_stream(
+      &self,
+      reader: impl Read + Send + 'static,
+      writer: impl Write + Send + 'static,
+   ) {
+      let stream = Stream::Stdio(StdioStream::new(reader, writer));
+      self.lock().handler.add_stream(stream);
+   }
+
+   /// Serves a host, that is connected through stdin and stdout.
+   ///
+   /// This allows running the device as a subprocess of `socat` or `ssh`, which tunnel the
+   /// USBIP connection to the host. See [`UsbIpServer::serve_stream`].
+   /// Since stdout carries the protocol, nothing else must be printed to it.
+   ///
+   /// After the session was closed, a thread stays blocked on stdin, until more input
+   /// arrives or stdin is closed. Thus stdin should not be read by anything else afterwards,
+   /// which is usually no issue, as the process exits together with its connection.
+   ///
+   /// # Example
+   /// ```no_run
+   /// use usbip_device::{UsbIpBusBuilder, UsbIpServer};
+   ///
+   /// // e.g. `socat TCP-LISTEN:3240,reuseaddr,fork EXEC:./device`
+   /// let server = UsbIpServer::unbound();
+   /// let bus = UsbIpBusBuilder::new().server(&server).build().unwrap();
+   /// server.serve_stdio();
+   /// ```
+   #[cfg(feature = "std")]
+   pub fn serve_stdio(&self) {
+      self.serve_stream(std::io::stdin(), std::io::stdout());
+   }
+
+   /// Sets a callback, that is invoked for every error, that occurs while polling.
+   ///
+   /// Errors caused by a connection, e.g. a malformed packet or a broken socket,
+   /// do not stop the server. Instead, the connection is closed, the imported device
+   /// returns to the listening state and the error is passed to this callback.
+   ///
+   /// The callback is invoked after the server was unlocked, so it may use the server
+   /// and the buses, e.g. call [`take_errors`](Self::take_errors).
+   pub fn set_error_handler(&self, handler: impl FnMut(&UsbIpError) + Send + 'static) {
+      self.lock().error_handler = Some(Arc::new(Mutex::new(ErrorHandler(Box::new(handler)))));
+   }
+
+   /// Returns the errors, that occured while polling since the last call.
+   ///
+   /// Only the last 64 errors are kept, so a server, whose errors are never taken,
+   /// does not fill up the memory.
+   pub fn take_errors(&self) -> Vec<UsbIpError> {
+      core::mem::take(&mut self.lock().errors).into()
+   }
+
+   /// Creates a new bus and exports it under the next free bus id.
+   pub(crate) fn add_bus(&self, speed: UsbSpeed, pipe_depth: usize, limits: Limits) -> UsbIpBus {
+      let mut inner = self.lock();
+
+      let devnum = inner.next_devnum;
+      inner.next_devnum += 1;
+
+      let devid = (BUSNUM << 16) | devnum;
+      let bus_id = format!("{}-{}", BUSNUM, devnum);
+      info!("exporting new device as {}", bus_id);
+
+      let bus = Arc::new(Mutex::new(UsbIpBusInner::new(
+         devid,
+         bus_id.clone(),
+         speed,
+         pipe_depth,
+         limits,
+      )));
+      inner.devices.push(ExportedDevice {
+         devid,
+         bus_id,
+         bus: Arc::downgrade(&bus),
+      });
+
+      UsbIpBus::from_parts(bus, self.clone())
+   }
+
+   /// Lets the bus with `devid` connect to the host at `addr` by itself.
+   #[cfg(feature = "std")]
+   pub(crate) fn add_export(&self, devid: u32, addr: SocketAddr) {
+      self.lock().exports.push(Export::new(devid, addr));
+   }
+
+   /// Handles the incoming traffic of all the exported devices.
+   pub(crate) fn poll(&self) {
+      self.lock().handle_socket();
+      self.dispatch_errors();
+   }
+
+   /// Passes the errors, that were reported while the server was locked, to the error handler.
+   fn dispatch_errors(&self) {
+      let (errors, handler) = {
+         let mut inner = self.lock();
+         (core::mem::take(&mut inner.unreported), inner.error_handler.clone())
+      };
+
+      if let Some(handler) = handler {
+         let ErrorHandler(ref mut handler) = *handler.lock();
+         for err in errors.iter() {
+            handler(err);
+         }
+      }
+   }
+
+   pub(crate) fn lock(&self) -> MutexGuard<'_, UsbIpServerInner> {
+      self.0.lock()
+   }
+}
+
+impl UsbIpServerInner {
+   /// Records an error, the server recovered from.
+   pub fn report(&mut self, err: UsbIpError) {
+      error!("{}", err);
+
+      // The error handler is invoked, once the lock is released
+      if self.error_handler.is_some() {
+         self.unreported.push(err.clone());
+      }
+      if self.errors.len() == MAX_ERRORS {
+         self.errors.pop_front();
+      }
+      self.errors.push_back(err);
+   }
+
+   /// Returns all the devices, that are still alive.
+   pub fn devices(&mut self) -> Vec<Arc<Mutex<UsbIpBusInner>>> {
+      // Drop the devices that no longer exist
+      self.devices.retain(|device| device.bus.strong_count() > 0);
+
+      self
+         .devices
+         .iter()
+         .filter_map(|device| device.bus.upgrade())
+         .collect()
+   }
+
+   /// Returns the device exported under `bus_id`.
+   pub fn device_by_bus_id(&self, bus_id: &str) -> Option<Arc<Mutex<UsbIpBusInner>>> {
+      self
+         .devices
+         .iter()
+         .find(|device| device.bus_id == bus_id)
+         .and_then(|device| device.bus.upgrade())
+   }
+
+   /// Returns the device with the device id `devid`.
+   pub fn device_by_devid(&self, devid: u32) -> Option<Arc<Mutex<UsbIpBusInner>>> {
+      self
+         .devices
+         .iter()
+         .find(|device| device.devid == devid)
+         .and_then(|device| device.bus.upgrade())
+   }
+}
@@ -0,0 +1,171 @@
+//! The USBIP protocol, independent of the transport it runs over.
+//!
+//! A session only ever deals with bytes: whatever the peer has sent is pushed into it,
+//! whatever has to go back is taken out of it. Reading from and writing to a socket is
+//! left to the transport, so the same state machine serves the TCP connections of the
+//! server as well as sessions, whose bytes are passed in by the user.
+
+use crate::{decoder::Decoder, handler::ConnectionState, server::UsbIpServer, UsbIpError};
+use alloc::{string::String, vec::Vec};
+
+/// The protocol state of a single connection.
+#[derive(Debug)]
+pub struct Session {
+   pub state: ConnectionState,
+
+   /// The peer, as it appears in the log
+   pub peer: String,
+
+   /// The device id of the device, that was imported over the session
+   pub devid: Option<u32>,
+
+   /// Set, once the device was attached to the peer, i.e. it has to be detached again
+   pub attached: bool,
+
+   /// The version of the protocol, the peer speaks, once it is known
+   pub version: Option<u16>,
+
+   /// The received bytes, that do not yet form a complete frame
+   pub decoder: Decoder,
+
+   /// The bytes, that wait to be sent to the peer
+   tx: Vec<u8>,
+
+   /// The number of bytes at the start of `tx`, that were already sent
+   sent: usize,
+}
+
+impl Session {
+   pub fn new(peer: String, state: ConnectionState, devid: Option<u32>) -> Self {
+      Self {
+         state,
+         peer,
+         devid,
+         attached: false,
+         version: None,
+         decoder: Decoder::default(),
+         tx: vec![],
+         sent: 0,
+      }
+   }
+
+   /// Appends bytes, that were received from the peer.
+   pub fn push(&mut self, data: &[u8]) {
+      self.decoder.push(data);
+   }
+
+   /// Queues `data` for sending to the peer.
+   pub fn send(&mut self, data: &[u8]) {
+      self.tx.extend_from_slice(data);
+   }
+
+   /// Returns the bytes, that wait to be sent.
+   pub fn pending(&self) -> &[u8] {
+      &self.tx[self.sent..]
+   }
+
+   /// Marks the first `len` pending bytes as sent.
+   ///
+   /// The sent bytes are only dropped, once they make up half of the buffer,
+   /// so a slow peer does not cause the rest of the buffer to be copied on every write.
+   #[cfg(feature = "std")]
+   pub fn consume(&mut self, len: usize) {
+      self.sent += len;
+      if self.sent * 2 >= self.tx.len() {
+         self.tx.drain(..self.sent);
+         self.sent = 0;
+      }
+   }
+
+   /// Removes all the bytes, that wait to be sent.
+   pub fn take(&mut self) -> Vec<u8> {
+      self.tx.drain(..self.sent);
+      self.sent = 0;
+      core::mem::take(&mut self.tx)
+   }
+}
+
+/// A USBIP session, whose transport is provided by the user.
+///
+/// The session behaves like a connection, a host has opened to the [`UsbIpServer`],
+/// but it does not read or write a socket itself. Instead, the bytes received from the
+/// host are passed to [`UsbIpSession::receive`] and the bytes to send to the host are
+/// returned by [`UsbIpSession::transmit`]. This way, the device can be attached over
+/// any byte stream, e.g. a serial line or a pipe.
+///
+/// Like a TCP connection, the session has to be driven by polling the device.
+/// The session is closed, when it is dropped.
+///
+/// # Example
+/// ```
+/// use usbip_device::{UsbIpBusBuilder, UsbIpServer};
+///
+/// let server = UsbIpServer::unbound();
+/// let bus = UsbIpBusBuilder::new().server(&server).build().unwrap();
+///
+/// // OP_REQ_DEVLIST
+/// let session = server.open_session();
+/// session.receive(&[0x01, 0x11, 0x80, 0x05, 0, 0, 0, 0]).unwrap();
+///
+/// // OP_REP_DEVLIST
+/// let reply = session.transmit();
+/// assert_eq!(&reply[..8], &[0x01, 0x11, 0x00, 0x05, 0, 0, 0, 0]);
+/// ```
+#[derive(Debug)]
+pub struct UsbIpSession {
+   server: UsbIpServer,
+   id: u64,
+}
+
+impl UsbIpSession {
+   pub(crate) fn new(server: UsbIpServer, id: u64) -> Self {
+      Self { server, id }
+   }
+
+   /// Passes bytes, that were received from the host, to the session.
+   ///
+   /// All the complete requests are handled right away, the bytes of a partial
+   /// request are kept until the rest arrives.
+   ///
+   /// # Errors
+   /// If the host has violated the protocol. The session is closed in this case.
+   pub fn receive(&self, data: &[u8]) -> Result<(), UsbIpError> {
+      self.server.lock().receive_session(self.id, data)
+   }
+
+   /// Returns the bytes, that have to be sent to the host.
+   pub fn transmit(&self) -> Vec<u8> {
+      self.server.lock().transmit_session(self.id)
+   }
+
+   /// Returns the bytes, that have to be sent to the host, unless the transport already buffers
+   /// too many bytes, that the host did not read.
+   ///
+   /// # Errors
+   /// [`UsbIpError::SendBufferFull`] if `buffered` exceeds the limit of the device.
+   /// The session is closed in this case.
+   #[cfg(feature = "smoltcp")]
+   pub(crate) fn transmit_within(&self, buffered: usize) -> Result<Vec<u8>, UsbIpError> {
+      self.server.lock().transmit_session_within(self.id, buffered)
+   }
+
+   /// Returns the version of the USBIP protocol, the host speaks.
+   ///
+   /// Returns `None`, until the host has sent its first request.
+   pub fn protocol_version(&self) -> Option<u16> {
+      self.server.lock().session_version(self.id)
+   }
+
+   /// Returns `true`, if the session was closed, e.g. because the device went away.
+   ///
+   /// The bytes, that were queued before, can still be taken with [`UsbIpSession::transmit`].
+   pub fn is_closed(&self) -> bool {
+      self.server.lock().is_session_closed(self.id)
+   }
+}
+
+impl Drop for UsbIpSession {
+   fn drop(&mut self) {
+      self.server.lock().close_session(self.id);
+   }
+}
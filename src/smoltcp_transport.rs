@@ -0,0 +1,131 @@
+//! A transport over a TCP socket of smoltcp, for devices, that bring their own network stack.
+//!
+//! Without `std`, there is no socket to listen on. Instead, the firmware adds a TCP socket
+//! to the socket set of its smoltcp interface and hands it to the transport. Once a host
+//! connects, the transport passes the bytes between the socket and a [`UsbIpSession`], so
+//! the host sees the same server as with the TCP listener of `std`.
+//! The socket serves one host at a time, once the connection is closed, it listens again.
+
+use crate::{UsbIpError, UsbIpServer, UsbIpSession};
+use alloc::vec::Vec;
+use smoltcp::{
+   iface::{SocketHandle, SocketSet},
+   socket::tcp,
+};
+
+/// Serves a [`UsbIpServer`] on a TCP socket of smoltcp.
+///
+/// # Example
+/// ```no_run
+/// use smoltcp::{iface::SocketSet, socket::tcp};
+/// use usbip_device::{SmoltcpTransport, UsbIpBusBuilder, UsbIpServer, USBIP_PORT};
+///
+/// let server = UsbIpServer::unbound();
+/// let bus = UsbIpBusBuilder::new().server(&server).build().unwrap();
+///
+/// let mut sockets = SocketSet::new(vec![]);
+/// let socket = tcp::Socket::new(
+///    tcp::SocketBuffer::new(vec![0; 4096]),
+///    tcp::SocketBuffer::new(vec![0; 4096]),
+/// );
+/// let mut transport = SmoltcpTransport::new(&server, sockets.add(socket), USBIP_PORT);
+///
+/// loop {
+///    // Poll the interface, then the transport, then the device
+///    transport.poll(&mut sockets).ok();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct SmoltcpTransport {
+   server: UsbIpServer,
+   handle: SocketHandle,
+   port: u16,
+
+   /// The session of the host, that is connected right now
+   session: Option<UsbIpSession>,
+
+   /// The bytes of the session, that did not fit into the send buffer of the socket yet
+   tx: Vec<u8>,
+}
+
+impl SmoltcpTransport {
+   /// Create a new transport, that serves `server` on the socket `handle`, listening on `port`.
+   pub fn new(server: &UsbIpServer, handle: SocketHandle, port: u16) -> Self {
+      Self {
+         server: server.clone(),
+         handle,
+         port,
+         session: None,
+         tx: vec![],
+      }
+   }
+
+   /// Passes the data, that was received on the socket, to the server and sends its replies.
+   ///
+   /// Call this every time after polling the interface. The device still has to be polled
+   /// as well, to handle the URBs of the host.
+   ///
+   /// # Errors
+   /// If the host has violated the protocol. The connection is closed in this case.
+   pub fn poll(&mut self, sockets: &mut SocketSet<'_>) -> Result<(), UsbIpError> {
+      let socket = sockets.get_mut::<tcp::Socket>(self.handle);
+
+      // Once a connection is over, wait for the next host
+      if !socket.is_open() {
+         self.session = None;
+         self.tx.clear();
+
+         if let Err(err) = socket.listen(self.port) {
+            warn!("failed to listen on port {}: {:?}", self.port, err);
+         }
+         return Ok(());
+      }
+
+      if self.session.is_none() {
+         // Still waiting for a host, or the last connection is not completely closed yet
+         if !socket.may_recv() {
+            return Ok(());
+         }
+
+         info!("new connection from {:?}", socket.remote_endpoint());
+         self.session = Some(self.server.open_session());
+      }
+      let session = self.session.as_ref().unwrap();
+
+      let mut result = Ok(());
+      while result.is_ok() && socket.can_recv() {
+         match socket.recv(|data| (data.len(), session.receive(data))) {
+            Ok(received) => result = received,
+            Err(_) => break,
+         }
+      }
+
+      // Whatever does not fit into the socket, is sent once the host has acknowledged more,
+      // a host, that does not acknowledge anything, must not fill up the memory
+      match session.transmit_within(self.tx.len()) {
+         Ok(data) => self.tx.extend_from_slice(&data),
+         Err(err) => {
+            warn!("connection on port {} aborted", self.port);
+            socket.abort();
+            self.session = None;
+            self.tx.clear();
+            return Err(err);
+         }
+      }
+      if !self.tx.is_empty() && socket.can_send() {
+         if let Ok(len) = socket.send_slice(&self.tx) {
+            self.tx.drain(..len);
+         }
+      }
+
+      // The host has closed the connection, or the device went away
+      if !socket.may_recv() || (session.is_closed() && self.tx.is_empty()) {
+         info!("connection on port {} closed", self.port);
+         socket.close();
+         self.session = None;
+         self.tx.clear();
+      }
+
+      result
+   }
+}
@@ -0,0 +1,142 @@
+use usb_device::{endpoint::EndpointType, Result as UsbResult, UsbError};
+
+/// The speed, the simulated device reports to the host.
+///
+/// The speed determines the rules, the endpoint configuration is validated against.
+///
+/// usb-device only supports control endpoints of up to 64 bytes, and most classes are written
+/// for full speed devices, i.e. their bulk endpoints have less than 512 bytes and their interrupt
+/// intervals count frames. At high, wireless and super speed, these are accepted with a warning,
+/// like the Linux host accepts such descriptors. Only endpoints, that can not work at the speed
+/// at all, e.g. bulk endpoints at low speed, are refused. usb-device panics in this case,
+/// when the class allocates the endpoint.
+///
+/// The default is high speed, for compatibility with older versions, which always reported
+/// high speed. Most microcontrollers are full speed devices.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UsbSpeed {
+   /// Low speed (1.5 Mbit/s)
+   Low,
+
+   /// Full speed (12 Mbit/s)
+   Full,
+
+   /// High speed (480 Mbit/s)
+   #[default]
+   High,
+
+   /// Wireless USB (480 Mbit/s)
+   Wireless,
+
+   /// Super speed (5 Gbit/s)
+   Super,
+}
+
+impl UsbSpeed {
+   /// Returns the value of the speed, as used by the USBIP protocol
+   /// (which matches `enum usb_device_speed` of the Linux kernel).
+   pub fn to_u32(self) -> u32 {
+      match self {
+         UsbSpeed::Low => 1,
+         UsbSpeed::Full => 2,
+         UsbSpeed::High => 3,
+         UsbSpeed::Wireless => 4,
+         UsbSpeed::Super => 5,
+      }
+   }
+
+   /// Checks, whether an endpoint with the given parameters is allowed at this speed.
+   ///
+   /// # Errors
+   /// [`UsbError::Unsupported`] if the endpoint violates the rules of the USB specification.
+   pub(crate) fn validate_endpoint(
+      self,
+      ty: EndpointType,
+      max_packet_size: u16,
+      interval: u8,
+   ) -> UsbResult<()> {
+      use EndpointType::*;
+      use UsbSpeed::*;
+
+      let max_packet_size_ok = match (self, ty) {
+         (Low, Control) => max_packet_size == 8,
+         // usb-device can not express larger control endpoints
+         (_, Control) => matches!(max_packet_size, 8 | 16 | 32 | 64),
+
+         (Low, Bulk) | (Low, Isochronous) => false,
+         (Full, Bulk) => matches!(max_packet_size, 8 | 16 | 32 | 64),
+         (High, Bulk) | (Wireless, Bulk) => {
+            max_packet_size.is_power_of_two() && (8..=512).contains(&max_packet_size)
+         }
+         (Super, Bulk) => max_packet_size.is_power_of_two() && (8..=1024).contains(&max_packet_size),
+
+         (Low, Interrupt) => (1..=8).contains(&max_packet_size),
+         (Full, Interrupt) => (1..=64).contains(&max_packet_size),
+         (_, Interrupt) => (1..=1024).contains(&max_packet_size),
+
+         (Full, Isochronous) => max_packet_size <= 1023,
+         (Wireless, Isochronous) => max_packet_size <= 3584,
+         (_, Isochronous) => max_packet_size <= 1024,
+      };
+
+      if !max_packet_size_ok {
+         error!(
+            "max packet size {} is not allowed for {:?} endpoints at {:?} speed",
+            max_packet_size,
+            ty,
+            self
+         );
+         return Err(UsbError::Unsupported);
+      }
+
+      // The sizes, the specification demands, but a full speed class does not use
+      let max_packet_size_expected = match (self, ty) {
+         (High, Control) => max_packet_size == 64,
+         (Wireless, Control) | (Super, Control) => false,
+         (High, Bulk) | (Wireless, Bulk) => max_packet_size == 512,
+         (Super, Bulk) => max_packet_size == 1024,
+         _ => true,
+      };
+
+      if !max_packet_size_expected {
+         warn!(
+            "max packet size {} is too small for {:?} endpoints at {:?} speed",
+            max_packet_size,
+            ty,
+            self
+         );
+      }
+
+      // Interrupt endpoints at low and full speed specify the interval in frames,
+      // all other periodic endpoints use an exponent
+      let interval_ok = match (self, ty) {
+         (Low, Interrupt) => (10..=255).contains(&interval),
+         (Full, Interrupt) => interval >= 1,
+         // The host clamps an interval, that counts frames, to the largest exponent
+         (_, Interrupt) => interval >= 1,
+         (_, Isochronous) => (1..=16).contains(&interval),
+         (_, Control) | (_, Bulk) => true,
+      };
+
+      if !interval_ok {
+         error!(
+            "interval {} is not allowed for {:?} endpoints at {:?} speed",
+            interval,
+            ty,
+            self
+         );
+         return Err(UsbError::Unsupported);
+      }
+
+      if ty == Interrupt && !matches!(self, Low | Full) && interval > 16 {
+         warn!(
+            "interval {} of an interrupt endpoint at {:?} speed is clamped to 16",
+            interval,
+            self
+         );
+      }
+
+      Ok(())
+   }
+}
@@ -0,0 +1,95 @@
+//! The status, a URB is completed with.
+//!
+//! USBIP reports the status of a URB the same way the Linux host controller drivers do,
+//! as a negative errno value. All the conditions of the simulated bus are mapped here.
+
+/// The condition, a URB is completed with.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrbStatus {
+   /// The transfer completed successfully
+   Ok,
+
+   /// The endpoint is halted, i.e. the device answered with a STALL handshake
+   Stall,
+
+   /// The device sent more data, than fits into the transfer buffer
+   Babble,
+
+   /// The transfer was shorter than requested, while `SHORT_NOT_OK` was set
+   ShortPacket,
+
+   /// The endpoint was not allocated by the device
+   NoEndpoint,
+
+   /// The device no longer exists
+   NoDevice,
+
+   /// The URB was unlinked by the host or aborted by a reset, before it completed
+   Unlinked,
+
+   /// Another control transfer is still in flight on the endpoint
+   Busy,
+
+   /// The frame of an isochronous packet passed, before it was scheduled
+   Missed,
+
+   /// The device was unplugged, while the URB was pending
+   Shutdown,
+
+   /// The URB itself is invalid, e.g. it has a negative transfer length
+   Invalid,
+
+   /// The URB exceeds the resources, the bus may use
+   NoMemory,
+
+   /// Any other errno, e.g. reported by a real device, whose responses are decoded
+   Other(i32),
+}
+
+impl UrbStatus {
+   /// Returns the value of the status field, as it is sent to the host.
+   pub fn to_errno(self) -> i32 {
+      match self {
+         UrbStatus::Ok => 0,
+         UrbStatus::Stall => -32,        // EPIPE
+         UrbStatus::Babble => -75,       // EOVERFLOW
+         UrbStatus::ShortPacket => -121, // EREMOTEIO
+         UrbStatus::NoEndpoint => -2,    // ENOENT
+         UrbStatus::NoDevice => -19,     // ENODEV
+         UrbStatus::Unlinked => -104,    // ECONNRESET
+         UrbStatus::Busy => -16,         // EBUSY
+         UrbStatus::Missed => -18,       // EXDEV
+         UrbStatus::Shutdown => -108,    // ESHUTDOWN
+         UrbStatus::Invalid => -22,      // EINVAL
+         UrbStatus::NoMemory => -12,     // ENOMEM
+         UrbStatus::Other(errno) => errno,
+      }
+   }
+
+   /// Parses the value of a status field.
+   ///
+   /// A value, that is none of the conditions of the simulated bus, is kept as [`UrbStatus::Other`],
+   /// so it is sent unchanged, when the status is encoded again.
+   pub fn from_errno(errno: i32) -> Self {
+      [
+         UrbStatus::Ok,
+         UrbStatus::Stall,
+         UrbStatus::Babble,
+         UrbStatus::ShortPacket,
+         UrbStatus::NoEndpoint,
+         UrbStatus::NoDevice,
+         UrbStatus::Unlinked,
+         UrbStatus::Busy,
+         UrbStatus::Missed,
+         UrbStatus::Shutdown,
+         UrbStatus::Invalid,
+         UrbStatus::NoMemory,
+      ]
+      .iter()
+      .copied()
+      .find(|status| status.to_errno() == errno)
+      .unwrap_or(UrbStatus::Other(errno))
+   }
+}
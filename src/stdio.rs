@@ -0,0 +1,108 @@
+//! A transport over any pair of a reader and a writer, e.g. stdin and stdout.
+//!
+//! This allows running the device as a subprocess, whose stdin and stdout are connected to
+//! the host, e.g. by `socat` or `ssh`. Unlike a socket, an arbitrary reader can not be
+//! switched to non-blocking mode, so reading and writing happen on separate threads,
+//! while the server keeps polling.
+//!
+//! The channels to the threads are bounded: a reader, whose data the server does not take,
+//! blocks, and a writer, that does not keep up, makes the server see a full socket.
+
+use std::{
+   io::{ErrorKind, Read, Result as IoResult, Write},
+   sync::mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError},
+};
+
+/// The number of chunks, that may wait in each direction.
+const DEPTH: usize = 16;
+
+/// The largest chunk, that is passed to the writer at once.
+const MAX_CHUNK: usize = 64 * 1024;
+
+/// The receiving and sending ends of the threads, that read and write the streams.
+#[derive(Debug)]
+pub struct StdioStream {
+   /// The chunks, the reader has read, it hangs up on the end of the stream
+   rx: Receiver<IoResult<Vec<u8>>>,
+
+   /// The rest of the last chunk, that did not fit into the buffer of the caller
+   buffer: Vec<u8>,
+
+   tx: SyncSender<Vec<u8>>,
+}
+
+impl StdioStream {
+   pub fn new(
+      mut reader: impl Read + Send + 'static,
+      mut writer: impl Write + Send + 'static,
+   ) -> Self {
+      let (reader_tx, rx) = mpsc::sync_channel(DEPTH);
+      std::thread::spawn(move || {
+         let mut buf = [0; 4096];
+         loop {
+            let result = match reader.read(&mut buf) {
+               Ok(0) => break,
+               Ok(len) => Ok(buf[..len].to_vec()),
+               Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+               Err(err) => Err(err),
+            };
+
+            // Stop, once the stream failed or the connection was closed
+            let failed = result.is_err();
+            if reader_tx.send(result).is_err() || failed {
+               break;
+            }
+         }
+      });
+
+      let (tx, writer_rx) = mpsc::sync_channel::<Vec<u8>>(DEPTH);
+      std::thread::spawn(move || {
+         // The data is sent right away, even if the writer is buffered, like stdout
+         for data in writer_rx {
+            if writer.write_all(&data).and_then(|_| writer.flush()).is_err() {
+               break;
+            }
+         }
+      });
+
+      Self {
+         rx,
+         buffer: vec![],
+         tx,
+      }
+   }
+}
+
+impl Read for StdioStream {
+   fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+      if self.buffer.is_empty() {
+         match self.rx.try_recv() {
+            Ok(result) => self.buffer = result?,
+            Err(TryRecvError::Empty) => return Err(ErrorKind::WouldBlock.into()),
+            Err(TryRecvError::Disconnected) => return Ok(0),
+         }
+      }
+
+      let len = usize::min(buf.len(), self.buffer.len());
+      buf[..len].copy_from_slice(&self.buffer[..len]);
+      self.buffer.drain(..len);
+      Ok(len)
+   }
+}
+
+impl Write for StdioStream {
+   fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+      let len = usize::min(buf.len(), MAX_CHUNK);
+      match self.tx.try_send(buf[..len].to_vec()) {
+         Ok(()) => Ok(len),
+         // Like a full socket, the rest is sent on one of the next polls
+         Err(TrySendError::Full(_)) => Err(ErrorKind::WouldBlock.into()),
+         // The writer thread has stopped after an error
+         Err(TrySendError::Disconnected(_)) => Err(ErrorKind::BrokenPipe.into()),
+      }
+   }
+
+   fn flush(&mut self) -> IoResult<()> {
+      Ok(())
+   }
+}
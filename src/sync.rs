@@ -0,0 +1,45 @@
+//! The lock, that guards the state shared by the buses and the server.
+//!
+//! With `std`, this is the mutex of the standard library. Without it, there is no
+//! scheduler to put a waiting thread to sleep, so a spin lock is used instead.
+
+use core::fmt::{Debug, Formatter, Result as FmtResult};
+
+#[cfg(feature = "std")]
+pub type MutexGuard<'a, T> = std::sync::MutexGuard<'a, T>;
+#[cfg(not(feature = "std"))]
+pub type MutexGuard<'a, T> = spin::MutexGuard<'a, T>;
+
+pub struct Mutex<T> {
+   #[cfg(feature = "std")]
+   inner: std::sync::Mutex<T>,
+   #[cfg(not(feature = "std"))]
+   inner: spin::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+   pub fn new(value: T) -> Self {
+      Self {
+         #[cfg(feature = "std")]
+         inner: std::sync::Mutex::new(value),
+         #[cfg(not(feature = "std"))]
+         inner: spin::Mutex::new(value),
+      }
+   }
+
+   /// Locks the mutex.
+   ///
+   /// A panic, while the lock was held, leaves the state inconsistent, so it is passed on.
+   pub fn lock(&self) -> MutexGuard<'_, T> {
+      #[cfg(feature = "std")]
+      return self.inner.lock().unwrap();
+      #[cfg(not(feature = "std"))]
+      return self.inner.lock();
+   }
+}
+
+impl<T: Debug> Debug for Mutex<T> {
+   fn fmt(&self, f: &mut Formatter) -> FmtResult {
+      self.inner.fmt(f)
+   }
+}
@@ -0,0 +1,40 @@
+//! The sockets, a bus is bound to by the builder.
+
+use super::*;
+use crate::UsbIpError;
+use std::net::{Ipv4Addr, Ipv6Addr, TcpListener};
+
+#[test]
+fn ephemeral_port() {
+   let first = UsbIpBusBuilder::new().port(0).build().unwrap();
+   let second = UsbIpBusBuilder::new().port(0).build().unwrap();
+
+   // Each bus gets a port of its own
+   let addr = first.local_addr().unwrap();
+   assert_eq!(addr.ip(), Ipv4Addr::LOCALHOST);
+   assert_ne!(addr.port(), 0);
+   assert_ne!(second.local_addr().unwrap().port(), addr.port());
+}
+
+#[test]
+fn ipv6() {
+   let mut device = Device::with_bus(UsbIpBusBuilder::new().address(Ipv6Addr::LOCALHOST));
+   let addr = device.bus.local_addr().unwrap();
+   assert_eq!(addr.ip(), Ipv6Addr::LOCALHOST);
+   assert_ne!(addr.port(), 0);
+
+   // The host connects to the bus over IPv6
+   let mut host = device.attach();
+   host.submit(0x81, 0, 64, [0; 8], &[]);
+   device.class.ep_in.write(&[1; 8]).unwrap();
+   assert_eq!(host.receive_submit(&mut device).data, [1; 8]);
+}
+
+#[test]
+fn port_in_use() {
+   let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+   let port = listener.local_addr().unwrap().port();
+
+   let result = UsbIpBusBuilder::new().port(port).build();
+   assert!(matches!(result, Err(UsbIpError::Io(ErrorKind::AddrInUse))));
+}
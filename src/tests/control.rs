@@ -0,0 +1,199 @@
+//! The stages of the control transfers on endpoint 0.
+//!
+//! The tests take the place of the device and drive endpoint 0 through the bus directly,
+//! such that every stage of a transfer can be observed.
+
+use super::*;
+use usb_device::{
+   bus::{PollResult, UsbBus},
+   endpoint::EndpointAddress,
+   UsbDirection, UsbError,
+};
+
+const GET_DESCRIPTOR_DEVICE: [u8; 8] = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00];
+const VENDOR_OUT: [u8; 8] = [0x41, 0x01, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00];
+const CLEAR_HALT_IN: [u8; 8] = [0x02, 0x01, 0x00, 0x00, 0x81, 0x00, 0x00, 0x00];
+
+fn ep0_in() -> EndpointAddress {
+   EndpointAddress::from_parts(0, UsbDirection::In)
+}
+
+fn ep0_out() -> EndpointAddress {
+   EndpointAddress::from_parts(0, UsbDirection::Out)
+}
+
+/// Polls the bus, until the SETUP packet of the next control transfer has arrived, and reads it.
+fn read_setup(device: &Device) -> Vec<u8> {
+   for _ in 0..200 {
+      if let PollResult::Data { ep_setup, .. } = device.usb_device.bus().poll() {
+         if ep_setup & 1 != 0 {
+            return read_packet(device).unwrap();
+         }
+      }
+      thread::sleep(Duration::from_millis(1));
+   }
+   panic!("no setup packet");
+}
+
+/// Reads the next packet from endpoint 0.
+fn read_packet(device: &Device) -> Option<Vec<u8>> {
+   let mut buf = [0; 64];
+   match device.usb_device.bus().read(ep0_out(), &mut buf) {
+      Ok(len) => Some(buf[..len].to_vec()),
+      Err(UsbError::WouldBlock) => None,
+      Err(err) => panic!("unexpected error {:?}", err),
+   }
+}
+
+fn write_packet(device: &Device, data: &[u8]) {
+   assert_eq!(device.usb_device.bus().write(ep0_in(), data).unwrap(), data.len());
+}
+
+/// Polls the bus without the device, until the next `USBIP_RET_SUBMIT` was received.
+fn receive(host: &mut Host, device: &Device) -> RetSubmit {
+   for _ in 0..200 {
+      device.usb_device.bus().poll();
+      host.read();
+      if let Some(ret) = host.decode_ret_submit() {
+         return ret;
+      }
+      thread::sleep(Duration::from_millis(1));
+   }
+   panic!("no response in {:02x?}", host.rx);
+}
+
+/// Polls the bus without the device for a while and checks, that the host received nothing.
+fn assert_idle(host: &mut Host, device: &Device) {
+   for _ in 0..20 {
+      device.usb_device.bus().poll();
+      thread::sleep(Duration::from_millis(1));
+   }
+   host.read();
+   assert!(host.rx.is_empty(), "unexpected data {:02x?}", host.rx);
+}
+
+#[test]
+fn control_in_ends_with_short_packet() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   let seqnum = host.submit(0x80, 0, 64, GET_DESCRIPTOR_DEVICE, &[]);
+   assert_eq!(read_setup(&device), GET_DESCRIPTOR_DEVICE);
+
+   write_packet(&device, &[1; 8]);
+   write_packet(&device, &[2; 8]);
+   assert_idle(&mut host, &device);
+   write_packet(&device, &[3; 2]);
+
+   // The URB completes, once the device has received the ZLP of the status stage
+   assert_idle(&mut host, &device);
+   assert_eq!(read_packet(&device), Some(vec![]));
+
+   let ret = receive(&mut host, &device);
+   assert_eq!(ret.seqnum, seqnum);
+   assert_eq!(ret.status, 0);
+   assert_eq!(ret.data, [[1; 8].to_vec(), [2; 8].to_vec(), [3; 2].to_vec()].concat());
+}
+
+#[test]
+fn control_in_ends_at_requested_length() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   host.submit(0x80, 0, 16, GET_DESCRIPTOR_DEVICE, &[]);
+   read_setup(&device);
+
+   // Two full packets fill the transfer buffer, no short packet is needed
+   write_packet(&device, &[1; 8]);
+   write_packet(&device, &[2; 8]);
+   assert_eq!(read_packet(&device), Some(vec![]));
+
+   let ret = receive(&mut host, &device);
+   assert_eq!(ret.status, 0);
+   assert_eq!(ret.actual_length, 16);
+}
+
+#[test]
+fn control_in_babble() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   host.submit(0x80, 0, 12, GET_DESCRIPTOR_DEVICE, &[]);
+   read_setup(&device);
+
+   // Only as much as fits into the transfer buffer is reported, there is no status stage
+   write_packet(&device, &[1; 8]);
+   write_packet(&device, &[2; 8]);
+   let ret = receive(&mut host, &device);
+   assert_eq!(ret.status, EOVERFLOW);
+   assert_eq!(ret.data, [[1; 8].to_vec(), [2; 4].to_vec()].concat());
+   assert_eq!(read_packet(&device), None);
+}
+
+#[test]
+fn control_out_spans_several_packets() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   let data: Vec<u8> = (0..20).collect();
+   let seqnum = host.submit(0x00, 0, 20, VENDOR_OUT, &data);
+   assert_eq!(read_setup(&device), VENDOR_OUT);
+
+   assert_eq!(read_packet(&device), Some(data[..8].to_vec()));
+   assert_eq!(read_packet(&device), Some(data[8..16].to_vec()));
+   assert_eq!(read_packet(&device), Some(data[16..].to_vec()));
+   assert_eq!(read_packet(&device), None);
+
+   // The URB completes, once the device has acknowledged the data with a ZLP
+   assert_idle(&mut host, &device);
+   write_packet(&device, &[]);
+
+   let ret = receive(&mut host, &device);
+   assert_eq!(ret.seqnum, seqnum);
+   assert_eq!(ret.status, 0);
+   assert_eq!(ret.actual_length, 20);
+   assert!(ret.data.is_empty());
+}
+
+#[test]
+fn second_setup_is_busy() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   let first = host.submit(0x80, 0, 64, GET_DESCRIPTOR_DEVICE, &[]);
+   read_setup(&device);
+   let second = host.submit(0x80, 0, 64, GET_DESCRIPTOR_DEVICE, &[]);
+
+   let ret = receive(&mut host, &device);
+   assert_eq!(ret.seqnum, second);
+   assert_eq!(ret.status, EBUSY);
+
+   // The transfer in flight is not disturbed
+   write_packet(&device, &[1; 2]);
+   assert_eq!(read_packet(&device), Some(vec![]));
+   let ret = receive(&mut host, &device);
+   assert_eq!(ret.seqnum, first);
+   assert_eq!(ret.status, 0);
+   assert_eq!(ret.data, [1; 2]);
+}
+
+#[test]
+fn tweak_is_applied_on_success_only() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+   let ep_in = device.class.ep_in.address();
+   device.class.ep_in.stall();
+
+   // The device refuses to clear the halt
+   host.submit(0x00, 0, 0, CLEAR_HALT_IN, &[]);
+   read_setup(&device);
+   device.usb_device.bus().set_stalled(ep0_in(), true);
+   assert_eq!(receive(&mut host, &device).status, EPIPE);
+   assert!(device.usb_device.bus().is_stalled(ep_in));
+
+   host.submit(0x00, 0, 0, CLEAR_HALT_IN, &[]);
+   read_setup(&device);
+   write_packet(&device, &[]);
+   assert_eq!(receive(&mut host, &device).status, 0);
+   assert!(!device.usb_device.bus().is_stalled(ep_in));
+}
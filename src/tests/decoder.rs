@@ -0,0 +1,72 @@
+//! The reassembly of frames, that arrive in pieces.
+
+use crate::decoder::Decoder;
+
+/// Encodes the `USBIP_CMD_SUBMIT` of an isochronous OUT URB, with a packet for each 8 bytes of `data`.
+///
+/// The data is followed by the descriptors of the packets.
+fn iso_out(seqnum: u32, data: &[u8]) -> Vec<u8> {
+   let number_of_packets = data.len() as u32 / 8;
+
+   let mut frame = vec![];
+   // command, seqnum, devid, direction and ep
+   for field in &[1, seqnum, 0x10001, 0, 2] {
+      frame.extend_from_slice(&field.to_be_bytes());
+   }
+   // transfer_flags, transfer_buffer_length, start_frame, number_of_packets and interval
+   for field in &[0x0002, data.len() as u32, 0, number_of_packets, 1] {
+      frame.extend_from_slice(&field.to_be_bytes());
+   }
+   frame.extend_from_slice(&[0; 8]);
+   frame.extend_from_slice(data);
+   // offset, length, actual_length and status of each packet
+   for index in 0..number_of_packets {
+      for field in &[index * 8, 8, 0, 0] {
+         frame.extend_from_slice(&field.to_be_bytes());
+      }
+   }
+   frame
+}
+
+#[test]
+fn frame_split_in_two() {
+   let data: Vec<u8> = (0..24).collect();
+   let frame = iso_out(1, &data);
+
+   // Every split point, including the ones inside the header and the iso descriptors
+   for split in 1..frame.len() {
+      let mut decoder = Decoder::default();
+      decoder.push(&frame[..split]);
+      assert!(decoder.decode_cmd(1024).unwrap().is_none(), "decoded at {}", split);
+
+      decoder.push(&frame[split..]);
+      let request = decoder.decode_cmd(1024).unwrap().unwrap();
+      assert_eq!(request.header.seqnum, 1);
+      assert_eq!(request.data, data, "split at {}", split);
+      assert_eq!(request.iso_packets.len(), 3, "split at {}", split);
+      assert!(decoder.is_empty());
+   }
+}
+
+#[test]
+fn frames_split_in_many_pieces() {
+   let data: Vec<u8> = (0..24).collect();
+   let mut frames = iso_out(1, &data);
+   let len = frames.len();
+   frames.extend(iso_out(2, &data));
+
+   // Inside the header, the command, the data, the iso descriptors and the next header
+   let splits = [0, 7, 30, 60, len - 20, len - 5, len + 10, frames.len()];
+
+   let mut decoder = Decoder::default();
+   let mut decoded = vec![];
+   for pieces in splits.windows(2) {
+      decoder.push(&frames[pieces[0]..pieces[1]]);
+      while let Some(request) = decoder.decode_cmd(1024).unwrap() {
+         decoded.push((pieces[1], request.header.seqnum, request.iso_packets.len()));
+      }
+   }
+
+   assert_eq!(decoded, vec![(len + 10, 1, 3), (frames.len(), 2, 3)]);
+   assert!(decoder.is_empty());
+}
@@ -0,0 +1,104 @@
+//! The export mode, in which the device connects to a waiting host.
+
+use super::*;
+use crate::UsbIpError;
+use std::net::TcpListener;
+
+/// Creates a device, that exports itself to the returned listener.
+fn exporting_device() -> (Device, TcpListener) {
+   let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+   listener.set_nonblocking(true).unwrap();
+   let device = Device::with_bus(UsbIpBusBuilder::new().export_to(listener.local_addr().unwrap()));
+   (device, listener)
+}
+
+/// Polls the device, until it has connected to `listener`, and receives its export request.
+fn accept(device: &mut Device, listener: &TcpListener) -> Host {
+   for _ in 0..200 {
+      device.poll();
+      match listener.accept() {
+         Ok((stream, _)) => {
+            let mut host = Host::new(stream);
+
+            // OP_REQ_EXPORT, followed by the device
+            let request = host.receive(device, |host| host.take(8 + 256 + 32 + 24));
+            assert_eq!(u16_at(&request, 0), USBIP_VERSION);
+            assert_eq!(u16_at(&request, 2), 0x8006);
+            assert_eq!(&request[8 + 256..8 + 256 + 4], b"1-1\0");
+            host.devid = (u32_at(&request, 8 + 288) << 16) | u32_at(&request, 8 + 292);
+            return host;
+         }
+         Err(err) if err.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+         Err(err) => panic!("unexpected error {}", err),
+      }
+   }
+   panic!("device did not connect");
+}
+
+/// Sends `OP_REP_EXPORT`.
+fn reply(host: &mut Host, return_code: i32) {
+   let mut reply = op_header(0x0006);
+   reply.extend_from_slice(&return_code.to_be_bytes());
+   host.send(&reply);
+}
+
+#[test]
+fn export_handshake() {
+   let (mut device, listener) = exporting_device();
+   let mut host = accept(&mut device, &listener);
+   reply(&mut host, 0);
+
+   // SET_CONFIGURATION(1)
+   host.submit(0x00, 0, 0, [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00], &[]);
+   assert_eq!(host.receive_submit(&mut device).status, 0);
+
+   let seqnum = host.submit(0x81, 0, 64, [0; 8], &[]);
+   device.class.ep_in.write(&[1, 2, 3]).unwrap();
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.status), (seqnum, 0));
+   assert_eq!(ret.data, [1, 2, 3]);
+}
+
+#[test]
+fn export_refused() {
+   let (mut device, listener) = exporting_device();
+   let mut host = accept(&mut device, &listener);
+   reply(&mut host, -1);
+
+   host.assert_closed(&mut device);
+   let errors = device.bus.server().take_errors();
+   assert!(matches!(errors[..], [UsbIpError::StatusNotOk(_)]), "{:?}", errors);
+}
+
+#[test]
+fn export_refused_with_status() {
+   let (mut device, listener) = exporting_device();
+   let mut host = accept(&mut device, &listener);
+
+   // usbipd answers a refusal with ST_NA
+   let mut reply = op_header(0x0006);
+   reply[4..8].copy_from_slice(&1u32.to_be_bytes());
+   reply.extend_from_slice(&(-1i32).to_be_bytes());
+   host.send(&reply);
+
+   host.assert_closed(&mut device);
+   let errors = device.bus.server().take_errors();
+   assert!(matches!(errors[..], [UsbIpError::StatusNotOk(1)]), "{:?}", errors);
+}
+
+#[test]
+fn export_reply_truncated() {
+   let (mut device, listener) = exporting_device();
+   let mut host = accept(&mut device, &listener);
+
+   // The header of the reply, but no return code
+   host.send(&op_header(0x0006));
+   drop(host);
+
+   for _ in 0..10 {
+      device.poll();
+      thread::sleep(Duration::from_millis(1));
+   }
+   let errors = device.bus.server().take_errors();
+   assert!(matches!(errors[..], [UsbIpError::ConnectionClosed]), "{:?}", errors);
+}
@@ -0,0 +1,111 @@
+use super::*;
+
+#[test]
+fn iso_in_one_packet_per_frame() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+   let ep = device.class.iso_in.address().into();
+
+   host.submit_iso(ep, URB_ISO_ASAP, 0, &[16, 16, 16], &[]);
+
+   // The pipe holds a single packet, the next one can be written once the host has taken it
+   let mut counter = 0;
+   for _ in 0..10 {
+      if device.class.iso_in.write(&[counter; 8]).is_ok() {
+         counter += 1;
+      }
+      device.frame();
+   }
+
+   let ret = host.receive_submit(&mut device);
+   assert_eq!(ret.status, 0);
+   assert_eq!(ret.error_count, 0);
+   assert_eq!(ret.actual_length, 24);
+   assert_eq!(ret.data, [[0; 8], [1; 8], [2; 8]].concat());
+
+   let actual_lengths: Vec<_> = ret.iso_packets.iter().map(|packet| packet.actual_length).collect();
+   assert_eq!(actual_lengths, vec![8, 8, 8]);
+   assert!(ret.iso_packets.iter().all(|packet| packet.status == 0));
+}
+
+#[test]
+fn iso_out() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+   let ep = device.class.iso_out.address().into();
+
+   host.submit_iso(ep, URB_ISO_ASAP, 0, &[3, 5], b"abcdefgh");
+
+   let mut received = vec![];
+   for _ in 0..10 {
+      device.frame();
+      let mut buf = [0; 16];
+      if let Ok(len) = device.class.iso_out.read(&mut buf) {
+         received.push(buf[..len].to_vec());
+      }
+   }
+   assert_eq!(received, vec![b"abc".to_vec(), b"defgh".to_vec()]);
+
+   let ret = host.receive_submit(&mut device);
+   assert_eq!(ret.status, 0);
+   assert_eq!(ret.actual_length, 8);
+   assert!(ret.data.is_empty());
+   assert_eq!(ret.iso_packets[1].offset, 3);
+   assert_eq!(ret.iso_packets[1].actual_length, 5);
+}
+
+#[test]
+fn iso_asap_follows_previous_urb() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+   let ep = device.class.iso_in.address().into();
+
+   // The device has nothing to send, which is a valid answer as well
+   host.submit_iso(ep, URB_ISO_ASAP, 0, &[16, 16], &[]);
+   host.submit_iso(ep, URB_ISO_ASAP, 0, &[16, 16], &[]);
+
+   let first = host.receive_submit(&mut device);
+   let second = host.receive_submit(&mut device);
+   assert_eq!(first.status, 0);
+   assert_eq!(first.actual_length, 0);
+   assert_eq!(second.start_frame, first.start_frame + 2);
+}
+
+#[test]
+fn iso_waits_for_start_frame() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+   let ep = device.class.iso_in.address().into();
+
+   // The host learns the frame counter of the bus from an URB, that was scheduled as soon as possible
+   host.submit_iso(ep, URB_ISO_ASAP, 0, &[16], &[]);
+   let frame = host.receive_submit(&mut device).start_frame;
+
+   host.submit_iso(ep, 0, frame + 1000, &[16, 16], &[]);
+   host.assert_idle(&mut device);
+
+   let ret = host.receive_submit(&mut device);
+   assert_eq!(ret.status, 0);
+   assert_eq!(ret.start_frame, frame + 1000);
+   assert_eq!(ret.error_count, 0);
+   assert!(ret.iso_packets.iter().all(|packet| packet.status == 0));
+}
+
+#[test]
+fn iso_late_packets_are_missed() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+   let ep = device.class.iso_in.address().into();
+
+   host.submit_iso(ep, URB_ISO_ASAP, 0, &[16], &[]);
+   let frame = host.receive_submit(&mut device).start_frame;
+
+   // The frames of the packets have passed, while the host was waiting for the response
+   host.submit_iso(ep, 0, frame, &[16, 16], &[]);
+
+   let ret = host.receive_submit(&mut device);
+   assert_eq!(ret.status, 0);
+   assert_eq!(ret.start_frame, frame);
+   assert_eq!(ret.error_count, 2);
+   assert!(ret.iso_packets.iter().all(|packet| packet.status == EXDEV));
+}
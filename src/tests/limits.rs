@@ -0,0 +1,84 @@
+//! The limits, that protect the device from a broken or malicious host.
+
+use super::*;
+use crate::UsbIpError;
+
+#[test]
+fn in_transfer_too_long() {
+   let mut device = Device::with_bus(UsbIpBusBuilder::new().max_transfer_length(64));
+   let mut host = device.attach();
+
+   host.submit(0x81, 0, 65, [0; 8], &[]);
+   assert_eq!(host.receive_submit(&mut device).status, EINVAL);
+
+   host.submit(0x81, 0, -1, [0; 8], &[]);
+   assert_eq!(host.receive_submit(&mut device).status, EINVAL);
+}
+
+#[test]
+fn out_transfer_too_long() {
+   let mut device = Device::with_bus(UsbIpBusBuilder::new().max_transfer_length(64));
+   let mut host = device.attach();
+
+   // The data can not be skipped, so the connection is closed
+   host.submit(0x01, 0, 65, [0; 8], &[0; 65]);
+   host.assert_closed(&mut device);
+
+   let errors = device.bus.server().take_errors();
+   assert!(matches!(errors[..], [UsbIpError::InvalidLength(65)]), "{:?}", errors);
+}
+
+#[test]
+fn too_many_iso_packets() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+   let ep = device.class.iso_in.address().into();
+
+   host.submit_iso(ep, URB_ISO_ASAP, 0, &[0; 1025], &[]);
+   host.assert_closed(&mut device);
+
+   let errors = device.bus.server().take_errors();
+   assert!(matches!(errors[..], [UsbIpError::InvalidLength(1025)]), "{:?}", errors);
+}
+
+#[test]
+fn duplicate_seqnum() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   let seqnum = host.submit(0x81, 0, 64, [0; 8], &[]);
+   host.seqnum -= 1;
+   assert_eq!(host.submit(0x81, 0, 64, [0; 8], &[]), seqnum);
+   assert_eq!(host.receive_submit(&mut device).status, EINVAL);
+
+   // The first URB is not affected
+   device.class.ep_in.write(&[1]).unwrap();
+   assert_eq!(host.receive_submit(&mut device).status, 0);
+}
+
+#[test]
+fn too_many_queued_urbs() {
+   let mut device = Device::with_bus(UsbIpBusBuilder::new().max_queued_urbs(2));
+   let mut host = device.attach();
+
+   host.submit(0x81, 0, 64, [0; 8], &[]);
+   host.submit(0x81, 0, 64, [0; 8], &[]);
+   let seqnum = host.submit(0x81, 0, 64, [0; 8], &[]);
+
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.status), (seqnum, ENOMEM));
+   host.assert_idle(&mut device);
+}
+
+#[test]
+fn too_many_buffered_bytes() {
+   let mut device = Device::with_bus(UsbIpBusBuilder::new().max_buffered_bytes(100));
+   let mut host = device.attach();
+
+   host.submit(0x81, 0, 64, [0; 8], &[]);
+   let seqnum = host.submit(0x81, 0, 64, [0; 8], &[]);
+
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.status), (seqnum, ENOMEM));
+   host.assert_idle(&mut device);
+}
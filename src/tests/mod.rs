@@ -0,0 +1,492 @@
+//! A host, that talks to a simulated device over its USBIP connection.
+//!
+//! The host encodes and decodes the messages by hand, such that a bug in the
+//! protocol implementation of the device does not cancel itself out.
+
+mod builder;
+mod control;
+mod decoder;
+mod export;
+mod iso;
+mod limits;
+mod out;
+mod protocol;
+mod reset;
+mod server;
+mod session;
+#[cfg(feature = "smoltcp")]
+mod smoltcp_transport;
+mod speed;
+#[cfg(unix)]
+mod stream;
+mod suspend;
+mod tweak;
+#[cfg(unix)]
+mod unix;
+mod unplug;
+mod urb;
+
+use crate::{UsbIpBus, UsbIpBusBuilder, UsbIpServer, UsbIpSession};
+use std::{
+   convert::TryInto,
+   io::{ErrorKind, Read, Write},
+   net::TcpStream,
+   thread,
+   time::Duration,
+};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use usb_device::{
+   bus::{InterfaceNumber, UsbBusAllocator},
+   class::UsbClass,
+   descriptor::DescriptorWriter,
+   endpoint::{EndpointIn, EndpointOut, EndpointType},
+   prelude::*,
+};
+
+pub const USBIP_VERSION: u16 = 0x0111;
+
+pub const ST_NODEV: u32 = 0x04;
+
+pub const URB_SHORT_NOT_OK: u32 = 0x0001;
+pub const URB_ISO_ASAP: u32 = 0x0002;
+pub const URB_ZERO_PACKET: u32 = 0x0040;
+
+pub const ENOENT: i32 = -2;
+pub const ENOMEM: i32 = -12;
+pub const EBUSY: i32 = -16;
+pub const EXDEV: i32 = -18;
+pub const ENODEV: i32 = -19;
+pub const EINVAL: i32 = -22;
+pub const EPIPE: i32 = -32;
+pub const EOVERFLOW: i32 = -75;
+pub const ECONNRESET: i32 = -104;
+pub const ESHUTDOWN: i32 = -108;
+pub const EREMOTEIO: i32 = -121;
+
+/// A vendor specific class with a bulk and an isochronous endpoint in each direction,
+/// which are driven directly by the tests.
+pub struct TestClass {
+   pub interface: InterfaceNumber,
+   pub ep_in: EndpointIn<'static, UsbIpBus>,
+   pub ep_out: EndpointOut<'static, UsbIpBus>,
+   pub iso_in: EndpointIn<'static, UsbIpBus>,
+   pub iso_out: EndpointOut<'static, UsbIpBus>,
+   /// The protocol of the interface, which can be changed to test how the descriptors are learned
+   pub protocol: u8,
+}
+
+impl UsbClass<UsbIpBus> for TestClass {
+   fn get_configuration_descriptors(
+      &self,
+      writer: &mut DescriptorWriter,
+   ) -> usb_device::Result<()> {
+      writer.interface(self.interface, 0xff, 0, self.protocol)?;
+      writer.endpoint(&self.ep_in)?;
+      writer.endpoint(&self.ep_out)?;
+      writer.endpoint(&self.iso_in)?;
+      writer.endpoint(&self.iso_out)
+   }
+}
+
+/// A simulated device, that exports a [`TestClass`] on an ephemeral port.
+pub struct Device {
+   /// A clone of the bus, the device runs on
+   pub bus: UsbIpBus,
+   pub usb_device: UsbDevice<'static, UsbIpBus>,
+   pub class: TestClass,
+}
+
+impl Device {
+   pub fn new() -> Self {
+      Self::with_bus(UsbIpBusBuilder::new())
+   }
+
+   /// Creates a device, that can only be reached through sessions.
+   pub fn unbound() -> Self {
+      Self::with_bus(UsbIpBusBuilder::new().server(&UsbIpServer::unbound()))
+   }
+
+   /// Creates a device on a bus, that is configured by `builder`.
+   pub fn with_bus(builder: UsbIpBusBuilder) -> Self {
+      let bus = builder.port(0).build().unwrap();
+      let handle = bus.clone();
+
+      // The class borrows the allocator for as long as the device lives
+      let allocator: &'static _ = Box::leak(Box::new(UsbBusAllocator::new(bus)));
+      let class = TestClass {
+         interface: allocator.interface(),
+         ep_in: allocator.bulk(64),
+         ep_out: allocator.bulk(64),
+         iso_in: allocator.alloc(None, EndpointType::Isochronous, 16, 1).unwrap(),
+         iso_out: allocator.alloc(None, EndpointType::Isochronous, 16, 1).unwrap(),
+         protocol: 0,
+      };
+      let usb_device = UsbDeviceBuilder::new(allocator, UsbVidPid(0x16c0, 0x27dd))
+         .supports_remote_wakeup(true)
+         .build();
+
+      // Let the device report its descriptors, such that it can be imported
+      let mut device = Self {
+         bus: handle,
+         usb_device,
+         class,
+      };
+      for _ in 0..10 {
+         device.poll();
+      }
+      device
+   }
+
+   pub fn poll(&mut self) {
+      for _ in 0..10 {
+         self.frame();
+      }
+   }
+
+   /// Polls the device once, which advances the bus by one frame.
+   pub fn frame(&mut self) {
+      self.usb_device.poll(&mut [&mut self.class]);
+   }
+
+   /// Opens a connection to the device.
+   pub fn connect(&self) -> Host {
+      let stream = TcpStream::connect(self.bus.local_addr().unwrap()).unwrap();
+      Host::new(stream)
+   }
+
+   /// Opens a session, whose bytes are passed in and out by the host.
+   pub fn open_session(&self) -> Host {
+      Host::with_link(Link::Session(self.bus.server().open_session()))
+   }
+
+   /// Opens a connection, imports the device and selects its configuration.
+   pub fn attach(&mut self) -> Host {
+      let mut host = self.connect();
+      assert_eq!(host.import(self, "1-1"), 0);
+
+      // SET_CONFIGURATION(1)
+      host.submit(0x00, 0, 0, [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00], &[]);
+      assert_eq!(host.receive_submit(self).status, 0);
+      host
+   }
+}
+
+/// A completed URB, as it is reported by `USBIP_RET_SUBMIT`.
+#[derive(Debug)]
+pub struct RetSubmit {
+   pub seqnum: u32,
+   pub status: i32,
+   pub actual_length: i32,
+   pub start_frame: i32,
+   pub error_count: i32,
+   pub data: Vec<u8>,
+   pub iso_packets: Vec<IsoPacket>,
+}
+
+/// The descriptor of a packet of an isochronous URB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsoPacket {
+   pub offset: u32,
+   pub length: u32,
+   pub actual_length: u32,
+   pub status: i32,
+}
+
+/// A socket, that does not block.
+pub trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+/// The transport, a host talks to the device over.
+pub enum Link {
+   Stream(Box<dyn Stream>),
+   Session(UsbIpSession),
+}
+
+/// The host end of a connection.
+pub struct Host {
+   pub link: Link,
+   rx: Vec<u8>,
+   seqnum: u32,
+   devid: u32,
+   /// The sequence numbers of the submitted URBs, and whether they are IN transfers
+   pending: Vec<(u32, bool)>,
+}
+
+impl Host {
+   pub fn new(stream: TcpStream) -> Self {
+      stream.set_nonblocking(true).unwrap();
+      // The requests are small, they must not wait for the ones before them to be acknowledged
+      stream.set_nodelay(true).unwrap();
+
+      Self::with_link(Link::Stream(Box::new(stream)))
+   }
+
+   #[cfg(unix)]
+   pub fn unix(stream: UnixStream) -> Self {
+      stream.set_nonblocking(true).unwrap();
+      Self::with_link(Link::Stream(Box::new(stream)))
+   }
+
+   fn with_link(link: Link) -> Self {
+      Self {
+         link,
+         rx: vec![],
+         seqnum: 0,
+         devid: 0,
+         pending: vec![],
+      }
+   }
+
+   pub fn send(&mut self, data: &[u8]) {
+      match self.link {
+         Link::Stream(ref mut stream) => stream.write_all(data).unwrap(),
+         Link::Session(ref session) => session.receive(data).unwrap(),
+      }
+   }
+
+   /// Polls the device, until `decode` finds a complete message in the received data.
+   pub fn receive<T>(&mut self, device: &mut Device, decode: impl Fn(&mut Self) -> Option<T>) -> T {
+      for _ in 0..200 {
+         device.poll();
+         self.read();
+         if let Some(message) = decode(self) {
+            return message;
+         }
+         thread::sleep(Duration::from_millis(1));
+      }
+      panic!("no response in {:02x?}", self.rx);
+   }
+
+   /// Polls the device for a while and checks, that it did not send anything.
+   pub fn assert_idle(&mut self, device: &mut Device) {
+      for _ in 0..10 {
+         device.poll();
+         thread::sleep(Duration::from_millis(1));
+      }
+      self.read();
+      assert!(self.rx.is_empty(), "unexpected data {:02x?}", self.rx);
+   }
+
+   /// Polls the device, until it has closed the connection.
+   pub fn assert_closed(&mut self, device: &mut Device) {
+      for _ in 0..200 {
+         device.poll();
+         let stream = match self.link {
+            Link::Stream(ref mut stream) => stream,
+            Link::Session(ref session) => {
+               let data = session.transmit();
+               assert!(data.is_empty(), "unexpected data {:02x?}", data);
+               if session.is_closed() {
+                  return;
+               }
+               continue;
+            }
+         };
+         let mut buf = [0; 1024];
+         match stream.read(&mut buf) {
+            Ok(0) => return,
+            Ok(len) => panic!("unexpected data {:02x?}", &buf[..len]),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => (),
+            Err(err) => panic!("unexpected error {}", err),
+         }
+         thread::sleep(Duration::from_millis(1));
+      }
+      panic!("connection is still open");
+   }
+
+   fn read(&mut self) {
+      let stream = match self.link {
+         Link::Stream(ref mut stream) => stream,
+         Link::Session(ref session) => return self.rx.extend(session.transmit()),
+      };
+      let mut buf = [0; 1024];
+      loop {
+         match stream.read(&mut buf) {
+            Ok(0) => return,
+            Ok(len) => self.rx.extend_from_slice(&buf[..len]),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+            Err(err) => panic!("unexpected error {}", err),
+         }
+      }
+   }
+
+   /// Takes `len` bytes from the received data, if they are available.
+   fn take(&mut self, len: usize) -> Option<Vec<u8>> {
+      if self.rx.len() < len {
+         return None;
+      }
+      Some(self.rx.drain(..len).collect())
+   }
+
+   /// Sends `OP_REQ_IMPORT` and returns the status of the reply.
+   pub fn import(&mut self, device: &mut Device, bus_id: &str) -> u32 {
+      let mut request = op_header(0x8003);
+      let mut bus_id_buf = [0; 32];
+      bus_id_buf[..bus_id.len()].copy_from_slice(bus_id.as_bytes());
+      request.extend_from_slice(&bus_id_buf);
+      self.send(&request);
+
+      let header = self.receive(device, |host| host.take(8));
+      assert_eq!(u16_at(&header, 0), USBIP_VERSION);
+      assert_eq!(u16_at(&header, 2), 0x0003);
+
+      let status = u32_at(&header, 4);
+      if status == 0 {
+         // The device follows, the interfaces are not reported on import
+         let device = self.receive(device, |host| host.take(256 + 32 + 24));
+         self.devid = (u32_at(&device, 288) << 16) | u32_at(&device, 292);
+      }
+      status
+   }
+
+   /// Sends `USBIP_CMD_SUBMIT` and returns its sequence number.
+   ///
+   /// `ep` is the endpoint address, i.e. bit 7 is set for IN endpoints.
+   pub fn submit(&mut self, ep: u8, flags: u32, length: i32, setup: [u8; 8], data: &[u8]) -> u32 {
+      let cmd = cmd_submit(flags, length, 0, 0, setup);
+      self.submit_urb(ep, &cmd, data)
+   }
+
+   /// Sends an isochronous `USBIP_CMD_SUBMIT` with a packet of each of the `lengths`.
+   ///
+   /// For OUT transfers, `data` holds the packets back to back.
+   pub fn submit_iso(
+      &mut self,
+      ep: u8,
+      flags: u32,
+      start_frame: i32,
+      lengths: &[u32],
+      data: &[u8],
+   ) -> u32 {
+      let length: u32 = lengths.iter().sum();
+      let cmd = cmd_submit(flags, length as i32, start_frame, lengths.len() as i32, [0; 8]);
+
+      // The descriptors of the packets follow the data
+      let mut data = data.to_vec();
+      let mut offset = 0u32;
+      for length in lengths {
+         data.extend_from_slice(&offset.to_be_bytes());
+         data.extend_from_slice(&length.to_be_bytes());
+         // actual_length and status
+         data.extend_from_slice(&[0; 8]);
+         offset += length;
+      }
+
+      self.submit_urb(ep, &cmd, &data)
+   }
+
+   fn submit_urb(&mut self, ep: u8, cmd: &[u8], data: &[u8]) -> u32 {
+      self.seqnum += 1;
+      let dir_in = ep & 0x80 != 0;
+
+      let mut request = self.header(1, dir_in, ep & 0x7f);
+      request.extend_from_slice(cmd);
+      request.extend_from_slice(data);
+      self.send(&request);
+
+      self.pending.push((self.seqnum, dir_in));
+      self.seqnum
+   }
+
+   /// Receives the next `USBIP_RET_SUBMIT`.
+   pub fn receive_submit(&mut self, device: &mut Device) -> RetSubmit {
+      self.receive(device, Self::decode_ret_submit)
+   }
+
+   fn decode_ret_submit(&mut self) -> Option<RetSubmit> {
+      if self.rx.len() < 48 {
+         return None;
+      }
+
+      let command = u32_at(&self.rx, 0);
+      assert_eq!(command, 3, "unexpected command");
+      let seqnum = u32_at(&self.rx, 4);
+      assert_eq!(u32_at(&self.rx, 8), self.devid);
+
+      let index = self
+         .pending
+         .iter()
+         .position(|&(pending, _)| pending == seqnum)
+         .unwrap_or_else(|| panic!("completion of unknown urb {}", seqnum));
+      let (_, dir_in) = self.pending[index];
+
+      // Only IN transfers carry data back to the host,
+      // isochronous transfers end with the descriptors of their packets
+      let actual_length = i32_at(&self.rx, 24);
+      let data_len = if dir_in { actual_length as usize } else { 0 };
+      let number_of_packets = i32_at(&self.rx, 32).max(0) as usize;
+      let message = self.take(48 + data_len + number_of_packets * 16)?;
+      self.pending.remove(index);
+
+      let iso_packets = message[48 + data_len..]
+         .chunks(16)
+         .map(|packet| IsoPacket {
+            offset: u32_at(packet, 0),
+            length: u32_at(packet, 4),
+            actual_length: u32_at(packet, 8),
+            status: i32_at(packet, 12),
+         })
+         .collect();
+
+      Some(RetSubmit {
+         seqnum,
+         status: i32_at(&message, 20),
+         actual_length,
+         start_frame: i32_at(&message, 28),
+         error_count: i32_at(&message, 36),
+         data: message[48..48 + data_len].to_vec(),
+         iso_packets,
+      })
+   }
+
+   fn header(&self, command: u32, dir_in: bool, ep: u8) -> Vec<u8> {
+      let mut header = vec![];
+      header.extend_from_slice(&command.to_be_bytes());
+      header.extend_from_slice(&self.seqnum.to_be_bytes());
+      header.extend_from_slice(&self.devid.to_be_bytes());
+      header.extend_from_slice(&(dir_in as u32).to_be_bytes());
+      header.extend_from_slice(&(ep as u32).to_be_bytes());
+      header
+   }
+}
+
+pub fn op_header(command: u16) -> Vec<u8> {
+   let mut header = vec![];
+   header.extend_from_slice(&USBIP_VERSION.to_be_bytes());
+   header.extend_from_slice(&command.to_be_bytes());
+   header.extend_from_slice(&0u32.to_be_bytes());
+   header
+}
+
+/// Encodes the fields of `USBIP_CMD_SUBMIT`, that follow the header.
+pub fn cmd_submit(
+   flags: u32,
+   length: i32,
+   start_frame: i32,
+   number_of_packets: i32,
+   setup: [u8; 8],
+) -> Vec<u8> {
+   let mut cmd = vec![];
+   cmd.extend_from_slice(&flags.to_be_bytes());
+   cmd.extend_from_slice(&length.to_be_bytes());
+   cmd.extend_from_slice(&start_frame.to_be_bytes());
+   cmd.extend_from_slice(&number_of_packets.to_be_bytes());
+   // The interval of the endpoint is used
+   cmd.extend_from_slice(&0i32.to_be_bytes());
+   cmd.extend_from_slice(&setup);
+   cmd
+}
+
+pub fn u16_at(data: &[u8], offset: usize) -> u16 {
+   u16::from_be_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+pub fn u32_at(data: &[u8], offset: usize) -> u32 {
+   u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn i32_at(data: &[u8], offset: usize) -> i32 {
+   i32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
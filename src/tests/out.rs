@@ -0,0 +1,103 @@
+//! The flow control of OUT transfers.
+
+use super::*;
+
+/// Lets the device receive the submitted URBs, then reads a packet from the bulk OUT endpoint
+/// and returns its length.
+fn read_packet(device: &mut Device) -> usize {
+   let mut buf = [0; 64];
+   device.poll();
+   device.class.ep_out.read(&mut buf).unwrap()
+}
+
+#[test]
+fn out_waits_until_the_device_reads_its_packets() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   let data: Vec<u8> = (0..=255).collect();
+   let seqnum = host.submit(0x01, 0, data.len() as i32, [0; 8], &data);
+
+   // The pipe holds two packets, the remaining ones wait for room
+   for _ in 0..3 {
+      host.assert_idle(&mut device);
+      assert_eq!(read_packet(&mut device), 64);
+   }
+   assert_eq!(read_packet(&mut device), 64);
+
+   let ret = host.receive_submit(&mut device);
+   assert_eq!(ret.seqnum, seqnum);
+   assert_eq!(ret.status, 0);
+   assert_eq!(ret.actual_length, 256);
+}
+
+#[test]
+fn out_urbs_complete_in_order() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   let first = host.submit(0x01, 0, 64, [0; 8], &[1; 64]);
+   let second = host.submit(0x01, 0, 10, [0; 8], &[2; 10]);
+   host.assert_idle(&mut device);
+
+   let mut buf = [0; 64];
+   assert_eq!(device.class.ep_out.read(&mut buf).unwrap(), 64);
+   assert_eq!(buf, [1; 64]);
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.actual_length), (first, 64));
+
+   assert_eq!(device.class.ep_out.read(&mut buf).unwrap(), 10);
+   assert_eq!(buf[..10], [2; 10]);
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.actual_length), (second, 10));
+}
+
+#[test]
+fn out_zero_length_packet() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   // An empty transfer is a single ZLP
+   host.submit(0x01, 0, 0, [0; 8], &[]);
+   host.assert_idle(&mut device);
+   assert_eq!(read_packet(&mut device), 0);
+   assert_eq!(host.receive_submit(&mut device).actual_length, 0);
+
+   // A transfer, that ends on a packet boundary, is only terminated on request
+   host.submit(0x01, URB_ZERO_PACKET, 64, [0; 8], &[0; 64]);
+   assert_eq!(read_packet(&mut device), 64);
+   host.assert_idle(&mut device);
+   assert_eq!(read_packet(&mut device), 0);
+   assert_eq!(host.receive_submit(&mut device).actual_length, 64);
+}
+
+#[test]
+fn out_pipe_depth() {
+   let mut device = Device::with_bus(UsbIpBusBuilder::new().pipe_depth(1));
+   let mut host = device.attach();
+
+   host.submit(0x01, 0, 128, [0; 8], &[0; 128]);
+   assert_eq!(read_packet(&mut device), 64);
+   host.assert_idle(&mut device);
+   assert_eq!(read_packet(&mut device), 64);
+   assert_eq!(host.receive_submit(&mut device).actual_length, 128);
+}
+
+#[test]
+fn out_pipe_depth_of_one_endpoint() {
+   let mut device = Device::with_bus(UsbIpBusBuilder::new().pipe_depth(1));
+   let ep_out = device.class.ep_out.address();
+   device.bus.set_pipe_depth(ep_out, 4).unwrap();
+   assert!(device.bus.set_pipe_depth(device.class.ep_in.address(), 4).is_err());
+   let mut host = device.attach();
+
+   // The endpoint takes the whole transfer, before the device reads it
+   host.submit(0x01, 0, 256, [0; 8], &[0; 256]);
+   host.assert_idle(&mut device);
+   assert_eq!(device.bus.lock().endpoint[1].pipe_out.as_ref().unwrap().data.len(), 4);
+
+   for _ in 0..4 {
+      assert_eq!(read_packet(&mut device), 64);
+   }
+   assert_eq!(host.receive_submit(&mut device).actual_length, 256);
+}
@@ -0,0 +1,294 @@
+//! The round trip of every message through its encoding and decoding.
+
+use crate::{protocol::*, UsbIpError};
+use std::fmt::Debug;
+
+/// Decodes the encoded `message` again and checks, that nothing was lost.
+///
+/// A partial frame must not be decoded, while a frame, that is followed by the next one,
+/// must only consume its own bytes.
+fn assert_round_trip<T: Clone + Debug + PartialEq>(
+   message: &T,
+   data: Vec<u8>,
+   decode: impl Fn(&[u8]) -> Result<Option<(T, usize)>, UsbIpError>,
+) {
+   for len in 0..data.len() {
+      assert_eq!(decode(&data[..len]).unwrap(), None, "decoded {} of {} bytes", len, data.len());
+   }
+
+   let mut stream = data.clone();
+   stream.extend_from_slice(&[0; 64]);
+   assert_eq!(decode(&stream).unwrap(), Some((message.clone(), data.len())));
+}
+
+fn op_header(command: u16) -> OpHeader {
+   OpHeader {
+      version: USBIP_VERSION,
+      command,
+      status: 0,
+   }
+}
+
+fn device(bus_id: &str, interfaces: Vec<OpInterfaceDescriptor>) -> OpDevice {
+   OpDevice {
+      path: format!("/sys/devices/pci0000:00/0000:00:01.2/usb1/{}", bus_id),
+      bus_id: bus_id.to_string(),
+      descriptor: OpDeviceDescriptor {
+         busnum: 1,
+         devnum: 2,
+         speed: 2,
+         vendor: 0x16c0,
+         product: 0x27dd,
+         bcd_device: 0x0100,
+         device_class: 0x02,
+         device_subclass: 0,
+         device_protocol: 0,
+         configuration_value: 1,
+         num_configurations: 1,
+         num_interfaces: interfaces.len() as u8,
+      },
+      interfaces,
+   }
+}
+
+fn interface(interface_class: u8) -> OpInterfaceDescriptor {
+   OpInterfaceDescriptor {
+      interface_class,
+      interface_subclass: 0x02,
+      interface_protocol: 0x01,
+      padding: 0,
+   }
+}
+
+fn header(command: UsbCmd, direction: Direction, ep: u32) -> UsbIpHeader {
+   UsbIpHeader {
+      command,
+      seqnum: 7,
+      devid: 0x10002,
+      direction,
+      ep,
+   }
+}
+
+fn iso_packets() -> Vec<UsbIpIsoPacketDescriptor> {
+   vec![
+      UsbIpIsoPacketDescriptor {
+         offset: 0,
+         length: 8,
+         actual_length: 8,
+         status: 0,
+      },
+      UsbIpIsoPacketDescriptor {
+         offset: 8,
+         length: 8,
+         actual_length: 3,
+         status: UrbStatus::Missed.to_errno(),
+      },
+   ]
+}
+
+#[test]
+fn op_request() {
+   let requests = [
+      OpRequest::ListDevices(op_header(0x8005)),
+      OpRequest::ConnectDevice(op_header(0x8003), "1-1".to_string()),
+   ];
+
+   for request in requests.iter() {
+      assert_round_trip(request, request.to_vec(), OpRequest::decode);
+   }
+}
+
+#[test]
+fn op_response() {
+   let responses = [
+      OpResponse {
+         version: USBIP_VERSION,
+         status: OpStatus::Ok,
+         cmd: OpResponseCommand::ListDevices(vec![]),
+      },
+      OpResponse {
+         version: USBIP_VERSION,
+         status: OpStatus::Ok,
+         cmd: OpResponseCommand::ListDevices(vec![
+            device("1-1", vec![interface(0x02), interface(0x0a)]),
+            device("1-2", vec![interface(0x03)]),
+         ]),
+      },
+      OpResponse {
+         version: 0x0106,
+         status: OpStatus::Ok,
+         cmd: OpResponseCommand::ConnectDevice(Some(device("1-1", vec![]))),
+      },
+      OpResponse {
+         version: USBIP_VERSION,
+         status: OpStatus::DevBusy,
+         cmd: OpResponseCommand::ConnectDevice(None),
+      },
+      OpResponse {
+         version: USBIP_VERSION,
+         status: OpStatus::Na,
+         cmd: OpResponseCommand::ListDevices(vec![]),
+      },
+   ];
+
+   for response in responses.iter() {
+      assert_round_trip(response, response.to_vec(), OpResponse::decode);
+   }
+}
+
+#[test]
+fn op_export() {
+   // The interfaces are not part of the export request
+   let request = OpExportRequest {
+      version: USBIP_VERSION,
+      device: device("1-1", vec![]),
+   };
+   assert_round_trip(&request, request.to_vec(), OpExportRequest::decode);
+
+   let reply = OpExportReply {
+      header: op_header(0x0006),
+      return_code: -1,
+   };
+   assert_round_trip(&reply, reply.to_vec(), OpExportReply::decode);
+
+   // A refusal of usbipd still carries the return code
+   let reply = OpExportReply {
+      header: OpHeader {
+         status: OpStatus::Na.to_u32(),
+         ..op_header(0x0006)
+      },
+      return_code: -1,
+   };
+   assert_round_trip(&reply, reply.to_vec(), OpExportReply::decode);
+}
+
+#[test]
+fn cmd_submit() {
+   let submit = |direction, data, iso_packets: Vec<UsbIpIsoPacketDescriptor>| UsbIpRequest {
+      header: header(UsbCmd::Request, direction, 1),
+      cmd: UsbIpRequestCmd::Cmd(UsbIpCmdSubmit {
+         transfer_flags: TransferFlags::SHORT_NOT_OK | TransferFlags::ZERO_PACKET,
+         transfer_buffer_length: 16,
+         start_frame: 3,
+         number_of_packets: iso_packets.len() as i32,
+         interval: 1,
+         setup: [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00],
+      }),
+      data,
+      iso_packets,
+   };
+
+   let requests = [
+      submit(Direction::IN, vec![], vec![]),
+      submit(Direction::OUT, (0..16).collect(), vec![]),
+      submit(Direction::IN, vec![], iso_packets()),
+      submit(Direction::OUT, (0..16).collect(), iso_packets()),
+   ];
+
+   for request in requests.iter() {
+      assert_round_trip(request, request.to_vec(), |data| UsbIpRequest::decode(data, 16));
+   }
+}
+
+#[test]
+fn cmd_unlink() {
+   let request = UsbIpRequest {
+      header: header(UsbCmd::UnlinkRequest, Direction::OUT, 0),
+      cmd: UsbIpRequestCmd::Unlink(UsbIpCmdUnlink { seqnum: 6 }),
+      data: vec![],
+      iso_packets: vec![],
+   };
+
+   assert_round_trip(&request, request.to_vec(), |data| UsbIpRequest::decode(data, 0));
+}
+
+#[test]
+fn ret_submit() {
+   let submitted = header(UsbCmd::Request, Direction::IN, 1);
+   let submitted_out = header(UsbCmd::Request, Direction::OUT, 1);
+
+   let iso = |header, data| {
+      UsbIpResponse::ret_submit_iso(header, 0x10002, UrbStatus::Ok, 42, iso_packets(), data)
+   };
+
+   let responses = [
+      UsbIpResponse::ret_submit(&submitted, 0x10002, UrbStatus::Ok, 5, b"hello".to_vec()),
+      UsbIpResponse::ret_submit(&submitted, 0x10002, UrbStatus::Stall, 0, vec![]),
+      UsbIpResponse::ret_submit(&submitted_out, 0x10002, UrbStatus::Ok, 64, vec![]),
+      iso(&submitted, (0..11).collect()),
+      iso(&submitted_out, vec![]),
+   ];
+
+   for response in responses.iter() {
+      assert_round_trip(response, response.to_vec(), |data| UsbIpResponse::decode(data, 16));
+   }
+}
+
+#[test]
+fn ret_submit_too_long() {
+   let submitted = header(UsbCmd::Request, Direction::IN, 1);
+   let response = UsbIpResponse::ret_submit(&submitted, 0x10002, UrbStatus::Ok, 17, vec![0; 17]);
+   let data = response.to_vec();
+   assert!(matches!(UsbIpResponse::decode(&data, 16), Err(UsbIpError::InvalidLength(17))));
+
+   // The data of a bogus length is not waited for
+   let mut data = data[..48].to_vec();
+   data[24..28].copy_from_slice(&i32::MAX.to_be_bytes());
+   assert!(matches!(
+      UsbIpResponse::decode(&data, 16),
+      Err(UsbIpError::InvalidLength(i32::MAX))
+   ));
+}
+
+#[test]
+fn ret_unlink() {
+   let unlinked = header(UsbCmd::UnlinkRequest, Direction::OUT, 0);
+   let response = UsbIpResponse::ret_unlink(&unlinked, 0x10002, UrbStatus::Unlinked);
+
+   assert_round_trip(&response, response.to_vec(), |data| UsbIpResponse::decode(data, 0));
+}
+
+#[test]
+fn unknown_status() {
+   // EPROTO, as reported by a real device, is kept unchanged
+   let submitted = header(UsbCmd::Request, Direction::OUT, 1);
+   let status = UrbStatus::from_errno(-71);
+   let response = UsbIpResponse::ret_submit(&submitted, 0x10002, status, 0, vec![]);
+   let data = response.to_vec();
+
+   assert_eq!(&data[20..24], &(-71i32).to_be_bytes());
+   assert_round_trip(&response, data, |data| UsbIpResponse::decode(data, 0));
+
+   assert_eq!(UrbStatus::from_errno(-71), UrbStatus::Other(-71));
+   assert_eq!(UrbStatus::from_errno(-32), UrbStatus::Stall);
+}
+
+#[test]
+fn iso_packet_descriptor() {
+   for packet in iso_packets() {
+      assert_eq!(UsbIpIsoPacketDescriptor::from_slice(&packet.to_array()), packet);
+   }
+}
+
+#[test]
+fn headers() {
+   let header = header(UsbCmd::Response, Direction::IN, 3);
+   assert_eq!(UsbIpHeader::from_slice(&header.to_array()).unwrap(), header);
+
+   let header = op_header(0x8003);
+   assert_eq!(OpHeader::from_slice(&header.to_array()), header);
+}
+
+#[test]
+fn long_bus_id_is_cut_off() {
+   let request = OpRequest::ConnectDevice(op_header(0x8003), "a".repeat(31) + "é");
+
+   // The last character does not fit anymore
+   let data = request.to_vec();
+   assert_eq!(data.len(), 40);
+   match OpRequest::decode(&data).unwrap() {
+      Some((OpRequest::ConnectDevice(_, bus_id), 40)) => assert_eq!(bus_id, "a".repeat(31)),
+      result => panic!("unexpected {:?}", result),
+   }
+}
@@ -0,0 +1,71 @@
+//! The reset, a device forces to come back as a new device.
+
+use super::*;
+use usb_device::bus::UsbBus;
+
+/// Lists the devices over a new connection and returns the protocol of the first interface.
+fn interface_protocol(device: &mut Device) -> u8 {
+   let mut host = device.connect();
+   host.send(&op_header(0x8005));
+
+   let header = host.receive(device, |host| host.take(12));
+   assert_eq!(u32_at(&header, 8), 1);
+   let reply = host.receive(device, |host| host.take(256 + 32 + 24 + 4));
+   reply[256 + 32 + 24 + 2]
+}
+
+#[test]
+fn force_reset() {
+   let mut device = Device::new();
+   assert_eq!(interface_protocol(&mut device), 0);
+   let mut host = device.attach();
+
+   let seqnum = host.submit(0x01, 0, 10, [0; 8], &[1; 10]);
+   host.submit(0x81, 0, 64, [0; 8], &[]);
+   host.assert_idle(&mut device);
+
+   // The OUT URB completes, but its response is not sent before the reset
+   let mut buf = [0; 64];
+   assert_eq!(device.class.ep_out.read(&mut buf).unwrap(), 10);
+   device.class.protocol = 1;
+   device.usb_device.bus().force_reset().unwrap();
+
+   // The IN URB goes away without an answer
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.status), (seqnum, 0));
+   host.assert_closed(&mut device);
+
+   // The device comes back with the descriptors, it reports now
+   assert_eq!(interface_protocol(&mut device), 1);
+   let mut host = device.connect();
+   assert_eq!(host.import(&mut device, "1-1"), 0);
+}
+
+#[test]
+fn port_reset_keeps_endpoints() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   // SET_FEATURE(PORT_RESET), as the host sends it to the hub of the device
+   let seqnum = host.submit(0x00, 0, 0, [0x23, 0x03, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00], &[]);
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.status), (seqnum, 0));
+   device.poll();
+
+   // The endpoints, the class allocated, are still there
+   {
+      let inner = device.bus.lock();
+      assert!(!inner.reset);
+      assert!(inner.endpoint[1].pipe_in.is_some());
+      assert!(inner.endpoint[1].pipe_out.is_some());
+   }
+
+   // The connection is still imported, so the next URB is a command
+   let seqnum = host.submit(0x01, 0, 10, [0; 8], &[1; 10]);
+   host.assert_idle(&mut device);
+   let mut buf = [0; 64];
+   assert_eq!(device.class.ep_out.read(&mut buf).unwrap(), 10);
+   assert_eq!(buf[..10], [1; 10]);
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.status, ret.actual_length), (seqnum, 0, 10));
+}
@@ -0,0 +1,41 @@
+//! A server, that exports several devices on one listener.
+
+use super::*;
+
+const SET_CONFIGURATION: [u8; 8] = [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+#[test]
+fn server_routes_urbs_to_the_imported_device() {
+   let server = UsbIpServer::bind(([127, 0, 0, 1], 0)).unwrap();
+   let mut first = Device::with_bus(UsbIpBusBuilder::new().server(&server));
+   let mut second = Device::with_bus(UsbIpBusBuilder::new().server(&server));
+
+   // Both devices are listed, each with its interface
+   let mut host = second.connect();
+   host.send(&op_header(0x8005));
+   let header = host.receive(&mut second, |host| host.take(12));
+   assert_eq!(u32_at(&header, 8), 2);
+   let devices = host.receive(&mut second, |host| host.take(2 * (256 + 32 + 24 + 4)));
+   assert_eq!(&devices[256..260], b"1-1\0");
+   assert_eq!(&devices[316 + 256..316 + 260], b"1-2\0");
+
+   let mut host = second.connect();
+   assert_eq!(host.import(&mut second, "1-2"), 0);
+   assert_eq!(host.devid, 0x0001_0002);
+   host.submit(0x00, 0, 0, SET_CONFIGURATION, &[]);
+   assert_eq!(host.receive_submit(&mut second).status, 0);
+
+   // The URB is only seen by the second device
+   let seqnum = host.submit(0x81, 0, 64, [0; 8], &[]);
+   first.poll();
+   host.assert_idle(&mut second);
+   second.class.ep_in.write(&[2; 8]).unwrap();
+   let ret = host.receive_submit(&mut second);
+   assert_eq!((ret.seqnum, ret.status), (seqnum, 0));
+   assert_eq!(ret.data, [2; 8]);
+
+   // The first device is still free
+   let mut other = first.connect();
+   assert_eq!(other.import(&mut first, "1-1"), 0);
+   assert_eq!(other.devid, 0x0001_0001);
+}
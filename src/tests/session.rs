@@ -0,0 +1,175 @@
+//! Sessions, whose bytes are passed in and out by the user instead of a socket.
+
+use super::*;
+use crate::UsbIpError;
+
+/// Returns the session, `host` talks over.
+fn session(host: &Host) -> &UsbIpSession {
+   match host.link {
+      Link::Session(ref session) => session,
+      Link::Stream(_) => panic!("host is not connected over a session"),
+   }
+}
+
+/// Sends `USBIP_CMD_UNLINK` for the URB `seqnum` and returns the status of the reply.
+fn unlink(host: &mut Host, device: &mut Device, seqnum: u32) -> i32 {
+   host.seqnum += 1;
+   let mut request = host.header(2, false, 0);
+   request.extend_from_slice(&seqnum.to_be_bytes());
+   request.extend_from_slice(&[0; 24]);
+   host.send(&request);
+
+   let reply = host.receive(device, |host| host.take(48));
+   assert_eq!(u32_at(&reply, 0), 4);
+   assert_eq!(u32_at(&reply, 4), host.seqnum);
+   i32_at(&reply, 20)
+}
+
+#[test]
+fn session_list_devices() {
+   let mut device = Device::unbound();
+   let mut host = device.open_session();
+   host.send(&op_header(0x8005));
+
+   let header = host.receive(&mut device, |host| host.take(12));
+   assert_eq!(u16_at(&header, 2), 0x0005);
+   assert_eq!(u32_at(&header, 4), 0);
+   assert_eq!(u32_at(&header, 8), 1);
+
+   // The device is followed by its single interface
+   let reply = host.receive(&mut device, |host| host.take(256 + 32 + 24 + 4));
+   assert_eq!(&reply[256..260], b"1-1\0");
+   assert_eq!(u16_at(&reply, 256 + 32 + 12), 0x16c0);
+}
+
+#[test]
+fn session_import_fragmented() {
+   let mut device = Device::unbound();
+   let mut host = device.open_session();
+
+   let mut request = op_header(0x8003);
+   let mut bus_id = [0; 32];
+   bus_id[..3].copy_from_slice(b"1-1");
+   request.extend_from_slice(&bus_id);
+
+   // Nothing is answered, until the request is complete
+   let (last, head) = request.split_last().unwrap();
+   for byte in head {
+      host.send(&[*byte]);
+      assert!(session(&host).transmit().is_empty());
+   }
+   host.send(&[*last]);
+
+   let header = host.receive(&mut device, |host| host.take(8));
+   assert_eq!(u32_at(&header, 4), 0);
+   let reply = host.receive(&mut device, |host| host.take(256 + 32 + 24));
+   assert_eq!(&reply[256..260], b"1-1\0");
+   assert!(!session(&host).is_closed());
+}
+
+#[test]
+fn session_submit() {
+   let mut device = Device::unbound();
+   let mut host = device.open_session();
+   assert_eq!(host.import(&mut device, "1-1"), 0);
+
+   // GET_DESCRIPTOR(DEVICE)
+   let seqnum = host.submit(0x80, 0, 18, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 18, 0x00], &[]);
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.status, ret.actual_length), (seqnum, 0, 18));
+   assert_eq!(&ret.data[..2], &[18, 0x01]);
+   assert_eq!(&ret.data[8..12], &[0xc0, 0x16, 0xdd, 0x27]);
+}
+
+#[test]
+fn session_unlink() {
+   let mut device = Device::unbound();
+   let mut host = device.open_session();
+   assert_eq!(host.import(&mut device, "1-1"), 0);
+
+   // SET_CONFIGURATION(1)
+   host.submit(0x00, 0, 0, [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00], &[]);
+   assert_eq!(host.receive_submit(&mut device).status, 0);
+
+   // The device has nothing to send, so the URB stays pending
+   let ep = device.class.ep_in.address().index() as u8 | 0x80;
+   let pending = host.submit(ep, 0, 64, [0; 8], &[]);
+   host.assert_idle(&mut device);
+
+   assert_eq!(unlink(&mut host, &mut device, pending), ECONNRESET);
+
+   // The unlinked URB is never completed
+   device.class.ep_in.write(&[1, 2, 3]).unwrap();
+   host.assert_idle(&mut device);
+}
+
+#[test]
+fn session_device_busy() {
+   let mut device = Device::unbound();
+   let mut host = device.open_session();
+   assert_eq!(host.import(&mut device, "1-1"), 0);
+
+   let mut other = device.open_session();
+   assert_eq!(other.import(&mut device, "1-1"), 0x02);
+   other.assert_closed(&mut device);
+
+   // The first host keeps the device
+   assert!(!session(&host).is_closed());
+
+   // Once the session is dropped, the device can be imported again
+   drop(host);
+   let mut other = device.open_session();
+   assert_eq!(other.import(&mut device, "1-1"), 0);
+}
+
+#[test]
+fn session_protocol_version() {
+   let mut device = Device::unbound();
+   let mut host = device.open_session();
+   assert_eq!(session(&host).protocol_version(), None);
+
+   // The older version is answered in its own version
+   let mut request = op_header(0x8005);
+   request[..2].copy_from_slice(&0x0106u16.to_be_bytes());
+   host.send(&request);
+   assert_eq!(session(&host).protocol_version(), Some(0x0106));
+
+   let header = host.receive(&mut device, |host| host.take(12));
+   assert_eq!(u16_at(&header, 0), 0x0106);
+}
+
+#[test]
+fn session_unsupported_version() {
+   let mut device = Device::unbound();
+   let mut host = device.open_session();
+
+   let mut request = op_header(0x8005);
+   request[..2].copy_from_slice(&0x0200u16.to_be_bytes());
+   match session(&host).receive(&request) {
+      Err(UsbIpError::UnsupportedVersion(0x0200)) => (),
+      result => panic!("unexpected {:?}", result),
+   }
+
+   // ST_NA, a refused request carries no devices
+   let header = host.receive(&mut device, |host| host.take(8));
+   assert_eq!(u32_at(&header, 4), 0x01);
+   host.assert_closed(&mut device);
+}
+
+#[test]
+fn session_errors_are_capped() {
+   let device = Device::unbound();
+   let server = device.bus.server();
+
+   // Every session is closed because of an unknown command
+   for command in 0..100u16 {
+      let session = server.open_session();
+      assert!(session.receive(&op_header(0x8100 + command)).is_err());
+   }
+
+   // The oldest errors were dropped
+   let errors = server.take_errors();
+   assert_eq!(errors.len(), 64);
+   assert!(matches!(errors[63], UsbIpError::InvalidCommand(0x8163)), "{:?}", errors[63]);
+   assert!(server.take_errors().is_empty());
+}
@@ -0,0 +1,199 @@
+//! The transport over a TCP socket of smoltcp.
+//!
+//! Both ends live on the loopback interface of smoltcp, the host is played by a second socket.
+
+use super::*;
+use crate::{SmoltcpTransport, UsbIpError, USBIP_PORT};
+use smoltcp::{
+   iface::{Config, Interface, SocketHandle, SocketSet},
+   phy::{Loopback, Medium},
+   socket::tcp,
+   time::Instant,
+   wire::{EthernetAddress, IpAddress, IpCidr},
+};
+
+const GET_DESCRIPTOR_DEVICE: [u8; 8] = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+
+/// The interface with the socket of the transport and the socket of the host.
+struct Network {
+   loopback: Loopback,
+   iface: Interface,
+   sockets: SocketSet<'static>,
+   transport: SmoltcpTransport,
+   client: SocketHandle,
+   rx: Vec<u8>,
+}
+
+impl Network {
+   /// Serves `device` on the loopback interface, the host receives into a buffer of `rx_len` bytes.
+   fn new(device: &Device, rx_len: usize) -> Self {
+      let mut loopback = Loopback::new(Medium::Ethernet);
+      let config = Config::new(EthernetAddress([0x02, 0, 0, 0, 0, 1]).into());
+      let mut iface = Interface::new(config, &mut loopback, Instant::now());
+      iface.update_ip_addrs(|addrs| {
+         addrs.push(IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8)).unwrap();
+      });
+
+      let mut sockets = SocketSet::new(vec![]);
+      let server = sockets.add(socket(256, 256));
+      let client = sockets.add(socket(rx_len, 4096));
+      let transport = SmoltcpTransport::new(device.bus.server(), server, USBIP_PORT);
+
+      Self {
+         loopback,
+         iface,
+         sockets,
+         transport,
+         client,
+         rx: vec![],
+      }
+   }
+
+   /// Polls the interface, the transport and the device once.
+   fn poll(&mut self, device: &mut Device) -> Result<(), UsbIpError> {
+      self.iface.poll(Instant::now(), &mut self.loopback, &mut self.sockets);
+      let result = self.transport.poll(&mut self.sockets);
+      device.frame();
+      result
+   }
+
+   fn client(&mut self) -> &mut tcp::Socket<'static> {
+      self.sockets.get_mut(self.client)
+   }
+
+   /// Connects the host to the transport.
+   fn connect(&mut self, device: &mut Device, local_port: u16) {
+      // The socket of the transport only listens, once the transport was polled
+      self.poll(device).unwrap();
+      let endpoint = (IpAddress::v4(127, 0, 0, 1), USBIP_PORT);
+      let client = self.sockets.get_mut::<tcp::Socket>(self.client);
+      client.connect(self.iface.context(), endpoint, local_port).unwrap();
+
+      for _ in 0..100 {
+         self.poll(device).unwrap();
+         if self.client().may_send() {
+            return;
+         }
+      }
+      panic!("not connected");
+   }
+
+   fn send(&mut self, data: &[u8]) {
+      assert_eq!(self.client().send_slice(data).unwrap(), data.len());
+   }
+
+   /// Polls, until the host has received `len` bytes, and takes them.
+   fn receive(&mut self, device: &mut Device, len: usize) -> Vec<u8> {
+      for _ in 0..100 {
+         self.poll(device).unwrap();
+         let mut rx = std::mem::take(&mut self.rx);
+         while self.client().can_recv() {
+            self.client()
+               .recv(|data| {
+                  rx.extend_from_slice(data);
+                  (data.len(), ())
+               })
+               .unwrap();
+         }
+         self.rx = rx;
+
+         if self.rx.len() >= len {
+            return self.rx.drain(..len).collect();
+         }
+      }
+      panic!("no response in {:02x?}", self.rx);
+   }
+
+   /// Imports the first device of the bus and returns its devid.
+   fn import(&mut self, device: &mut Device) -> u32 {
+      let mut request = op_header(0x8003);
+      let mut bus_id = [0; 32];
+      bus_id[..3].copy_from_slice(b"1-1");
+      request.extend_from_slice(&bus_id);
+      self.send(&request);
+
+      let header = self.receive(device, 8);
+      assert_eq!(u32_at(&header, 4), 0);
+      let reply = self.receive(device, 256 + 32 + 24);
+      (u32_at(&reply, 288) << 16) | u32_at(&reply, 292)
+   }
+}
+
+/// Creates a socket, that acknowledges right away, the test does not wait for timers.
+fn socket(rx_len: usize, tx_len: usize) -> tcp::Socket<'static> {
+   let mut socket = tcp::Socket::new(
+      tcp::SocketBuffer::new(vec![0; rx_len]),
+      tcp::SocketBuffer::new(vec![0; tx_len]),
+   );
+   socket.set_ack_delay(None);
+   socket.set_nagle_enabled(false);
+   socket
+}
+
+/// Encodes a `USBIP_CMD_SUBMIT` of an IN transfer without data.
+fn submit_in(seqnum: u32, devid: u32, ep: u32, setup: [u8; 8], length: i32) -> Vec<u8> {
+   let mut request = vec![];
+   for field in &[1, seqnum, devid, 1, ep] {
+      request.extend_from_slice(&field.to_be_bytes());
+   }
+   request.extend(cmd_submit(0, length, 0, 0, setup));
+   request
+}
+
+#[test]
+fn smoltcp_serves_one_host_after_another() {
+   let mut device = Device::unbound();
+   let mut network = Network::new(&device, 4096);
+
+   for local_port in [49152, 49153] {
+      network.connect(&mut device, local_port);
+      let devid = network.import(&mut device);
+
+      network.send(&submit_in(1, devid, 0, GET_DESCRIPTOR_DEVICE, 18));
+      let ret = network.receive(&mut device, 48 + 18);
+      assert_eq!(u32_at(&ret, 0), 3);
+      assert_eq!(i32_at(&ret, 20), 0);
+      assert_eq!(ret[48..50], [18, 1]);
+
+      // Once the host is gone, the socket listens for the next one
+      network.client().close();
+      for _ in 0..100 {
+         network.poll(&mut device).unwrap();
+      }
+      assert!(!network.client().is_open());
+   }
+}
+
+#[test]
+fn smoltcp_send_buffer_is_bounded() {
+   let mut device = Device::with_bus(
+      UsbIpBusBuilder::new()
+         .server(&UsbIpServer::unbound())
+         .max_buffered_bytes(1024),
+   );
+   let mut network = Network::new(&device, 128);
+   network.connect(&mut device, 49152);
+   let devid = network.import(&mut device);
+
+   // The host stops reading, the responses pile up in the transport,
+   // since the URBs are sent to an endpoint, that does not exist, they complete right away
+   let mut result = Ok(());
+   for seqnum in 1..100 {
+      network.send(&submit_in(seqnum, devid, 15, [0; 8], 0));
+      result = network.poll(&mut device);
+      if result.is_err() {
+         break;
+      }
+   }
+   assert!(matches!(result, Err(UsbIpError::SendBufferFull(len)) if len > 1024));
+   assert!(matches!(
+      device.bus.server().take_errors()[..],
+      [UsbIpError::SendBufferFull(_)]
+   ));
+
+   // The connection is aborted
+   for _ in 0..100 {
+      network.poll(&mut device).unwrap();
+   }
+   assert!(!network.client().is_open());
+}
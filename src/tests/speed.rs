@@ -0,0 +1,41 @@
+//! The validation of the endpoints against the speed of the device.
+
+use super::*;
+use crate::UsbSpeed;
+use usb_device::{bus::UsbBus, UsbDirection, UsbError};
+
+/// Allocates a bulk IN endpoint on a new bus of the given speed.
+fn alloc_bulk(speed: UsbSpeed, max_packet_size: u16) -> usb_device::Result<()> {
+   let mut bus = UsbIpBusBuilder::new()
+      .server(&UsbIpServer::unbound())
+      .speed(speed)
+      .build()
+      .unwrap();
+   bus.alloc_ep(UsbDirection::In, None, EndpointType::Bulk, max_packet_size, 0)
+      .map(drop)
+}
+
+#[test]
+fn full_speed_bulk_too_large() {
+   assert!(matches!(alloc_bulk(UsbSpeed::Full, 512), Err(UsbError::Unsupported)));
+}
+
+#[test]
+fn full_speed_bulk() {
+   assert!(alloc_bulk(UsbSpeed::Full, 64).is_ok());
+   assert!(alloc_bulk(UsbSpeed::Full, 8).is_ok());
+   assert!(matches!(alloc_bulk(UsbSpeed::Full, 48), Err(UsbError::Unsupported)));
+}
+
+#[test]
+fn high_speed_bulk_of_a_full_speed_class() {
+   // The host expects 512 bytes, but accepts less, so this is only warned about
+   assert!(alloc_bulk(UsbSpeed::High, 64).is_ok());
+   assert!(alloc_bulk(UsbSpeed::High, 512).is_ok());
+   assert!(matches!(alloc_bulk(UsbSpeed::High, 1024), Err(UsbError::Unsupported)));
+}
+
+#[test]
+fn low_speed_bulk() {
+   assert!(matches!(alloc_bulk(UsbSpeed::Low, 8), Err(UsbError::Unsupported)));
+}
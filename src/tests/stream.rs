@@ -0,0 +1,46 @@
+//! Serving a host over a reader and a writer, instead of a socket.
+
+use super::*;
+
+/// Serves the device on one end of a socket pair and returns the host on the other end.
+fn serve(device: &Device) -> Host {
+   let (host, served) = UnixStream::pair().unwrap();
+   let reader = served.try_clone().unwrap();
+   device.bus.server().serve_stream(reader, served);
+   Host::unix(host)
+}
+
+#[test]
+fn stream_submit() {
+   let mut device = Device::unbound();
+   let mut host = serve(&device);
+   assert_eq!(host.import(&mut device, "1-1"), 0);
+
+   // GET_DESCRIPTOR(DEVICE)
+   let seqnum = host.submit(0x80, 0, 18, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 18, 0x00], &[]);
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.status, ret.actual_length), (seqnum, 0, 18));
+   assert_eq!(&ret.data[8..12], &[0xc0, 0x16, 0xdd, 0x27]);
+}
+
+#[test]
+fn stream_end_detaches() {
+   let mut device = Device::unbound();
+   let mut host = serve(&device);
+   assert_eq!(host.import(&mut device, "1-1"), 0);
+
+   let mut other = serve(&device);
+   assert_eq!(other.import(&mut device, "1-1"), 0x02);
+
+   // The end of the input closes the connection and frees the device,
+   // once the reader thread has seen it
+   drop(host);
+   for _ in 0..200 {
+      let mut other = serve(&device);
+      if other.import(&mut device, "1-1") == 0 {
+         return;
+      }
+      thread::sleep(Duration::from_millis(1));
+   }
+   panic!("device is still attached");
+}
@@ -0,0 +1,77 @@
+//! The simulated suspend and resume of the bus.
+
+use super::*;
+use usb_device::UsbError;
+
+const SET_REMOTE_WAKEUP: [u8; 8] = [0x00, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+const CLEAR_REMOTE_WAKEUP: [u8; 8] = [0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+#[test]
+fn host_suspend_and_resume() {
+   let mut device = Device::new();
+   let _host = device.attach();
+   assert_eq!(device.usb_device.state(), UsbDeviceState::Configured);
+
+   device.bus.host_suspend();
+   device.poll();
+   assert_eq!(device.usb_device.state(), UsbDeviceState::Suspend);
+
+   device.bus.host_resume();
+   device.poll();
+   assert_eq!(device.usb_device.state(), UsbDeviceState::Configured);
+}
+
+#[test]
+fn urb_resumes_the_bus() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   device.bus.host_suspend();
+   device.poll();
+   assert_eq!(device.usb_device.state(), UsbDeviceState::Suspend);
+
+   let seqnum = host.submit(0x81, 0, 64, [0; 8], &[]);
+   host.assert_idle(&mut device);
+   assert_eq!(device.usb_device.state(), UsbDeviceState::Configured);
+
+   device.class.ep_in.write(&[1]).unwrap();
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.status), (seqnum, 0));
+}
+
+#[test]
+fn remote_wakeup() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   host.submit(0x00, 0, 0, SET_REMOTE_WAKEUP, &[]);
+   assert_eq!(host.receive_submit(&mut device).status, 0);
+   assert!(device.usb_device.remote_wakeup_enabled());
+
+   // Only a suspended bus can be woken up
+   assert!(matches!(device.bus.remote_wakeup(), Err(UsbError::InvalidState)));
+
+   device.bus.host_suspend();
+   device.poll();
+   assert_eq!(device.usb_device.state(), UsbDeviceState::Suspend);
+
+   device.bus.remote_wakeup().unwrap();
+   device.poll();
+   assert_eq!(device.usb_device.state(), UsbDeviceState::Configured);
+}
+
+#[test]
+fn remote_wakeup_not_enabled() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   host.submit(0x00, 0, 0, SET_REMOTE_WAKEUP, &[]);
+   assert_eq!(host.receive_submit(&mut device).status, 0);
+   host.submit(0x00, 0, 0, CLEAR_REMOTE_WAKEUP, &[]);
+   assert_eq!(host.receive_submit(&mut device).status, 0);
+
+   device.bus.host_suspend();
+   device.poll();
+   assert!(matches!(device.bus.remote_wakeup(), Err(UsbError::InvalidState)));
+   assert_eq!(device.usb_device.state(), UsbDeviceState::Suspend);
+}
@@ -0,0 +1,124 @@
+//! The standard requests, that the bus applies to its pipes.
+
+use super::*;
+
+const CLEAR_HALT_IN: [u8; 8] = [0x02, 0x01, 0x00, 0x00, 0x81, 0x00, 0x00, 0x00];
+const CLEAR_HALT_OUT: [u8; 8] = [0x02, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00];
+const SET_INTERFACE: [u8; 8] = [0x01, 0x0b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+const SET_CONFIGURATION_0: [u8; 8] = [0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+/// Sends a request without data stage on endpoint 0 and returns its sequence number.
+fn control(host: &mut Host, setup: [u8; 8]) -> u32 {
+   host.submit(0x00, 0, 0, setup, &[])
+}
+
+#[test]
+fn clear_halt_keeps_pending_urbs() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   let seqnum = host.submit(0x81, 0, 64, [0; 8], &[]);
+   host.assert_idle(&mut device);
+
+   let request = control(&mut host, CLEAR_HALT_IN);
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.status), (request, 0));
+   host.assert_idle(&mut device);
+
+   device.class.ep_in.write(&[1, 2, 3]).unwrap();
+   let ret = host.receive_submit(&mut device);
+   assert_eq!(ret.seqnum, seqnum);
+   assert_eq!(ret.status, 0);
+   assert_eq!(ret.data, [1, 2, 3]);
+}
+
+#[test]
+fn clear_halt_discards_queued_packets() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   // Written, before the host asked for it
+   device.class.ep_in.write(&[1; 8]).unwrap();
+   control(&mut host, CLEAR_HALT_IN);
+   assert_eq!(host.receive_submit(&mut device).status, 0);
+
+   let seqnum = host.submit(0x81, 0, 64, [0; 8], &[]);
+   host.assert_idle(&mut device);
+
+   device.class.ep_in.write(&[2; 8]).unwrap();
+   let ret = host.receive_submit(&mut device);
+   assert_eq!(ret.seqnum, seqnum);
+   assert_eq!(ret.data, [2; 8]);
+}
+
+#[test]
+fn clear_halt_keeps_unread_out_packets() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   let data: Vec<u8> = (0..128).collect();
+   let seqnum = host.submit(0x01, 0, 128, [0; 8], &data);
+   host.assert_idle(&mut device);
+
+   control(&mut host, CLEAR_HALT_OUT);
+   assert_eq!(host.receive_submit(&mut device).status, 0);
+
+   let mut buf = [0; 64];
+   assert_eq!(device.class.ep_out.read(&mut buf).unwrap(), 64);
+   assert_eq!(buf[..], data[..64]);
+   assert_eq!(device.class.ep_out.read(&mut buf).unwrap(), 64);
+   assert_eq!(buf[..], data[64..]);
+
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.actual_length), (seqnum, 128));
+}
+
+#[test]
+fn clear_halt_after_stall() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   device.class.ep_in.stall();
+   host.submit(0x81, 0, 64, [0; 8], &[]);
+   assert_eq!(host.receive_submit(&mut device).status, EPIPE);
+
+   control(&mut host, CLEAR_HALT_IN);
+   assert_eq!(host.receive_submit(&mut device).status, 0);
+
+   host.submit(0x81, 0, 64, [0; 8], &[]);
+   device.class.ep_in.write(&[1]).unwrap();
+   assert_eq!(host.receive_submit(&mut device).status, 0);
+}
+
+#[test]
+fn set_interface_keeps_pending_urbs_of_remaining_endpoints() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   let seqnum = host.submit(0x81, 0, 64, [0; 8], &[]);
+   let request = control(&mut host, SET_INTERFACE);
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.status), (request, 0));
+   host.assert_idle(&mut device);
+
+   device.class.ep_in.write(&[1]).unwrap();
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.status), (seqnum, 0));
+}
+
+#[test]
+fn set_configuration_fails_pending_urbs_of_removed_endpoints() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   let seqnum = host.submit(0x81, 0, 64, [0; 8], &[]);
+   let request = control(&mut host, SET_CONFIGURATION_0);
+
+   let mut statuses = vec![];
+   for _ in 0..2 {
+      let ret = host.receive_submit(&mut device);
+      statuses.push((ret.seqnum, ret.status));
+   }
+   statuses.sort_unstable();
+   assert_eq!(statuses, [(seqnum, ECONNRESET), (request, 0)]);
+}
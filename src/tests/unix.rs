@@ -0,0 +1,61 @@
+//! Serving the devices on a Unix domain socket.
+
+use super::*;
+use crate::UsbIpError;
+use std::{fs, os::unix::net::UnixListener, path::PathBuf};
+
+/// Returns a path in the temporary directory, that no other test uses.
+fn socket_path(name: &str) -> PathBuf {
+   let file = format!("usbip-device-{}-{}.sock", name, std::process::id());
+   let path = std::env::temp_dir().join(file);
+   let _ = fs::remove_file(&path);
+   path
+}
+
+#[test]
+fn unix_socket_replaces_stale_file() {
+   let path = socket_path("stale");
+
+   // The file of a socket, nobody listens on anymore
+   drop(UnixListener::bind(&path).unwrap());
+   assert!(path.exists());
+
+   let server = UsbIpServer::bind_unix(&path).unwrap();
+   assert_eq!(server.path(), Some(path.clone()));
+   assert_eq!(server.local_addr(), None);
+
+   let mut device = Device::with_bus(UsbIpBusBuilder::new().server(&server));
+   let mut host = Host::unix(UnixStream::connect(&path).unwrap());
+   assert_eq!(host.import(&mut device, "1-1"), 0);
+
+   // GET_DESCRIPTOR(DEVICE)
+   let seqnum = host.submit(0x80, 0, 18, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 18, 0x00], &[]);
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.status, ret.actual_length), (seqnum, 0, 18));
+}
+
+#[test]
+fn unix_socket_in_use() {
+   let path = socket_path("in-use");
+   let server = UsbIpServer::bind_unix(&path).unwrap();
+
+   match UsbIpServer::bind_unix(&path) {
+      Err(UsbIpError::Io(ErrorKind::AddrInUse)) => (),
+      result => panic!("unexpected {:?}", result.map(|server| server.path())),
+   }
+
+   // The file is removed with the server, that owns it
+   assert!(path.exists());
+   drop(server);
+   assert!(!path.exists());
+}
+
+#[test]
+fn unix_socket_keeps_other_files() {
+   let path = socket_path("file");
+   fs::write(&path, b"no socket").unwrap();
+
+   assert!(UsbIpServer::bind_unix(&path).is_err());
+   assert_eq!(fs::read(&path).unwrap(), b"no socket");
+   fs::remove_file(&path).unwrap();
+}
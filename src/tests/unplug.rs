@@ -0,0 +1,110 @@
+//! Pulling the device from the bus, while the server keeps running.
+
+use super::*;
+use usb_device::bus::UsbBus;
+
+#[test]
+fn unplug_fails_pending_urbs() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+   let iso_ep = device.class.iso_in.address().into();
+
+   host.submit(0x81, 0, 64, [0; 8], &[]);
+   host.submit(0x01, 0, 256, [0; 8], &[0; 256]);
+   host.submit_iso(iso_ep, URB_ISO_ASAP, 0, &[16; 100], &[]);
+
+   // Let the URBs arrive, the isochronous one is far from done after a few frames
+   for _ in 0..10 {
+      device.frame();
+      thread::sleep(Duration::from_millis(1));
+   }
+   device.bus.unplug();
+   assert!(!device.bus.is_plugged());
+
+   let mut iso_packets = vec![];
+   for _ in 0..3 {
+      let ret = host.receive_submit(&mut device);
+      assert_eq!(ret.status, ESHUTDOWN);
+      iso_packets.extend(ret.iso_packets);
+   }
+   assert_eq!(iso_packets.len(), 100);
+   assert_eq!(iso_packets.last().unwrap().status, ESHUTDOWN);
+   host.assert_closed(&mut device);
+}
+
+#[test]
+fn unplugged_device_is_not_exported() {
+   let mut device = Device::new();
+   device.bus.unplug();
+   device.poll();
+
+   let mut host = device.connect();
+   assert_eq!(host.import(&mut device, "1-1"), ST_NODEV);
+
+   device.bus.plug();
+   device.poll();
+   let mut host = device.connect();
+   assert_eq!(host.import(&mut device, "1-1"), 0);
+}
+
+#[test]
+fn unplug_after_packets() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   let seqnum = host.submit(0x01, 0, 256, [0; 8], &[0; 256]);
+   device.bus.unplug_after(2);
+   host.assert_idle(&mut device);
+
+   let mut buf = [0; 64];
+   assert_eq!(device.class.ep_out.read(&mut buf).unwrap(), 64);
+   assert!(device.bus.is_plugged());
+   assert_eq!(device.class.ep_out.read(&mut buf).unwrap(), 64);
+   assert!(!device.bus.is_plugged());
+
+   let ret = host.receive_submit(&mut device);
+   assert_eq!(ret.seqnum, seqnum);
+   assert_eq!(ret.status, ESHUTDOWN);
+   assert_eq!(ret.actual_length, 128);
+   host.assert_closed(&mut device);
+}
+
+#[test]
+fn replug_during_enumeration() {
+   let mut device = Device::new();
+
+   // Let the device learn its descriptors anew and pull it, while the first read is in flight
+   device.bus.force_reset().unwrap();
+   device.frame();
+   device.frame();
+   device.bus.unplug();
+   device.bus.plug();
+
+   for _ in 0..50 {
+      device.frame();
+   }
+   let mut host = device.connect();
+   assert_eq!(host.import(&mut device, "1-1"), 0);
+}
+
+#[test]
+fn urb_for_dropped_device() {
+   let server = UsbIpServer::bind(([127, 0, 0, 1], 0)).unwrap();
+   let mut device = Device::with_bus(UsbIpBusBuilder::new().server(&server));
+
+   // A device without class, that goes away, while it is imported
+   let mut host = device.connect();
+   {
+      let bus = UsbIpBusBuilder::new().server(&server).build().unwrap();
+      let allocator = UsbBusAllocator::new(bus);
+      let mut usb_device = UsbDeviceBuilder::new(&allocator, UsbVidPid(0x16c0, 0x27dd)).build();
+      for _ in 0..100 {
+         usb_device.poll(&mut []);
+      }
+      assert_eq!(host.import(&mut device, "1-2"), 0);
+   }
+
+   let seqnum = host.submit(0x81, 0, 64, [0; 8], &[]);
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.status), (seqnum, ENODEV));
+}
@@ -0,0 +1,88 @@
+use super::*;
+
+fn ep_in(device: &Device) -> u8 {
+   device.class.ep_in.address().into()
+}
+
+#[test]
+fn in_completes_at_transfer_buffer_length() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   // A full packet does not end the transfer
+   let seqnum = host.submit(ep_in(&device), 0, 128, [0; 8], &[]);
+   device.poll();
+   device.class.ep_in.write(&[1; 64]).unwrap();
+   host.assert_idle(&mut device);
+
+   device.class.ep_in.write(&[2; 64]).unwrap();
+   let ret = host.receive_submit(&mut device);
+   assert_eq!(ret.seqnum, seqnum);
+   assert_eq!(ret.status, 0);
+   assert_eq!(ret.actual_length, 128);
+   assert_eq!(&ret.data[..64], &[1; 64][..]);
+   assert_eq!(&ret.data[64..], &[2; 64][..]);
+}
+
+#[test]
+fn in_short_packet() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   host.submit(ep_in(&device), 0, 128, [0; 8], &[]);
+   device.poll();
+   device.class.ep_in.write(&[1; 64]).unwrap();
+   device.class.ep_in.write(&[2; 10]).unwrap();
+
+   let ret = host.receive_submit(&mut device);
+   assert_eq!(ret.status, 0);
+   assert_eq!(ret.actual_length, 74);
+}
+
+#[test]
+fn in_short_packet_not_ok() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   host.submit(ep_in(&device), URB_SHORT_NOT_OK, 128, [0; 8], &[]);
+   device.poll();
+   device.class.ep_in.write(&[1; 64]).unwrap();
+   device.class.ep_in.write(&[2; 10]).unwrap();
+
+   // The data, that was received, is still reported
+   let ret = host.receive_submit(&mut device);
+   assert_eq!(ret.status, EREMOTEIO);
+   assert_eq!(ret.actual_length, 74);
+   assert_eq!(&ret.data[64..], &[2; 10][..]);
+}
+
+#[test]
+fn in_babble() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   host.submit(ep_in(&device), 0, 10, [0; 8], &[]);
+   device.poll();
+   device.class.ep_in.write(&[1; 64]).unwrap();
+
+   // Only as much as fits into the transfer buffer is reported
+   let ret = host.receive_submit(&mut device);
+   assert_eq!(ret.status, EOVERFLOW);
+   assert_eq!(ret.actual_length, 10);
+   assert_eq!(ret.data, vec![1; 10]);
+}
+
+#[test]
+fn unallocated_endpoint() {
+   let mut device = Device::new();
+   let mut host = device.attach();
+
+   // The class has allocated endpoints 1 and 2 only
+   let seqnum = host.submit(0x83, 0, 64, [0; 8], &[]);
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.status, ret.actual_length), (seqnum, ENOENT, 0));
+
+   let seqnum = host.submit(0x03, 0, 8, [0; 8], &[1; 8]);
+   let ret = host.receive_submit(&mut device);
+   assert_eq!((ret.seqnum, ret.status, ret.actual_length), (seqnum, ENOENT, 0));
+}
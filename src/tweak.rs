@@ -0,0 +1,206 @@
+//! The standard requests, whose effects reach beyond the device.
+//!
+//! Like the stub driver of Linux, the bus recognizes the requests on endpoint 0,
+//! that change the state of the pipes: clearing a halt, selecting a configuration or
+//! an alternate setting and resetting the port. It also keeps track of whether the host
+//! allows the device to wake it up. The host controller would handle these
+//! on its side of the bus, so the bus applies them to its pipes, once the device accepted them.
+//! Like on Linux, the URBs, that are queued on a pipe, stay there, unless the endpoint
+//! goes away with the new configuration or alternate setting.
+//! The port reset is addressed to the hub, it never reaches the device.
+
+use crate::{cmd::UsbIpHeader, status::UrbStatus, UsbIpBusInner, NUM_ENDPOINTS};
+use core::convert::TryInto;
+use usb_device::{endpoint::EndpointAddress, UsbDirection};
+
+const REQUEST_TYPE_STANDARD_DEVICE: u8 = 0x00;
+const REQUEST_TYPE_STANDARD_INTERFACE: u8 = 0x01;
+const REQUEST_TYPE_STANDARD_ENDPOINT: u8 = 0x02;
+const REQUEST_TYPE_CLASS_OTHER: u8 = 0x23;
+
+const REQUEST_CLEAR_FEATURE: u8 = 1;
+const REQUEST_SET_FEATURE: u8 = 3;
+const REQUEST_SET_CONFIGURATION: u8 = 9;
+const REQUEST_SET_INTERFACE: u8 = 11;
+
+const FEATURE_ENDPOINT_HALT: u16 = 0;
+const FEATURE_DEVICE_REMOTE_WAKEUP: u16 = 1;
+const FEATURE_PORT_RESET: u16 = 4;
+
+/// A request on endpoint 0, that changes the state of the bus.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tweak {
+   /// `CLEAR_FEATURE(ENDPOINT_HALT)` on an endpoint
+   ClearHalt(EndpointAddress),
+
+   /// `SET_INTERFACE`
+   SetInterface {
+      interface_number: u8,
+      alternate_setting: u8,
+   },
+
+   /// `SET_CONFIGURATION`
+   SetConfiguration(u8),
+
+   /// `SET_FEATURE(PORT_RESET)` on the port of the device
+   PortReset,
+
+   /// `SET_FEATURE(DEVICE_REMOTE_WAKEUP)` or `CLEAR_FEATURE(DEVICE_REMOTE_WAKEUP)`
+   RemoteWakeup(bool),
+}
+
+impl Tweak {
+   /// Recognizes the requests, whose effects have to be applied to the bus.
+   pub fn from_setup(setup: &[u8; 8]) -> Option<Self> {
+      let value = u16::from_le_bytes(setup[2..4].try_into().unwrap());
+      let index = u16::from_le_bytes(setup[4..6].try_into().unwrap());
+
+      match (setup[0], setup[1], value) {
+         (REQUEST_TYPE_STANDARD_ENDPOINT, REQUEST_CLEAR_FEATURE, FEATURE_ENDPOINT_HALT) => {
+            Some(Tweak::ClearHalt(EndpointAddress::from(index as u8)))
+         }
+         (REQUEST_TYPE_STANDARD_INTERFACE, REQUEST_SET_INTERFACE, _) => Some(Tweak::SetInterface {
+            interface_number: index as u8,
+            alternate_setting: value as u8,
+         }),
+         (REQUEST_TYPE_STANDARD_DEVICE, REQUEST_SET_CONFIGURATION, _) => {
+            Some(Tweak::SetConfiguration(value as u8))
+         }
+         (REQUEST_TYPE_CLASS_OTHER, REQUEST_SET_FEATURE, FEATURE_PORT_RESET) => Some(Tweak::PortReset),
+         (REQUEST_TYPE_STANDARD_DEVICE, REQUEST_SET_FEATURE, FEATURE_DEVICE_REMOTE_WAKEUP) => {
+            Some(Tweak::RemoteWakeup(true))
+         }
+         (REQUEST_TYPE_STANDARD_DEVICE, REQUEST_CLEAR_FEATURE, FEATURE_DEVICE_REMOTE_WAKEUP) => {
+            Some(Tweak::RemoteWakeup(false))
+         }
+         _ => None,
+      }
+   }
+}
+
+impl UsbIpBusInner {
+   /// Applies the effects of a request, the device has accepted.
+   pub fn apply_tweak(&mut self, tweak: Tweak) {
+      debug!("applying {:?} to the bus", tweak);
+
+      match tweak {
+         // The halt of endpoint 0 is cleared by the next SETUP anyway
+         Tweak::ClearHalt(ep_addr) if ep_addr.index() == 0 => (),
+         Tweak::ClearHalt(ep_addr) => self.clear_halt(ep_addr),
+         Tweak::SetInterface {
+            interface_number,
+            alternate_setting,
+         } => {
+            // The endpoints of the old alternate setting, that are not part of the new one, go away
+            let old_setting = self.enumeration.alternate_setting(interface_number);
+            let old_endpoints = self.enumeration.interface_endpoints(interface_number, old_setting);
+            let new_endpoints = self.enumeration.interface_endpoints(interface_number, alternate_setting);
+
+            for ep_addr in old_endpoints {
+               if !new_endpoints.contains(&ep_addr) {
+                  self.reset_pipe(EndpointAddress::from(ep_addr));
+               }
+            }
+            for ep_addr in new_endpoints {
+               self.clear_halt(EndpointAddress::from(ep_addr));
+            }
+            self.enumeration.set_interface(interface_number, alternate_setting);
+         }
+         Tweak::SetConfiguration(configuration_value) => {
+            // If the configuration is unknown, all the endpoints are kept
+            let endpoints = self.enumeration.configuration_endpoints(configuration_value);
+
+            for index in 1..NUM_ENDPOINTS {
+               for &direction in [UsbDirection::In, UsbDirection::Out].iter() {
+                  let ep_addr = EndpointAddress::from_parts(index, direction);
+                  match endpoints {
+                     Some(ref endpoints) if !endpoints.contains(&u8::from(ep_addr)) => {
+                        self.reset_pipe(ep_addr)
+                     }
+                     _ => self.clear_halt(ep_addr),
+                  }
+               }
+            }
+            self.enumeration.set_configuration(configuration_value);
+         }
+         Tweak::PortReset => (),
+         Tweak::RemoteWakeup(enabled) => self.remote_wakeup_enabled = enabled,
+      }
+   }
+
+   /// Resets the port of the device on behalf of the host.
+   ///
+   /// The request is acknowledged right away, the following URBs wait until the device
+   /// has processed the USB reset.
+   pub fn reset_port(&mut self, header: &UsbIpHeader) {
+      info!("host requested a port reset");
+
+      self.reset_pipes();
+      self.complete(header, UrbStatus::Ok);
+      self.reset = true;
+   }
+
+   /// Resets all the pipes, except for the ones of endpoint 0.
+   fn reset_pipes(&mut self) {
+      for index in 1..NUM_ENDPOINTS {
+         self.reset_pipe(EndpointAddress::from_parts(index, UsbDirection::In));
+         self.reset_pipe(EndpointAddress::from_parts(index, UsbDirection::Out));
+      }
+   }
+
+   /// Clears the halt and the queued packets of a pipe, the pending URBs are kept.
+   fn clear_halt(&mut self, ep_addr: EndpointAddress) {
+      let ep = match self.endpoint.get_mut(ep_addr.index()) {
+         Some(ep) => ep,
+         None => return,
+      };
+
+      match ep_addr.direction() {
+         UsbDirection::In => {
+            if let Some(ep_in) = ep.pipe_in.as_mut() {
+               ep_in.stalled = false;
+               ep_in.data.clear();
+            }
+         }
+         UsbDirection::Out => {
+            if let Some(ep_out) = ep.pipe_out.as_mut() {
+               ep_out.stalled = false;
+
+               // The packets, the device has not read yet, belong to the first pending URB,
+               // they are moved into the cleared pipe again
+               if let Some(out) = ep.pending_outs.front_mut() {
+                  for packet in ep_out.data.drain(..).rev() {
+                     out.packets.push_front(packet);
+                  }
+                  out.queued = 0;
+               }
+               ep_out.data.clear();
+            }
+            self.try_receive_pending(ep_addr.index());
+         }
+      }
+   }
+
+   /// Clears the halt and the queued packets of a pipe, that went away.
+   ///
+   /// The URBs, that are still pending on the pipe, are given back to the host.
+   fn reset_pipe(&mut self, ep_addr: EndpointAddress) {
+      self.fail_pending(ep_addr, UrbStatus::Unlinked);
+
+      let ep = match self.endpoint.get_mut(ep_addr.index()) {
+         Some(ep) => ep,
+         None => return,
+      };
+      let pipe = match ep_addr.direction() {
+         UsbDirection::In => ep.pipe_in.as_mut(),
+         UsbDirection::Out => ep.pipe_out.as_mut(),
+      };
+
+      if let Some(pipe) = pipe {
+         pipe.stalled = false;
+         pipe.data.clear();
+         pipe.abort_isos(UrbStatus::Unlinked, self.devid, &mut self.outgoing);
+      }
+   }
+}